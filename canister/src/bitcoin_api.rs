@@ -1,3 +1,4 @@
+use crate::chain::ChainParams;
 use anyhow::anyhow;
 use bitcoin::{block::Header, BlockHash};
 use candid::{self, CandidType, Deserialize, Principal};
@@ -53,20 +54,32 @@ pub(crate) async fn get_block_headers(
   res
 }
 
-pub async fn get_block_hash(
-  network: BitcoinNetwork,
-  height: u32,
-) -> crate::Result<Option<BlockHash>> {
-  // Bitcoin canister integration is temporarily disabled for regtest and testnet4.
+pub async fn get_block_hash(chain: &ChainParams, height: u32) -> crate::Result<Option<BlockHash>> {
+  // Bitcoin canister integration is temporarily disabled for networks other than mainnet.
   // As a workaround, we're using direct HTTPS outcalls to Bitcoin node to fetch block hashes
   // for these networks.
-  if network == BitcoinNetwork::Regtest || network == BitcoinNetwork::Testnet {
+  if chain.bitcoin_canister.is_none() {
     return match crate::rpc::get_block_hash(height).await {
       Ok(hash) => Ok(Some(hash)),
       Err(_err) => Ok(None),
     };
   }
 
+  Ok(
+    get_block_header(chain, height)
+      .await?
+      .map(|header| header.block_hash()),
+  )
+}
+
+pub async fn get_block_header(chain: &ChainParams, height: u32) -> crate::Result<Option<Header>> {
+  let Some(network) = chain.bitcoin_canister else {
+    let Ok(block_hash) = crate::rpc::get_block_hash(height).await else {
+      return Ok(None);
+    };
+    return crate::rpc::get_block_header(&block_hash).await.map(Some);
+  };
+
   match get_block_headers(network, height, Some(height)).await {
     Ok(response) => {
       let header_bytes = response
@@ -77,9 +90,9 @@ pub async fn get_block_hash(
 
       let header =
         <Header as bitcoin::consensus::Decodable>::consensus_decode(&mut header_bytes.as_slice())
-          .map_err(|_| anyhow!("failed to decode block hash at height: {}", height))?;
+          .map_err(|_| anyhow!("failed to decode block header at height: {}", height))?;
 
-      Ok(Some(header.block_hash()))
+      Ok(Some(header))
     }
     Err(err)
       if err.0 == RejectionCode::CanisterReject && err.1.contains("StartHeightDoesNotExist") =>
@@ -0,0 +1,185 @@
+use crate::config::{Chain, CustomChain};
+use bitcoin::blockdata::constants::SUBSIDY_HALVING_INTERVAL;
+use bitcoin::consensus::Params;
+use bitcoin::pow::{CompactTarget, Target};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ordinals::Rune;
+
+/// Number of characters in the shortest rune name available before the
+/// minimum name length starts to unlock.
+const LOCKED_LENGTH: u32 = 12;
+
+/// Network-dependent constants, derived from `Config`.
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+  /// Network of the bitcoin canister serving the chain's headers, `None` if
+  /// headers are fetched over RPC.
+  pub bitcoin_canister: Option<BitcoinNetwork>,
+  pub first_rune_height: u32,
+  pub subsidy_halving_interval: u32,
+  pub subnet_nodes: u64,
+  /// Proof of work rules the header chain is validated against.
+  pub consensus: Params,
+  pub enforce_bip94: bool,
+}
+
+impl ChainParams {
+  pub fn new(chain: &Chain) -> Self {
+    match chain {
+      Chain::Mainnet => Self::with_network(
+        bitcoin::Network::Bitcoin,
+        // bitcoin canister integration is temporarily only used on mainnet
+        Some(BitcoinNetwork::Mainnet),
+        34,
+      ),
+      Chain::Testnet4 => Self::with_network(bitcoin::Network::Testnet4, None, 13),
+      Chain::Signet => Self::with_network(bitcoin::Network::Signet, None, 13),
+      Chain::Regtest => Self::with_network(bitcoin::Network::Regtest, None, 13),
+      Chain::Custom(custom) => Self::custom(custom),
+    }
+  }
+
+  fn with_network(
+    network: bitcoin::Network,
+    bitcoin_canister: Option<BitcoinNetwork>,
+    subnet_nodes: u64,
+  ) -> Self {
+    Self {
+      bitcoin_canister,
+      first_rune_height: Rune::first_rune_height(network),
+      subsidy_halving_interval: SUBSIDY_HALVING_INTERVAL,
+      subnet_nodes,
+      consensus: Params::new(network),
+      enforce_bip94: network == bitcoin::Network::Testnet4,
+    }
+  }
+
+  fn custom(custom: &CustomChain) -> Self {
+    let mut consensus = Params::new(bitcoin::Network::Regtest);
+    consensus.max_attainable_target =
+      Target::from_compact(CompactTarget::from_consensus(custom.pow_limit));
+    consensus.pow_target_spacing = custom.pow_target_spacing;
+    consensus.pow_target_timespan = custom.pow_target_timespan;
+    consensus.allow_min_difficulty_blocks = custom.allow_min_difficulty_blocks;
+    consensus.no_pow_retargeting = custom.no_pow_retargeting;
+
+    Self {
+      bitcoin_canister: None,
+      first_rune_height: custom.first_rune_height,
+      subsidy_halving_interval: custom.subsidy_halving_interval,
+      subnet_nodes: custom.subnet_nodes,
+      consensus,
+      enforce_bip94: custom.enforce_bip94,
+    }
+  }
+
+  /// Returns the smallest rune name that may be etched at `height`. The
+  /// minimum length unlocks one character at a time over the halving epoch
+  /// starting at the first rune height.
+  pub fn minimum_at_height(&self, height: u32) -> Rune {
+    let offset = height.saturating_add(1);
+    let interval = (self.subsidy_halving_interval / LOCKED_LENGTH).max(1);
+
+    let start = self.first_rune_height;
+    let end = start.saturating_add(self.subsidy_halving_interval);
+
+    if offset < start {
+      return Rune(step(LOCKED_LENGTH));
+    }
+
+    if offset >= end {
+      return Rune(0);
+    }
+
+    let progress = offset.saturating_sub(start);
+
+    let length = LOCKED_LENGTH.saturating_sub(progress / interval).max(1);
+
+    let end = step(length - 1);
+
+    let start = step(length);
+
+    let remainder = u128::from(progress % interval);
+
+    Rune(start - ((start - end) * remainder / u128::from(interval)))
+  }
+}
+
+/// Returns the first rune with a name of `length` + 1 characters.
+fn step(length: u32) -> u128 {
+  (1..=length).map(|i| 26u128.pow(i)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use ordinals::Height;
+
+  #[test]
+  fn steps() {
+    assert_eq!(step(0), 0);
+    assert_eq!(step(1), 26);
+    assert_eq!(step(2), 702);
+    assert_eq!(step(12), 99246114928149462);
+  }
+
+  #[test]
+  fn minimum_at_height_matches_ordinals() {
+    for (chain, network) in [
+      (Chain::Mainnet, bitcoin::Network::Bitcoin),
+      (Chain::Testnet4, bitcoin::Network::Testnet4),
+      (Chain::Signet, bitcoin::Network::Signet),
+      (Chain::Regtest, bitcoin::Network::Regtest),
+    ] {
+      let params = ChainParams::new(&chain);
+      let start = params.first_rune_height;
+      let interval = SUBSIDY_HALVING_INTERVAL / LOCKED_LENGTH;
+      let heights = [
+        0,
+        1,
+        start,
+        start + 1,
+        start + interval,
+        start + interval * 11,
+      ]
+      .into_iter()
+      .chain((0..=LOCKED_LENGTH).flat_map(|i| {
+        let height = start + interval * i;
+        [height.saturating_sub(2), height.saturating_sub(1), height]
+      }))
+      .chain([start + SUBSIDY_HALVING_INTERVAL, u32::MAX]);
+
+      for height in heights {
+        assert_eq!(
+          params.minimum_at_height(height),
+          Rune::minimum_at_height(network, Height(height)),
+          "{chain:?} at height {height}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn custom_chain_schedule() {
+    let params = ChainParams::new(&Chain::Custom(CustomChain {
+      first_rune_height: 1_000,
+      subsidy_halving_interval: 1_200,
+      subnet_nodes: 13,
+      pow_limit: 0x207fffff,
+      pow_target_spacing: 600,
+      pow_target_timespan: 1_209_600,
+      allow_min_difficulty_blocks: true,
+      no_pow_retargeting: true,
+      enforce_bip94: false,
+    }));
+
+    assert_eq!(params.minimum_at_height(998), Rune(step(12)));
+    assert_eq!(params.minimum_at_height(999), Rune(step(12)));
+    assert_eq!(params.minimum_at_height(1_099), Rune(step(11)));
+    assert_eq!(params.minimum_at_height(1_199), Rune(step(10)));
+    assert_eq!(params.minimum_at_height(2_198), Rune(1));
+    assert_eq!(params.minimum_at_height(2_199), Rune(0));
+    assert!(params.minimum_at_height(1_049) < Rune(step(12)));
+    assert!(params.minimum_at_height(1_049) > Rune(step(11)));
+  }
+}
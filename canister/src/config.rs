@@ -1,14 +1,139 @@
+use crate::chain::ChainParams;
+use crate::selection::RuneSelection;
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use ic_stable_structures::storable::{Bound, Storable};
 use serde::Serialize;
 use std::borrow::Cow;
 
+/// Chain the indexer follows.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Chain {
+  Mainnet,
+  Testnet4,
+  Signet,
+  Regtest,
+  Custom(CustomChain),
+}
+
+/// Parameters of a Bitcoin fork. Its headers and blocks are fetched over RPC.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CustomChain {
+  pub first_rune_height: u32,
+  pub subsidy_halving_interval: u32,
+  pub subnet_nodes: u64,
+  /// Compact encoding of the highest target a block may have.
+  pub pow_limit: u32,
+  pub pow_target_spacing: u64,
+  pub pow_target_timespan: u64,
+  pub allow_min_difficulty_blocks: bool,
+  pub no_pow_retargeting: bool,
+  pub enforce_bip94: bool,
+}
+
+/// State of the index after the block before `Config::start_height`, loaded
+/// in chunks before indexing starts. See `index::snapshot` for the format.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SnapshotManifest {
+  /// Hex-encoded header of the last block included in the snapshot.
+  pub block_header: String,
+  pub runes: u64,
+  pub reserved_runes: u64,
+  /// Hex-encoded sha256 of each chunk, in order.
+  pub chunk_hashes: Vec<String>,
+}
+
+/// Selects runes whose balances are indexed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum RuneSelector {
+  /// A rune id such as `840000:3`.
+  Id(String),
+  /// A rune name without spacers, where `*` matches any run of letters.
+  Name(String),
+}
+
+/// Role of the canister in a deployment that spreads output balances over
+/// several canisters, see `index::shard`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Sharding {
+  /// Indexes blocks and keeps rune entries, storing output balances on
+  /// `shards` by outpoint hash. Changing the shards would move outputs, so
+  /// they are fixed at install.
+  Coordinator { shards: Vec<Principal> },
+  /// Stores the output balances sent by `coordinator` and doesn't index.
+  Shard { coordinator: Principal },
+}
+
+/// Log of the rune events of every indexed block, see `index::events`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EventLogConfig {
+  /// Events of blocks at least this deep are moved to archive canisters once
+  /// an archive wasm has been uploaded. Must be larger than the deepest
+  /// rollback, see `Config::max_rollback_depth`.
+  pub archive_depth: u32,
+  /// Events sent to an archive per call.
+  pub archive_batch_size: u32,
+  /// Events an archive holds before the next archive is spawned.
+  pub max_events_per_archive: u64,
+  /// Cycles each archive is created with.
+  pub archive_cycles: u128,
+}
+
+/// Format of the notifications sent to subscribers for every indexed block.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum NotificationVersion {
+  /// `new_block_detected` with the height, hash and every txid of the block.
+  V1,
+  /// `new_block_detected_v2` with the block's rune transactions and their
+  /// events, see `notifier::rune_block`.
+  V2,
+}
+
+/// Reorgs up to this depth are rolled back using per-block change records.
+pub const DEFAULT_MAX_REORG_DEPTH: u32 = 6;
+
+/// A subscriber call taking longer than this counts as failed.
+pub const DEFAULT_NOTIFICATION_TIMEOUT_SECS: u64 = 60;
+
+/// Recent blocks whose P2TR outputs are cached, about a day of blocks.
+pub const DEFAULT_TAPROOT_CACHE_DEPTH: u32 = 144;
+
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Config {
   pub network: BitcoinNetwork,
   pub bitcoin_rpc_url: String,
   pub subscribers: Vec<Principal>,
+  pub max_reorg_depth: Option<u32>,
+  /// Blocks between compact checkpoints used to recover from reorgs deeper
+  /// than `max_reorg_depth`. Checkpoints are disabled when unset or zero.
+  pub checkpoint_interval: Option<u32>,
+  /// Keep merkle branches of runestone transactions to serve inclusion proofs.
+  pub tx_inclusion_proofs: Option<bool>,
+  /// Overrides the chain implied by `network`, which is then ignored.
+  pub chain: Option<Chain>,
+  /// Height indexing starts from instead of the first rune height. Heights
+  /// after the first rune height require a snapshot of the state before it.
+  pub start_height: Option<u32>,
+  pub snapshot: Option<SnapshotManifest>,
+  /// A warning is logged when the stable memory size first reaches this.
+  pub stable_memory_warning_bytes: Option<u64>,
+  /// A warning is logged when the heap size first reaches this.
+  pub heap_memory_warning_bytes: Option<u64>,
+  /// Only balances of these runes are indexed when set. Rune entries are
+  /// kept for all runes. Can only be set at install.
+  pub indexed_runes: Option<Vec<RuneSelector>>,
+  /// Can only be set at install.
+  pub sharding: Option<Sharding>,
+  pub event_log: Option<EventLogConfig>,
+  /// Index whose events this canister stores if it is an archive.
+  pub archive_of: Option<Principal>,
+  /// Defaults to `V1`.
+  pub notification_version: Option<NotificationVersion>,
+  /// Seconds a subscriber has to answer a notification before it is retried.
+  pub notification_timeout_secs: Option<u64>,
+  /// Recent blocks whose P2TR outputs are kept for etching commitment
+  /// checks. Older commitments are looked up over RPC.
+  pub taproot_cache_depth: Option<u32>,
 }
 
 impl Default for Config {
@@ -17,28 +142,198 @@ impl Default for Config {
       network: BitcoinNetwork::Regtest,
       bitcoin_rpc_url: "".to_string(),
       subscribers: vec![],
+      max_reorg_depth: None,
+      checkpoint_interval: None,
+      tx_inclusion_proofs: None,
+      chain: None,
+      start_height: None,
+      snapshot: None,
+      stable_memory_warning_bytes: None,
+      heap_memory_warning_bytes: None,
+      indexed_runes: None,
+      sharding: None,
+      event_log: None,
+      archive_of: None,
+      notification_version: None,
+      notification_timeout_secs: None,
+      taproot_cache_depth: None,
+    }
+  }
+}
+
+/// Layout of `Config` before it was stored as candid.
+#[derive(Deserialize)]
+struct LegacyConfig {
+  network: BitcoinNetwork,
+  bitcoin_rpc_url: String,
+  subscribers: Vec<Principal>,
+}
+
+impl From<LegacyConfig> for Config {
+  fn from(legacy: LegacyConfig) -> Self {
+    Self {
+      network: legacy.network,
+      bitcoin_rpc_url: legacy.bitcoin_rpc_url,
+      subscribers: legacy.subscribers,
+      ..Default::default()
     }
   }
 }
 
 impl Config {
-  pub fn get_subnet_nodes(&self) -> u64 {
-    match self.network {
-      BitcoinNetwork::Regtest => 13,
-      BitcoinNetwork::Testnet => 13,
-      BitcoinNetwork::Mainnet => 34,
+  pub fn max_reorg_depth(&self) -> u32 {
+    self.max_reorg_depth.unwrap_or(DEFAULT_MAX_REORG_DEPTH)
+  }
+
+  pub fn checkpoint_interval(&self) -> Option<u32> {
+    self.checkpoint_interval.filter(|interval| *interval > 0)
+  }
+
+  /// Depth of the deepest reorg that can be rolled back, from the oldest
+  /// checkpoint if checkpoints are enabled.
+  pub fn max_rollback_depth(&self) -> u32 {
+    let checkpoints = self
+      .checkpoint_interval()
+      .map(|interval| interval.saturating_mul(crate::index::reorg::MAX_CHECKPOINTS))
+      .unwrap_or_default();
+    self.max_reorg_depth().saturating_add(checkpoints)
+  }
+
+  pub fn tx_inclusion_proofs(&self) -> bool {
+    self.tx_inclusion_proofs.unwrap_or_default()
+  }
+
+  pub fn notification_version(&self) -> NotificationVersion {
+    self.notification_version.unwrap_or(NotificationVersion::V1)
+  }
+
+  pub fn notification_timeout(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(
+      self
+        .notification_timeout_secs
+        .unwrap_or(DEFAULT_NOTIFICATION_TIMEOUT_SECS),
+    )
+  }
+
+  pub fn taproot_cache_depth(&self) -> u32 {
+    self
+      .taproot_cache_depth
+      .unwrap_or(DEFAULT_TAPROOT_CACHE_DEPTH)
+  }
+
+  pub fn chain(&self) -> Chain {
+    self.chain.clone().unwrap_or(match self.network {
+      BitcoinNetwork::Mainnet => Chain::Mainnet,
+      BitcoinNetwork::Testnet => Chain::Testnet4,
+      BitcoinNetwork::Regtest => Chain::Regtest,
+    })
+  }
+
+  pub fn chain_params(&self) -> ChainParams {
+    ChainParams::new(&self.chain())
+  }
+
+  /// Height of the first block to index when nothing has been indexed yet.
+  pub fn start_height(&self) -> u32 {
+    self
+      .start_height
+      .unwrap_or_else(|| self.chain_params().first_rune_height)
+  }
+
+  /// Runes whose balances are indexed, or `None` if all of them are.
+  pub fn rune_selection(&self) -> Option<RuneSelection> {
+    self
+      .indexed_runes
+      .as_ref()
+      .map(|selectors| RuneSelection::new(selectors).expect("validated at install"))
+  }
+
+  /// Shards storing the output balances if this canister is a coordinator.
+  pub fn shards(&self) -> Option<Vec<Principal>> {
+    match &self.sharding {
+      Some(Sharding::Coordinator { shards }) => Some(shards.clone()),
+      _ => None,
     }
   }
+
+  /// Coordinator whose output balances this canister stores if it is a shard.
+  pub fn coordinator(&self) -> Option<Principal> {
+    match &self.sharding {
+      Some(Sharding::Shard { coordinator }) => Some(*coordinator),
+      _ => None,
+    }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if let Some(selectors) = &self.indexed_runes {
+      RuneSelection::new(selectors)?;
+    }
+    if let Some(event_log) = &self.event_log {
+      // archived events can't be reverted, so no rollback may reach them
+      if event_log.archive_depth <= self.max_rollback_depth() {
+        return Err(
+          "the event archive_depth must be larger than max_reorg_depth plus \
+           checkpoint_interval times the number of checkpoints kept"
+            .to_string(),
+        );
+      }
+      if event_log.archive_batch_size == 0 || event_log.max_events_per_archive == 0 {
+        return Err("event archives require a batch size and capacity".to_string());
+      }
+    }
+    // the change record of the current block is needed to roll it back
+    if self.max_reorg_depth == Some(0) {
+      return Err("max_reorg_depth must be positive".to_string());
+    }
+    if self.notification_timeout_secs == Some(0) {
+      return Err("notification_timeout_secs must be positive".to_string());
+    }
+    if self.taproot_cache_depth == Some(0) {
+      return Err("taproot_cache_depth must be positive".to_string());
+    }
+    if let Some(shards) = self.shards() {
+      if shards.is_empty() {
+        return Err("a coordinator requires at least one shard".to_string());
+      }
+      // shards only keep the change records of the reorg window
+      if self.checkpoint_interval().is_some() {
+        return Err("checkpoints are not supported with shards".to_string());
+      }
+      if self.snapshot.is_some() {
+        return Err("snapshots are not supported with shards".to_string());
+      }
+    }
+    match (&self.snapshot, self.start_height) {
+      (Some(_), None) => Err("a snapshot requires a start_height".to_string()),
+      (Some(_), Some(0)) => Err("a snapshot cannot start at height 0".to_string()),
+      (None, Some(start_height)) if start_height > self.chain_params().first_rune_height => {
+        Err("a start_height after the first rune height requires a snapshot".to_string())
+      }
+      _ => Ok(()),
+    }
+  }
+
+  pub fn get_subnet_nodes(&self) -> u64 {
+    self.chain_params().subnet_nodes
+  }
 }
 
+// Config is stored as candid so that new optional fields can be added without
+// breaking the decoding of a config written by an older version.
 impl Storable for Config {
   fn to_bytes(&self) -> Cow<[u8]> {
-    let bytes = bincode::serialize(self).unwrap();
+    let bytes = candid::encode_one(self).unwrap();
     Cow::Owned(bytes)
   }
 
   fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-    bincode::deserialize(bytes.as_ref()).unwrap()
+    if bytes.starts_with(b"DIDL") {
+      candid::decode_one(bytes.as_ref()).unwrap()
+    } else {
+      bincode::deserialize::<LegacyConfig>(bytes.as_ref())
+        .unwrap()
+        .into()
+    }
   }
 
   const BOUND: Bound = Bound::Unbounded;
@@ -48,6 +343,15 @@ impl Storable for Config {
 pub struct UpgradeArgs {
   pub bitcoin_rpc_url: Option<String>,
   pub subscribers: Option<Vec<Principal>>,
+  pub max_reorg_depth: Option<u32>,
+  pub checkpoint_interval: Option<u32>,
+  pub tx_inclusion_proofs: Option<bool>,
+  pub stable_memory_warning_bytes: Option<u64>,
+  pub heap_memory_warning_bytes: Option<u64>,
+  pub event_log: Option<EventLogConfig>,
+  pub notification_version: Option<NotificationVersion>,
+  pub notification_timeout_secs: Option<u64>,
+  pub taproot_cache_depth: Option<u32>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
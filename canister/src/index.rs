@@ -1,11 +1,20 @@
-use self::entry::{Entry, RuneEntry};
+use self::access::{AuditRecord, Roles};
+use self::archive::Archives;
+use self::entry::{Entry, EventRecord, RuneEntry};
 use self::lot::Lot;
+use self::outbox::{Cursors, OutboxEntry};
+use self::schema::Legacy;
+use self::snapshot::SnapshotProgress;
+use self::subscriptions::{Settings, Subscriptions};
+use self::webhooks::Webhooks;
 use super::Result;
 use crate::config::Config;
 use crate::index::entry::{
-  ChangeRecord, HeaderValue, OutPointValue, RuneBalances, RuneIdValue, TxidValue,
+  BlockHashValue, ChainWorkValue, ChangeRecord, Checkpoint, HeaderValue, IndexProgress,
+  OutPointValue, PackedOutPoint, ProofTxids, RuneBalances, RuneIdValue, RuneTransactions,
+  TaprootOutPoints, TxProof, TxidValue,
 };
-use crate::logs::INFO;
+use crate::logs::{CRITICAL, INFO};
 use anyhow::anyhow;
 use bitcoin::{
   block::Header,
@@ -13,27 +22,45 @@ use bitcoin::{
   consensus::{self, Decodable, Encodable},
   hash_types::BlockHash,
   hashes::Hash,
+  pow::Work,
   Block, OutPoint, Transaction, Txid,
 };
 use ic_canister_log::log;
-use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell};
 use ordinals::{
-  Artifact, Edict, Etching, Height, Pile, Rune, RuneId, Runestone, SatPoint, SpacedRune, Terms,
+  Artifact, Edict, Etching, Pile, Rune, RuneId, Runestone, SatPoint, SpacedRune, Terms,
 };
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::atomic::{self, AtomicBool};
 
+pub mod access;
+pub mod archive;
 pub mod entry;
+pub mod events;
+pub mod export;
+mod headers;
+pub mod icrc3;
 mod lot;
-mod reorg;
+pub mod outbox;
+mod proof;
+pub(crate) mod reorg;
+pub mod schema;
+pub mod shard;
+pub mod snapshot;
+mod staging;
+pub mod storage;
+pub mod subscriptions;
 pub mod updater;
+mod varint;
+pub mod webhooks;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+const WASM_PAGE_SIZE: u64 = 65_536;
+
 thread_local! {
   static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
       RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -63,13 +90,13 @@ thread_local! {
       )
   );
 
-  static OUTPOINT_TO_RUNE_BALANCES: RefCell<StableBTreeMap<OutPointValue, RuneBalances, Memory>> = RefCell::new(
+  static LEGACY_OUTPOINT_TO_RUNE_BALANCES: RefCell<StableBTreeMap<OutPointValue, Legacy<RuneBalances>, Memory>> = RefCell::new(
       StableBTreeMap::init(
           MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
       )
   );
 
-  static RUNE_ID_TO_RUNE_ENTRY: RefCell<StableBTreeMap<RuneIdValue, RuneEntry, Memory>> = RefCell::new(
+  static LEGACY_RUNE_ID_TO_RUNE_ENTRY: RefCell<StableBTreeMap<RuneIdValue, Legacy<RuneEntry>, Memory>> = RefCell::new(
       StableBTreeMap::init(
           MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
       )
@@ -87,17 +114,190 @@ thread_local! {
       )
   );
 
-  static OUTPOINT_TO_HEIGHT: RefCell<StableBTreeMap<OutPointValue, u32, Memory>> = RefCell::new(
+  static LEGACY_OUTPOINT_TO_HEIGHT: RefCell<StableBTreeMap<OutPointValue, u32, Memory>> = RefCell::new(
       StableBTreeMap::init(
           MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
       )
   );
 
-  static HEIGHT_TO_CHANGE_RECORD: RefCell<StableBTreeMap<u32, ChangeRecord, Memory>> = RefCell::new(
+  static LEGACY_HEIGHT_TO_CHANGE_RECORD: RefCell<StableBTreeMap<u32, Legacy<ChangeRecord>, Memory>> = RefCell::new(
       StableBTreeMap::init(
           MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
       )
   );
+
+  static OUTPOINT_TO_TAPROOT_HEIGHT: RefCell<StableBTreeMap<OutPointValue, u32, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+      )
+  );
+
+  static HEIGHT_TO_TAPROOT_OUTPOINTS: RefCell<StableBTreeMap<u32, TaprootOutPoints, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+      )
+  );
+
+  static HEIGHT_TO_INDEX_PROGRESS: RefCell<StableBTreeMap<u32, IndexProgress, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+      )
+  );
+
+  static HEIGHT_TO_CHECKPOINT: RefCell<StableBTreeMap<u32, Checkpoint, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+      )
+  );
+
+  static BLOCK_HASH_TO_HEIGHT: RefCell<StableBTreeMap<BlockHashValue, u32, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+      )
+  );
+
+  static HEIGHT_TO_CHAIN_WORK: RefCell<StableBTreeMap<u32, ChainWorkValue, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+      )
+  );
+
+  static TXID_TO_TX_PROOF: RefCell<StableBTreeMap<TxidValue, TxProof, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+      )
+  );
+
+  static HEIGHT_TO_PROOF_TXIDS: RefCell<StableBTreeMap<u32, ProofTxids, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+      )
+  );
+
+  static SNAPSHOT_PROGRESS: RefCell<StableCell<SnapshotProgress, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+          SnapshotProgress::default()
+      ).unwrap()
+  );
+
+  static STORED_SCHEMA_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+          0
+      ).unwrap()
+  );
+
+  static RUNE_ID_TO_RUNE_ENTRY: RefCell<StableBTreeMap<RuneIdValue, RuneEntry, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+      )
+  );
+
+  static OUTPOINT_TO_OVERFLOW_BALANCES: RefCell<StableBTreeMap<OutPointValue, Vec<u8>, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+      )
+  );
+
+  static HEIGHT_TO_CHANGE_RECORD: RefCell<StableBTreeMap<u32, ChangeRecord, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+      )
+  );
+
+  static OUTPOINT_TO_BALANCES: RefCell<StableBTreeMap<OutPointValue, PackedOutPoint, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+      )
+  );
+
+  static EVENTS: RefCell<StableBTreeMap<u64, EventRecord, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+      )
+  );
+
+  static HEIGHT_TO_FIRST_EVENT: RefCell<StableBTreeMap<u32, u64, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+      )
+  );
+
+  static ARCHIVES: RefCell<StableCell<Archives, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+          Archives::default()
+      ).unwrap()
+  );
+
+  static ARCHIVE_WASM: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+          Vec::new()
+      ).unwrap()
+  );
+
+  /// Hash of the last event's ICRC-3 block, empty while the log is.
+  static LAST_EVENT_HASH: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+          Vec::new()
+      ).unwrap()
+  );
+
+  static HEIGHT_TO_RUNE_TRANSACTIONS: RefCell<StableBTreeMap<u32, RuneTransactions, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+      )
+  );
+
+  static OUTBOX: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+      )
+  );
+
+  static OUTBOX_CURSORS: RefCell<StableCell<Cursors, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+          Cursors::default()
+      ).unwrap()
+  );
+
+  static SUBSCRIPTIONS: RefCell<StableCell<Subscriptions, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+          Subscriptions::default()
+      ).unwrap()
+  );
+
+  static SUBSCRIBER_SETTINGS: RefCell<StableCell<Settings, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+          Settings::default()
+      ).unwrap()
+  );
+
+  static WEBHOOKS: RefCell<StableCell<Webhooks, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+          Webhooks::default()
+      ).unwrap()
+  );
+
+  static ROLES: RefCell<StableCell<Roles, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+          Roles::default()
+      ).unwrap()
+  );
+
+  static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditRecord, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+      )
+  );
 }
 
 static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
@@ -124,6 +324,16 @@ pub fn mem_set_config(config: Config) -> Result<Config> {
     .map_err(|e| anyhow::anyhow!("Failed to set config: {:?}", e))
 }
 
+pub fn mem_get_snapshot_progress() -> SnapshotProgress {
+  SNAPSHOT_PROGRESS.with(|m| m.borrow().get().clone())
+}
+
+pub fn mem_set_snapshot_progress(progress: SnapshotProgress) -> Result<SnapshotProgress> {
+  SNAPSHOT_PROGRESS
+    .with(|m| m.borrow_mut().set(progress))
+    .map_err(|e| anyhow::anyhow!("Failed to set snapshot progress: {:?}", e))
+}
+
 pub fn mem_latest_block() -> Option<(u32, BlockHash)> {
   HEIGHT_TO_BLOCK_HEADER.with(|m| {
     m.borrow()
@@ -149,25 +359,82 @@ pub fn mem_block_hash(height: u32) -> Option<BlockHash> {
   })
 }
 
+pub fn mem_get_block_header(height: u32) -> Option<Header> {
+  HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().get(&height).map(Header::load))
+}
+
 pub fn mem_insert_block_header(height: u32, header_value: HeaderValue) {
+  let header = Header::load(header_value);
+  let block_hash = header.block_hash();
+  let chain_work = height
+    .checked_sub(1)
+    .and_then(mem_get_chain_work)
+    .map_or(header.work(), |work| work + header.work());
+  HEIGHT_TO_CHAIN_WORK.with(|m| m.borrow_mut().insert(height, chain_work.store()));
+  BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow_mut().insert(block_hash.store(), height));
   HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow_mut().insert(height, header_value));
 }
 
 pub fn mem_remove_block_header(height: u32) -> Option<HeaderValue> {
-  HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow_mut().remove(&height))
+  let header_value = HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow_mut().remove(&height))?;
+  let block_hash = Header::load(header_value).block_hash();
+  BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow_mut().remove(&block_hash.store()));
+  HEIGHT_TO_CHAIN_WORK.with(|m| m.borrow_mut().remove(&height));
+  Some(header_value)
+}
+
+/// Returns the cumulative work of the indexed chain up to and including `height`.
+pub fn mem_get_chain_work(height: u32) -> Option<Work> {
+  HEIGHT_TO_CHAIN_WORK.with(|m| m.borrow().get(&height).map(Work::load))
+}
+
+/// Computes the cumulative work of headers stored before it was tracked.
+pub fn mem_index_chain_work() {
+  let headers = HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().len());
+  if HEIGHT_TO_CHAIN_WORK.with(|m| m.borrow().len()) == headers {
+    return;
+  }
+  HEIGHT_TO_BLOCK_HEADER.with(|headers| {
+    HEIGHT_TO_CHAIN_WORK.with(|m| {
+      let mut map = m.borrow_mut();
+      let mut chain_work: Option<(u32, Work)> = None;
+      for (height, header_value) in headers.borrow().iter() {
+        let work = Header::load(header_value).work();
+        let work = match chain_work {
+          Some((prev, total)) if prev + 1 == height => total + work,
+          _ => work,
+        };
+        map.insert(height, work.store());
+        chain_work = Some((height, work));
+      }
+    })
+  });
 }
 
-pub fn mem_prune_block_header(height: u32) {
-  HEIGHT_TO_BLOCK_HEADER.with(|m| {
-    let mut map = m.borrow_mut();
-    let keys_to_remove: Vec<u32> = map
-      .iter()
-      .take_while(|(h, _)| *h <= height)
-      .map(|(h, _)| h)
-      .collect();
-    for key in keys_to_remove {
-      map.remove(&key);
-    }
+/// Returns the height of the block if it is part of the indexed chain.
+pub fn mem_get_block_height(block_hash: BlockHash) -> Option<u32> {
+  BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow().get(&block_hash.store()))
+}
+
+/// Confirmations of a block in the indexed best chain, 1 for the tip.
+pub fn mem_block_confirmations(block_hash: BlockHash) -> Option<u32> {
+  let height = mem_get_block_height(block_hash)?;
+  Some(mem_latest_block_height()? - height + 1)
+}
+
+/// Indexes the hashes of headers stored before the hash index existed.
+pub fn mem_index_block_hashes() {
+  let headers = HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().len());
+  if BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow().len()) == headers {
+    return;
+  }
+  HEIGHT_TO_BLOCK_HEADER.with(|headers| {
+    BLOCK_HASH_TO_HEIGHT.with(|m| {
+      let mut map = m.borrow_mut();
+      for (height, header_value) in headers.borrow().iter() {
+        map.insert(Header::load(header_value).block_hash().store(), height);
+      }
+    })
   });
 }
 
@@ -182,6 +449,10 @@ pub fn mem_statistic_reserved_runes() -> u64 {
   })
 }
 
+pub fn mem_get_statistic_reserved_runes(height: u32) -> Option<u64> {
+  HEIGHT_TO_STATISTIC_RESERVED_RUNES.with(|m| m.borrow().get(&height))
+}
+
 pub fn mem_insert_statistic_reserved_runes(height: u32, runes: u64) {
   HEIGHT_TO_STATISTIC_RESERVED_RUNES.with(|m| m.borrow_mut().insert(height, runes));
 }
@@ -215,6 +486,10 @@ pub fn mem_statistic_runes() -> u64 {
   })
 }
 
+pub fn mem_get_statistic_runes(height: u32) -> Option<u64> {
+  HEIGHT_TO_STATISTIC_RUNES.with(|m| m.borrow().get(&height))
+}
+
 pub fn mem_insert_statistic_runes(height: u32, runes: u64) {
   HEIGHT_TO_STATISTIC_RUNES.with(|m| m.borrow_mut().insert(height, runes));
 }
@@ -242,41 +517,112 @@ pub fn mem_prune_statistic_runes(height: u32) {
   });
 }
 
-pub fn mem_length_outpoint_to_rune_balances() -> u64 {
-  OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().len())
+/// Returns the stable memory allocated to the region with the given id, in bytes.
+pub fn mem_region_bytes(id: u8) -> u64 {
+  MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)).size()) * WASM_PAGE_SIZE
+}
+
+pub fn mem_get_schema_version() -> u32 {
+  STORED_SCHEMA_VERSION.with(|m| *m.borrow().get())
+}
+
+pub fn mem_set_schema_version(version: u32) -> Result<u32> {
+  STORED_SCHEMA_VERSION
+    .with(|m| m.borrow_mut().set(version))
+    .map_err(|e| anyhow::anyhow!("Failed to set schema version: {:?}", e))
+}
+
+pub fn mem_length_outpoints() -> u64 {
+  OUTPOINT_TO_BALANCES.with(|m| m.borrow().len())
+    + LEGACY_OUTPOINT_TO_HEIGHT.with(|m| m.borrow().len())
 }
 
-pub fn mem_get_outpoint_to_rune_balances(outpoint_value: OutPointValue) -> Option<RuneBalances> {
-  OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().get(&outpoint_value))
+/// Returns the balances of an output and the height it was created at.
+pub fn mem_get_outpoint(outpoint_value: OutPointValue) -> Option<(RuneBalances, u32)> {
+  match OUTPOINT_TO_BALANCES.with(|m| m.borrow().get(&outpoint_value)) {
+    Some(packed) => unpack_outpoint(outpoint_value, packed, || {
+      OUTPOINT_TO_OVERFLOW_BALANCES.with(|m| m.borrow().get(&outpoint_value))
+    }),
+    None => {
+      let height = LEGACY_OUTPOINT_TO_HEIGHT.with(|m| m.borrow().get(&outpoint_value))?;
+      let rune_balances =
+        LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().get(&outpoint_value).map(|v| v.0))?;
+      Some((rune_balances, height))
+    }
+  }
 }
 
-pub fn mem_insert_outpoint_to_rune_balances(
+/// Unpacks an output, treating it as missing if its balances are missing
+/// from the overflow map.
+fn unpack_outpoint(
+  outpoint_value: OutPointValue,
+  packed: PackedOutPoint,
+  overflow: impl FnOnce() -> Option<Vec<u8>>,
+) -> Option<(RuneBalances, u32)> {
+  let unpacked = packed.unpack(overflow);
+  if unpacked.is_none() {
+    log!(
+      CRITICAL,
+      "overflow balances of output {} are missing",
+      OutPoint::load(outpoint_value)
+    );
+  }
+  unpacked
+}
+
+pub fn mem_insert_outpoint(
   outpoint_value: OutPointValue,
   rune_balances: RuneBalances,
+  height: u32,
 ) {
-  OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow_mut().insert(outpoint_value, rune_balances));
+  let (packed, overflow) = PackedOutPoint::pack(&rune_balances, height);
+  OUTPOINT_TO_OVERFLOW_BALANCES.with(|m| match overflow {
+    Some(balances) => m.borrow_mut().insert(outpoint_value, balances),
+    None => m.borrow_mut().remove(&outpoint_value),
+  });
+  OUTPOINT_TO_BALANCES.with(|m| m.borrow_mut().insert(outpoint_value, packed));
+  mem_remove_unpacked_outpoint(outpoint_value);
 }
 
-pub(crate) fn mem_remove_outpoint_to_rune_balances(
+pub(crate) fn mem_remove_outpoint(outpoint_value: OutPointValue) -> Option<(RuneBalances, u32)> {
+  let unpacked = mem_remove_unpacked_outpoint(outpoint_value);
+  let overflow = OUTPOINT_TO_OVERFLOW_BALANCES.with(|m| m.borrow_mut().remove(&outpoint_value));
+  OUTPOINT_TO_BALANCES
+    .with(|m| m.borrow_mut().remove(&outpoint_value))
+    .and_then(|packed| unpack_outpoint(outpoint_value, packed, || overflow))
+    .or(unpacked)
+}
+
+/// Removes an output stored before balances were packed.
+pub(crate) fn mem_remove_unpacked_outpoint(
   outpoint_value: OutPointValue,
-) -> Option<RuneBalances> {
-  OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow_mut().remove(&outpoint_value))
+) -> Option<(RuneBalances, u32)> {
+  let height = LEGACY_OUTPOINT_TO_HEIGHT.with(|m| m.borrow_mut().remove(&outpoint_value));
+  let legacy = LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow_mut().remove(&outpoint_value));
+  Some((legacy?.0, height?))
 }
 
 pub fn mem_length_rune_id_to_rune_entry() -> u64 {
   RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len())
+    + LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len())
 }
 
 pub fn mem_get_rune_id_to_rune_entry(rune_id_value: RuneIdValue) -> Option<RuneEntry> {
-  RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().get(&rune_id_value))
+  RUNE_ID_TO_RUNE_ENTRY
+    .with(|m| m.borrow().get(&rune_id_value))
+    .or_else(|| LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().get(&rune_id_value).map(|v| v.0)))
 }
 
 pub fn mem_insert_rune_id_to_rune_entry(rune_id_value: RuneIdValue, rune_entry: RuneEntry) {
   RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow_mut().insert(rune_id_value, rune_entry));
+  LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow_mut().remove(&rune_id_value));
 }
 
 pub(crate) fn mem_remove_rune_id_to_rune_entry(rune_id_value: RuneIdValue) -> Option<RuneEntry> {
-  RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow_mut().remove(&rune_id_value))
+  let legacy = LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow_mut().remove(&rune_id_value));
+  RUNE_ID_TO_RUNE_ENTRY
+    .with(|m| m.borrow_mut().remove(&rune_id_value))
+    .or(legacy.map(|v| v.0))
 }
 
 pub fn mem_length_rune_to_rune_id() -> u64 {
@@ -307,50 +653,231 @@ pub(crate) fn mem_remove_transaction_id_to_rune(txid: TxidValue) -> Option<u128>
   TRANSACTION_ID_TO_RUNE.with(|m| m.borrow_mut().remove(&txid))
 }
 
-pub fn mem_length_outpoint_to_height() -> u64 {
-  OUTPOINT_TO_HEIGHT.with(|m| m.borrow().len())
+pub fn mem_length_change_record() -> u64 {
+  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().len())
+    + LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().len())
 }
 
-pub fn mem_get_outpoint_to_height(outpoint: OutPointValue) -> Option<u32> {
-  OUTPOINT_TO_HEIGHT.with(|m| m.borrow().get(&outpoint))
+pub(crate) fn mem_insert_change_record(height: u32, change_record: ChangeRecord) {
+  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow_mut().insert(height, change_record));
+  LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow_mut().remove(&height));
 }
 
-pub fn mem_insert_outpoint_to_height(outpoint: OutPointValue, height: u32) {
-  OUTPOINT_TO_HEIGHT.with(|m| m.borrow_mut().insert(outpoint, height));
+pub(crate) fn mem_get_change_record(height: u32) -> Option<ChangeRecord> {
+  HEIGHT_TO_CHANGE_RECORD
+    .with(|m| m.borrow().get(&height))
+    .or_else(|| LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().get(&height).map(|v| v.0)))
 }
 
-pub(crate) fn mem_remove_outpoint_to_height(outpoint_value: OutPointValue) -> Option<u32> {
-  OUTPOINT_TO_HEIGHT.with(|m| m.borrow_mut().remove(&outpoint_value))
+pub(crate) fn mem_contains_change_record(height: u32) -> bool {
+  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().contains_key(&height))
+    || LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().contains_key(&height))
 }
 
-pub fn mem_length_change_record() -> u64 {
-  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().len())
+pub(crate) fn mem_remove_change_record(height: u32) -> Option<ChangeRecord> {
+  let legacy = LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow_mut().remove(&height));
+  HEIGHT_TO_CHANGE_RECORD
+    .with(|m| m.borrow_mut().remove(&height))
+    .or(legacy.map(|v| v.0))
 }
 
-pub(crate) fn mem_insert_change_record(height: u32, change_record: ChangeRecord) {
-  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow_mut().insert(height, change_record));
+pub(crate) fn mem_change_record_heights(height: u32) -> Vec<u32> {
+  let mut heights: Vec<u32> =
+    HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().range(..=height).map(|(h, _)| h).collect());
+  heights.extend(LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| {
+    m.borrow()
+      .range(..=height)
+      .map(|(h, _)| h)
+      .collect::<Vec<u32>>()
+  }));
+  heights.sort_unstable();
+  heights
 }
 
-pub(crate) fn mem_get_change_record(height: u32) -> Option<ChangeRecord> {
-  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().get(&height))
+pub fn mem_prune_change_record(height: u32) {
+  for key in mem_change_record_heights(height) {
+    mem_remove_change_record(key);
+  }
 }
 
-pub(crate) fn mem_remove_change_record(height: u32) -> Option<ChangeRecord> {
-  HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow_mut().remove(&height))
+pub fn mem_length_checkpoint() -> u64 {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow().len())
 }
 
-pub fn mem_prune_change_record(height: u32) {
-  HEIGHT_TO_CHANGE_RECORD.with(|m| {
+pub(crate) fn mem_get_checkpoint(height: u32) -> Option<Checkpoint> {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow().get(&height))
+}
+
+pub(crate) fn mem_latest_checkpoint() -> Option<(u32, Checkpoint)> {
+  let height = mem_checkpoint_heights().pop()?;
+  mem_get_checkpoint(height).map(|checkpoint| (height, checkpoint))
+}
+
+/// Removes every checkpoint, which stop being updated once checkpoints are
+/// disabled.
+pub fn mem_clear_checkpoints() {
+  for height in mem_checkpoint_heights() {
+    mem_remove_checkpoint(height);
+  }
+}
+
+pub(crate) fn mem_checkpoint_heights() -> Vec<u32> {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow().iter().map(|(h, _)| h).collect())
+}
+
+pub(crate) fn mem_insert_checkpoint(height: u32, checkpoint: Checkpoint) {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow_mut().insert(height, checkpoint));
+}
+
+pub(crate) fn mem_remove_checkpoint(height: u32) -> Option<Checkpoint> {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow_mut().remove(&height))
+}
+
+pub fn mem_length_taproot_outpoints() -> u64 {
+  OUTPOINT_TO_TAPROOT_HEIGHT.with(|m| m.borrow().len())
+}
+
+pub(crate) fn mem_get_taproot_outpoint_height(outpoint_value: OutPointValue) -> Option<u32> {
+  OUTPOINT_TO_TAPROOT_HEIGHT.with(|m| m.borrow().get(&outpoint_value))
+}
+
+pub(crate) fn mem_insert_taproot_outpoints(height: u32, outpoints: Vec<OutPoint>) {
+  OUTPOINT_TO_TAPROOT_HEIGHT.with(|m| {
     let mut map = m.borrow_mut();
-    let keys_to_remove: Vec<u32> = map
+    for outpoint in outpoints.iter() {
+      map.insert(outpoint.store(), height);
+    }
+  });
+  HEIGHT_TO_TAPROOT_OUTPOINTS.with(|m| {
+    m.borrow_mut()
+      .insert(height, TaprootOutPoints { outpoints })
+  });
+}
+
+pub(crate) fn mem_remove_taproot_outpoints(height: u32) {
+  if let Some(taproot_outpoints) =
+    HEIGHT_TO_TAPROOT_OUTPOINTS.with(|m| m.borrow_mut().remove(&height))
+  {
+    OUTPOINT_TO_TAPROOT_HEIGHT.with(|m| {
+      let mut map = m.borrow_mut();
+      for outpoint in taproot_outpoints.outpoints {
+        map.remove(&outpoint.store());
+      }
+    });
+  }
+}
+
+pub(crate) fn mem_prune_taproot_outpoints(height: u32) {
+  let heights: Vec<u32> = HEIGHT_TO_TAPROOT_OUTPOINTS.with(|m| {
+    m.borrow()
       .iter()
       .take_while(|(h, _)| *h <= height)
       .map(|(h, _)| h)
-      .collect();
-    for key in keys_to_remove {
-      map.remove(&key);
+      .collect()
+  });
+  for h in heights {
+    mem_remove_taproot_outpoints(h);
+  }
+}
+
+pub fn mem_length_tx_proofs() -> u64 {
+  TXID_TO_TX_PROOF.with(|m| m.borrow().len())
+}
+
+pub fn mem_get_tx_proof(txid: Txid) -> Option<TxProof> {
+  TXID_TO_TX_PROOF.with(|m| m.borrow().get(&txid.store()))
+}
+
+/// Checks that the transaction at `index` is committed to by the header of a
+/// block in the indexed best chain at `height` through the given merkle branch.
+/// Blocks indexed before proofs were enabled can't be checked.
+pub fn verify_tx_inclusion(
+  txid: Txid,
+  header: &Header,
+  height: u32,
+  index: u32,
+  branch: &[bitcoin::TxMerkleNode],
+) -> bool {
+  let Some(proof_txids) = HEIGHT_TO_PROOF_TXIDS.with(|m| m.borrow().get(&height)) else {
+    return false;
+  };
+
+  mem_block_hash(height) == Some(header.block_hash())
+    && proof::verify(
+      txid,
+      index,
+      branch,
+      proof_txids.tx_count,
+      header.merkle_root,
+    )
+}
+
+pub(crate) fn mem_insert_tx_proofs(height: u32, tx_count: u32, tx_proofs: Vec<(Txid, TxProof)>) {
+  let txids = tx_proofs.iter().map(|(txid, _)| *txid).collect();
+  TXID_TO_TX_PROOF.with(|m| {
+    let mut map = m.borrow_mut();
+    for (txid, tx_proof) in tx_proofs {
+      map.insert(txid.store(), tx_proof);
     }
   });
+  HEIGHT_TO_PROOF_TXIDS.with(|m| {
+    m.borrow_mut()
+      .insert(height, ProofTxids { txids, tx_count })
+  });
+}
+
+pub(crate) fn mem_remove_tx_proofs(height: u32) {
+  if let Some(proof_txids) = HEIGHT_TO_PROOF_TXIDS.with(|m| m.borrow_mut().remove(&height)) {
+    TXID_TO_TX_PROOF.with(|m| {
+      let mut map = m.borrow_mut();
+      for txid in proof_txids.txids {
+        map.remove(&txid.store());
+      }
+    });
+  }
+}
+
+pub fn mem_get_rune_transactions(height: u32) -> Option<RuneTransactions> {
+  HEIGHT_TO_RUNE_TRANSACTIONS.with(|m| m.borrow().get(&height))
+}
+
+pub(crate) fn mem_insert_rune_transactions(height: u32, transactions: RuneTransactions) {
+  HEIGHT_TO_RUNE_TRANSACTIONS.with(|m| m.borrow_mut().insert(height, transactions));
+}
+
+pub(crate) fn mem_remove_rune_transactions(height: u32) {
+  HEIGHT_TO_RUNE_TRANSACTIONS.with(|m| m.borrow_mut().remove(&height));
+}
+
+/// Removes the rune transactions of blocks at or below `height`.
+pub(crate) fn mem_prune_rune_transactions(height: u32) {
+  HEIGHT_TO_RUNE_TRANSACTIONS.with(|m| {
+    let mut map = m.borrow_mut();
+    let heights = map.range(..=height).map(|(h, _)| h).collect::<Vec<u32>>();
+    for h in heights {
+      map.remove(&h);
+    }
+  });
+}
+
+pub(crate) fn mem_get_index_progress() -> Option<(u32, IndexProgress)> {
+  HEIGHT_TO_INDEX_PROGRESS.with(|m| m.borrow().iter().next())
+}
+
+pub(crate) fn mem_insert_index_progress(height: u32, progress: IndexProgress) {
+  HEIGHT_TO_INDEX_PROGRESS.with(|m| m.borrow_mut().insert(height, progress));
+}
+
+pub(crate) fn mem_remove_index_progress(height: u32) -> Option<IndexProgress> {
+  HEIGHT_TO_INDEX_PROGRESS.with(|m| m.borrow_mut().remove(&height))
+}
+
+pub(crate) fn mem_clear_index_progress() {
+  let heights: Vec<u32> =
+    HEIGHT_TO_INDEX_PROGRESS.with(|m| m.borrow().iter().map(|(h, _)| h).collect());
+  for height in heights {
+    mem_remove_index_progress(height);
+  }
 }
 
 pub fn mem_get_etching(txid: Txid) -> Option<(RuneId, RuneEntry)> {
@@ -358,9 +885,7 @@ pub fn mem_get_etching(txid: Txid) -> Option<(RuneId, RuneEntry)> {
     m.borrow()
       .get(&Txid::store(txid))
       .and_then(|rune| RUNE_TO_RUNE_ID.with(|m| m.borrow().get(&rune)))
-      .and_then(|id| {
-        RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().get(&id).map(|e| (RuneId::load(id), e)))
-      })
+      .and_then(|id| mem_get_rune_id_to_rune_entry(id).map(|e| (RuneId::load(id), e)))
   })
 }
 
@@ -402,12 +927,8 @@ pub fn init_mainnet() {
   mem_insert_transaction_id_to_rune(etching.store(), rune.store());
 }
 
-pub fn next_block(network: BitcoinNetwork) -> (u32, Option<BlockHash>) {
+pub fn next_block() -> (u32, Option<BlockHash>) {
   mem_latest_block()
     .map(|(height, prev_blockhash)| (height + 1, Some(prev_blockhash)))
-    .unwrap_or(match network {
-      BitcoinNetwork::Mainnet => (Rune::first_rune_height(bitcoin::Network::Bitcoin), None),
-      BitcoinNetwork::Testnet => (Rune::first_rune_height(bitcoin::Network::Testnet4), None),
-      BitcoinNetwork::Regtest => (Rune::first_rune_height(bitcoin::Network::Regtest), None),
-    })
+    .unwrap_or_else(|| (mem_get_config().start_height(), None))
 }
@@ -0,0 +1,237 @@
+//! Roles of the principals allowed to manage the indexer, and the audit log
+//! of the privileged calls.
+//!
+//! Controllers have every role. Admins have every role too, and are the only
+//! ones granting roles and reading the audit log. Every authorized privileged
+//! update is recorded. Denied calls are not, so that anyone calling privileged
+//! methods can't push the genuine records out of the log. Queries can't change
+//! the state, so they are checked but not recorded.
+
+use super::*;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Records kept, older ones are dropped.
+const MAX_AUDIT_LOG_LENGTH: u64 = 10_000;
+
+/// Records returned by a single `get_audit_log`.
+pub const MAX_AUDIT_RECORDS_PER_REQUEST: u64 = 1_000;
+
+/// Recorded arguments are cut to this size.
+const MAX_ARGUMENTS_LENGTH: usize = 1_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+  /// Grants roles, reads the audit log and manages snapshots and archives.
+  Admin,
+  /// Starts and stops indexing and retries notifications.
+  Operator,
+  /// Changes the RPC url, subscribers and webhooks.
+  ConfigEditor,
+  /// Reads the subscribers, webhooks and storage and delivery stats.
+  Metrics,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Roles(BTreeMap<Principal, BTreeSet<Role>>);
+
+impl Storable for Roles {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+  pub index: u64,
+  /// Time of the call, in nanoseconds.
+  pub timestamp: u64,
+  pub caller: Principal,
+  pub method: String,
+  pub arguments: String,
+}
+
+impl Storable for AuditRecord {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+fn update_roles<T>(f: impl FnOnce(&mut BTreeMap<Principal, BTreeSet<Role>>) -> T) -> T {
+  ROLES.with(|c| {
+    let mut roles = c.borrow().get().clone();
+    let result = f(&mut roles.0);
+    c.borrow_mut().set(roles).unwrap();
+    result
+  })
+}
+
+pub fn roles(principal: &Principal) -> BTreeSet<Role> {
+  ROLES.with(|c| {
+    c.borrow()
+      .get()
+      .0
+      .get(principal)
+      .cloned()
+      .unwrap_or_default()
+  })
+}
+
+pub fn list_roles() -> Vec<(Principal, Vec<Role>)> {
+  ROLES.with(|c| {
+    c.borrow()
+      .get()
+      .0
+      .iter()
+      .map(|(principal, roles)| (*principal, roles.iter().copied().collect()))
+      .collect()
+  })
+}
+
+pub fn grant_role(principal: Principal, role: Role) {
+  update_roles(|roles| roles.entry(principal).or_default().insert(role));
+  log!(INFO, "{:?} granted to {}", role, principal);
+}
+
+pub fn revoke_role(principal: Principal, role: Role) -> Result<(), String> {
+  let revoked = update_roles(|roles| {
+    let revoked = roles.get_mut(&principal).is_some_and(|r| r.remove(&role));
+    if roles.get(&principal).is_some_and(|r| r.is_empty()) {
+      roles.remove(&principal);
+    }
+    revoked
+  });
+  if !revoked {
+    return Err(format!("{} doesn't have the {:?} role", principal, role));
+  }
+  log!(INFO, "{:?} revoked from {}", role, principal);
+  Ok(())
+}
+
+fn grants(roles: &BTreeSet<Role>, role: Role) -> bool {
+  roles.contains(&Role::Admin) || roles.contains(&role)
+}
+
+/// Checks that the caller is a controller or has the role.
+pub fn check(caller: &Principal, role: Role) -> Result<(), String> {
+  if ic_cdk::api::is_controller(caller) || grants(&roles(caller), role) {
+    Ok(())
+  } else {
+    Err("Not authorized".to_string())
+  }
+}
+
+/// Checks that the caller has the role, and records the call in the audit
+/// log if it does.
+pub fn authorize(
+  caller: Principal,
+  role: Role,
+  method: &str,
+  arguments: String,
+) -> Result<(), String> {
+  check(&caller, role)?;
+  record(caller, method, arguments, ic_cdk::api::time());
+  Ok(())
+}
+
+fn record(caller: Principal, method: &str, mut arguments: String, timestamp: u64) {
+  if arguments.len() > MAX_ARGUMENTS_LENGTH {
+    let mut end = MAX_ARGUMENTS_LENGTH;
+    while !arguments.is_char_boundary(end) {
+      end -= 1;
+    }
+    arguments.truncate(end);
+    arguments.push_str("...");
+  }
+  AUDIT_LOG.with(|m| {
+    let mut m = m.borrow_mut();
+    let index = m.iter().rev().next().map_or(0, |(index, _)| index + 1);
+    m.insert(
+      index,
+      AuditRecord {
+        index,
+        timestamp,
+        caller,
+        method: method.to_string(),
+        arguments,
+      },
+    );
+    if index >= MAX_AUDIT_LOG_LENGTH {
+      m.remove(&(index - MAX_AUDIT_LOG_LENGTH));
+    }
+  });
+}
+
+/// Records from `start` on, at most `length` and `MAX_AUDIT_RECORDS_PER_REQUEST`.
+pub fn get_audit_log(start: u64, length: u64) -> Vec<AuditRecord> {
+  let length = length.min(MAX_AUDIT_RECORDS_PER_REQUEST) as usize;
+  AUDIT_LOG.with(|m| {
+    m.borrow()
+      .range(start..)
+      .take(length)
+      .map(|(_, record)| record)
+      .collect()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn admins_have_every_role() {
+    let admin = BTreeSet::from([Role::Admin]);
+    let operator = BTreeSet::from([Role::Operator]);
+    assert!(grants(&admin, Role::ConfigEditor));
+    assert!(grants(&operator, Role::Operator));
+    assert!(!grants(&operator, Role::Metrics));
+    assert!(!grants(&BTreeSet::new(), Role::Metrics));
+  }
+
+  #[test]
+  fn roles_are_granted_and_revoked() {
+    let principal = Principal::from_slice(&[1]);
+    grant_role(principal, Role::Operator);
+    grant_role(principal, Role::Metrics);
+    assert_eq!(
+      roles(&principal),
+      BTreeSet::from([Role::Operator, Role::Metrics])
+    );
+
+    revoke_role(principal, Role::Operator).unwrap();
+    assert!(revoke_role(principal, Role::Operator).is_err());
+    revoke_role(principal, Role::Metrics).unwrap();
+    assert!(list_roles().is_empty());
+  }
+
+  #[test]
+  fn audit_log_keeps_the_latest_records() {
+    let caller = Principal::from_slice(&[1]);
+    for timestamp in 0..MAX_AUDIT_LOG_LENGTH + 2 {
+      record(caller, "stop", String::new(), timestamp);
+    }
+    let records = get_audit_log(0, 2);
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].index, 2);
+    assert_eq!(records[1].timestamp, 3);
+
+    record(caller, "set_bitcoin_rpc_url", "é".repeat(1_000), 0);
+    let last = get_audit_log(MAX_AUDIT_LOG_LENGTH + 2, 10).pop().unwrap();
+    assert_eq!(last.method, "set_bitcoin_rpc_url");
+    assert_eq!(last.arguments.len(), MAX_ARGUMENTS_LENGTH + 3);
+  }
+}
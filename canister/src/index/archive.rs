@@ -0,0 +1,313 @@
+//! Archive canisters holding the events that fell out of the event log.
+//!
+//! Events of blocks deeper than `EventLogConfig::archive_depth` are sent in
+//! batches to the newest archive and removed locally once it stored them.
+//! When the newest archive is full, or none exists yet, a new one is created
+//! with the wasm uploaded by a controller and installed as an archive of this
+//! canister. Archives store the events in the same map as the index, with the
+//! same indices, and serve them to the callbacks returned by `get_events` and
+//! `icrc3_get_blocks`.
+
+use super::*;
+use crate::config::{EventLogConfig, RunesIndexerArgs};
+use crate::logs::CRITICAL;
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::main::{
+  create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+  InstallCodeArgument,
+};
+use ic_stable_structures::storable::{Bound, Storable};
+use std::borrow::Cow;
+use std::cell::Cell;
+
+thread_local! {
+  static ARCHIVING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Archive {
+  pub canister_id: Principal,
+  pub start: u64,
+  pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Archives {
+  archives: Vec<Archive>,
+  /// Canister created for the next archive but not installed yet, reused
+  /// when the installation is retried.
+  pending: Option<Principal>,
+}
+
+impl Storable for Archives {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn archives() -> Vec<Archive> {
+  ARCHIVES.with(|c| c.borrow().get().archives.clone())
+}
+
+pub(crate) fn set_archives(archives: Vec<Archive>) {
+  update_archives(|state| state.archives = archives);
+}
+
+fn pending_archive() -> Option<Principal> {
+  ARCHIVES.with(|c| c.borrow().get().pending)
+}
+
+fn set_pending_archive(pending: Option<Principal>) {
+  update_archives(|state| state.pending = pending);
+}
+
+fn update_archives(f: impl FnOnce(&mut Archives)) {
+  ARCHIVES.with(|c| {
+    let mut state = c.borrow().get().clone();
+    f(&mut state);
+    c.borrow_mut().set(state).unwrap();
+  });
+}
+
+/// Clears `ARCHIVING` when the archiving task ends, even if it traps.
+struct ArchivingGuard;
+
+impl ArchivingGuard {
+  fn new() -> Self {
+    ARCHIVING.set(true);
+    Self
+  }
+}
+
+impl Drop for ArchivingGuard {
+  fn drop(&mut self) {
+    ARCHIVING.set(false);
+  }
+}
+
+/// Number of events moved to archives, all of them preceding the local ones.
+pub(crate) fn archived_length() -> u64 {
+  archives()
+    .last()
+    .map(|archive| archive.start + archive.length)
+    .unwrap_or_default()
+}
+
+pub fn set_archive_wasm(wasm: Vec<u8>) {
+  ARCHIVE_WASM.with(|c| c.borrow_mut().set(wasm)).unwrap();
+}
+
+/// Starts moving the events of blocks deeper than the archive depth unless
+/// a previous run is still going. Called after every indexed block.
+pub(crate) fn schedule() {
+  let Some(config) = mem_get_config().event_log else {
+    return;
+  };
+  if ARCHIVING.get() || ARCHIVE_WASM.with(|c| c.borrow().get().is_empty()) {
+    return;
+  }
+  let Some(tip) = mem_latest_block_height() else {
+    return;
+  };
+  let Some(height) = (tip + 1).checked_sub(config.archive_depth) else {
+    return;
+  };
+  let end = events::first_event_from(height).unwrap_or_else(events::log_length);
+  if end <= events::first_index() {
+    return;
+  }
+
+  let guard = ArchivingGuard::new();
+  ic_cdk::spawn(async move {
+    let _guard = guard;
+    if let Err(e) = archive_events(&config, end).await {
+      log!(CRITICAL, "failed to archive events: {:?}", e);
+    }
+  });
+}
+
+/// Moves the local events in `..end` to archives, batch by batch.
+async fn archive_events(config: &EventLogConfig, end: u64) -> Result<()> {
+  loop {
+    let start = events::first_index();
+    if start >= end {
+      return Ok(());
+    }
+
+    let mut archives = archives();
+    if archives
+      .last()
+      .is_none_or(|archive| archive.length >= config.max_events_per_archive)
+    {
+      let canister_id = create_archive(config).await?;
+      log!(
+        INFO,
+        "created archive {} for events from index {}",
+        canister_id,
+        start
+      );
+      archives.push(Archive {
+        canister_id,
+        start,
+        length: 0,
+      });
+      set_archives(archives.clone());
+    }
+    let archive = archives.last_mut().unwrap();
+
+    let length = (end - start)
+      .min(config.archive_batch_size.into())
+      .min(config.max_events_per_archive - archive.length);
+    let batch = events::get(start, start + length)
+      .into_iter()
+      .map(|(_, record)| record.to_bytes().into_owned())
+      .collect::<Vec<Vec<u8>>>();
+
+    ic_cdk::call::<_, (Result<(), String>,)>(
+      archive.canister_id,
+      "append_archived_events",
+      (start, batch),
+    )
+    .await
+    .map_err(|(code, message)| {
+      anyhow!(
+        "failed to call archive {}: {:?} {}",
+        archive.canister_id,
+        code,
+        message
+      )
+    })?
+    .0
+    .map_err(|e| anyhow!("archive {} rejected events: {}", archive.canister_id, e))?;
+
+    archive.length += length;
+    set_archives(archives);
+    events::remove_archived(start + length);
+  }
+}
+
+/// Creates and installs a new archive. The created canister is kept until it
+/// is installed, so a failed installation is retried on the same canister
+/// instead of creating another one.
+async fn create_archive(config: &EventLogConfig) -> Result<Principal> {
+  let canister_id = match pending_archive() {
+    Some(canister_id) => canister_id,
+    None => {
+      let (record,) = create_canister(
+        CreateCanisterArgument {
+          settings: Some(CanisterSettings {
+            controllers: Some(vec![ic_cdk::id()]),
+            ..Default::default()
+          }),
+        },
+        config.archive_cycles,
+      )
+      .await
+      .map_err(|(code, message)| anyhow!("failed to create archive: {:?} {}", code, message))?;
+      set_pending_archive(Some(record.canister_id));
+      record.canister_id
+    }
+  };
+
+  let args = RunesIndexerArgs::Init(Config {
+    network: mem_get_config().network,
+    archive_of: Some(ic_cdk::id()),
+    ..Default::default()
+  });
+  // the canister holds no events yet, and may already have the code if an
+  // earlier installation succeeded without its answer being received
+  install_code(InstallCodeArgument {
+    mode: CanisterInstallMode::Reinstall,
+    canister_id,
+    wasm_module: ARCHIVE_WASM.with(|c| c.borrow().get().clone()),
+    arg: candid::encode_one(args).unwrap(),
+  })
+  .await
+  .map_err(|(code, message)| {
+    anyhow!(
+      "failed to install archive {}: {:?} {}",
+      canister_id,
+      code,
+      message
+    )
+  })?;
+  set_pending_archive(None);
+
+  Ok(canister_id)
+}
+
+/// Stores events sent by the index this canister archives.
+pub fn append_archived_events(start: u64, events: Vec<Vec<u8>>) {
+  EVENTS.with(|m| {
+    let mut m = m.borrow_mut();
+    for (index, bytes) in (start..).zip(events) {
+      m.insert(index, EventRecord::from_bytes(Cow::Owned(bytes)));
+    }
+  });
+}
+
+/// Archives holding events in `start..end`, with the part of the range each
+/// one holds.
+pub fn archived_ranges(start: u64, end: u64) -> Vec<(Principal, u64, u64)> {
+  archives()
+    .into_iter()
+    .filter_map(|archive| {
+      let from = start.max(archive.start);
+      let to = end.min(archive.start + archive.length);
+      (from < to).then_some((archive.canister_id, from, to - from))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ranges_are_split_over_archives() {
+    let archive = |id: u8, start, length| Archive {
+      canister_id: Principal::from_slice(&[id]),
+      start,
+      length,
+    };
+    set_archives(vec![archive(0, 0, 100), archive(1, 100, 50)]);
+
+    assert_eq!(archived_length(), 150);
+    assert_eq!(
+      archived_ranges(90, 200),
+      vec![
+        (Principal::from_slice(&[0]), 90, 10),
+        (Principal::from_slice(&[1]), 100, 50)
+      ]
+    );
+    assert_eq!(
+      archived_ranges(10, 20),
+      vec![(Principal::from_slice(&[0]), 10, 10)]
+    );
+    assert!(archived_ranges(150, 200).is_empty());
+  }
+
+  #[test]
+  fn pending_archive_survives_archive_updates() {
+    set_pending_archive(Some(Principal::from_slice(&[7])));
+    set_archives(vec![]);
+    assert_eq!(pending_archive(), Some(Principal::from_slice(&[7])));
+    set_pending_archive(None);
+    assert_eq!(pending_archive(), None);
+  }
+
+  #[test]
+  fn archiving_flag_is_cleared_on_drop() {
+    {
+      let _guard = ArchivingGuard::new();
+      assert!(ARCHIVING.get());
+    }
+    assert!(!ARCHIVING.get());
+  }
+}
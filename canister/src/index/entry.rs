@@ -1,6 +1,12 @@
 use super::*;
+use crate::index::schema::{self, LegacyRecord, Versioned};
+use crate::index::staging::Staging;
+use crate::index::varint;
+use bitcoin::hash_types::TxMerkleNode;
+use bitcoin::ScriptBuf;
 use ic_stable_structures::storable::{Bound, Storable};
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug, PartialEq)]
@@ -297,15 +303,22 @@ impl Entry for RuneEntry {
 
 impl Storable for RuneEntry {
   fn to_bytes(&self) -> Cow<[u8]> {
-    let vec = bincode::serialize(self).unwrap();
-    Cow::Owned(vec)
+    Cow::Owned(schema::encode(self))
   }
 
   fn from_bytes(bytes: Cow<[u8]>) -> Self {
-    bincode::deserialize(&bytes).unwrap()
+    schema::decode(&bytes)
   }
 
-  const BOUND: Bound = Bound::Bounded {
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for RuneEntry {
+  const VERSION: u8 = 1;
+}
+
+impl LegacyRecord for RuneEntry {
+  const LEGACY_BOUND: Bound = Bound::Bounded {
     max_size: 307,
     is_fixed_size: false,
   };
@@ -357,6 +370,34 @@ impl Entry for SatPoint {
   }
 }
 
+pub(super) type BlockHashValue = [u8; 32];
+
+impl Entry for BlockHash {
+  type Value = BlockHashValue;
+
+  fn load(value: Self::Value) -> Self {
+    BlockHash::from_byte_array(value)
+  }
+
+  fn store(self) -> Self::Value {
+    BlockHash::to_byte_array(self)
+  }
+}
+
+pub(super) type ChainWorkValue = [u8; 32];
+
+impl Entry for Work {
+  type Value = ChainWorkValue;
+
+  fn load(value: Self::Value) -> Self {
+    Work::from_le_bytes(value)
+  }
+
+  fn store(self) -> Self::Value {
+    self.to_le_bytes()
+  }
+}
+
 pub(super) type TxidValue = [u8; 32];
 
 impl Entry for Txid {
@@ -382,7 +423,149 @@ pub struct RuneBalances {
   pub balances: Vec<RuneBalance>,
 }
 
-impl Storable for RuneBalances {
+impl LegacyRecord for RuneBalances {}
+
+/// Largest packed output stored inline, enough for four balances of typical
+/// size. Outputs with more or larger balances keep them in the overflow map.
+pub(crate) const MAX_PACKED_OUTPOINT_SIZE: u32 = 128;
+
+const INLINE_BALANCES: u8 = 0;
+const OVERFLOW_BALANCES: u8 = 1;
+
+/// The height and balances of a rune-bearing output. The first byte tells
+/// whether the balances are inline or in the overflow map, followed by the
+/// height as a varint and, if inline, the packed balances.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedOutPoint(Vec<u8>);
+
+impl PackedOutPoint {
+  /// Packs an output, also returning the packed balances if they are too
+  /// large to be stored inline.
+  pub(crate) fn pack(rune_balances: &RuneBalances, height: u32) -> (Self, Option<Vec<u8>>) {
+    let mut balances = Vec::new();
+    for balance in &rune_balances.balances {
+      varint::encode_to_vec(balance.rune_id.block.into(), &mut balances);
+      varint::encode_to_vec(balance.rune_id.tx.into(), &mut balances);
+      varint::encode_to_vec(balance.balance, &mut balances);
+    }
+
+    let mut bytes = vec![INLINE_BALANCES];
+    varint::encode_to_vec(height.into(), &mut bytes);
+
+    if bytes.len() + balances.len() <= MAX_PACKED_OUTPOINT_SIZE as usize {
+      bytes.extend(balances);
+      (Self(bytes), None)
+    } else {
+      bytes[0] = OVERFLOW_BALANCES;
+      (Self(bytes), Some(balances))
+    }
+  }
+
+  pub(crate) fn is_overflow(&self) -> bool {
+    self.0[0] == OVERFLOW_BALANCES
+  }
+
+  /// Unpacks the output, reading the balances from `overflow` if they are not
+  /// inline. Returns `None` if `overflow` has none.
+  pub(crate) fn unpack(
+    &self,
+    overflow: impl FnOnce() -> Option<Vec<u8>>,
+  ) -> Option<(RuneBalances, u32)> {
+    let (height, len) = varint::decode(&self.0[1..]).unwrap();
+    let balances = if self.is_overflow() {
+      unpack_balances(&overflow()?)
+    } else {
+      unpack_balances(&self.0[1 + len..])
+    };
+    Some((balances, u32::try_from(height).unwrap()))
+  }
+}
+
+fn unpack_balances(mut bytes: &[u8]) -> RuneBalances {
+  let mut values = Vec::new();
+  while !bytes.is_empty() {
+    let (n, len) = varint::decode(bytes).unwrap();
+    values.push(n);
+    bytes = &bytes[len..];
+  }
+  RuneBalances {
+    balances: values
+      .chunks_exact(3)
+      .map(|value| RuneBalance {
+        rune_id: RuneId {
+          block: u64::try_from(value[0]).unwrap(),
+          tx: u32::try_from(value[1]).unwrap(),
+        },
+        balance: value[2],
+      })
+      .collect(),
+  }
+}
+
+impl Storable for PackedOutPoint {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Borrowed(&self.0)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    Self(bytes.into_owned())
+  }
+
+  const BOUND: Bound = Bound::Bounded {
+    max_size: MAX_PACKED_OUTPOINT_SIZE,
+    is_fixed_size: false,
+  };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaprootOutPoints {
+  pub outpoints: Vec<OutPoint>,
+}
+
+impl Storable for TaprootOutPoints {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxProof {
+  pub block_hash: BlockHash,
+  pub index: u32,
+  pub branch: Vec<TxMerkleNode>,
+}
+
+impl Storable for TxProof {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The transactions of a block with inclusion proofs, kept for every block
+/// indexed with proofs enabled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofTxids {
+  pub txids: Vec<Txid>,
+  /// Number of transactions in the block, which fixes the depth of its
+  /// merkle tree.
+  pub tx_count: u32,
+}
+
+impl Storable for ProofTxids {
   fn to_bytes(&self) -> Cow<[u8]> {
     let vec = bincode::serialize(self).unwrap();
     Cow::Owned(vec)
@@ -416,7 +599,220 @@ impl ChangeRecord {
   }
 }
 
+impl ChangeRecord {
+  /// Folds the change record of a later block into this one, so that rolling
+  /// back the result restores the state from before this record's block.
+  pub fn fold(&mut self, later: ChangeRecord) {
+    let mut added_outpoints = self
+      .added_outpoints
+      .drain(..)
+      .collect::<HashSet<OutPoint>>();
+
+    for (outpoint, rune_balances, height) in later.removed_outpoints {
+      // outpoints created and spent within the folded range cancel out
+      if !added_outpoints.remove(&outpoint) {
+        self
+          .removed_outpoints
+          .push((outpoint, rune_balances, height));
+      }
+    }
+
+    self.added_outpoints = added_outpoints
+      .into_iter()
+      .chain(later.added_outpoints)
+      .collect();
+
+    for (rune_id, burned) in later.burned {
+      self.burned.entry(rune_id).or_insert(burned);
+    }
+
+    for (rune_id, mints) in later.mints {
+      self.mints.entry(rune_id).or_insert(mints);
+    }
+
+    self.added_runes.extend(later.added_runes);
+  }
+}
+
 impl Storable for ChangeRecord {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(schema::encode(self))
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    schema::decode(&bytes)
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for ChangeRecord {
+  const VERSION: u8 = 1;
+}
+
+impl LegacyRecord for ChangeRecord {}
+
+/// Change a block made to the rune state, derived from its `ChangeRecord`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RuneEvent {
+  Etched {
+    rune_id: RuneId,
+    rune: Rune,
+    txid: Txid,
+  },
+  Minted {
+    rune_id: RuneId,
+    mints: u128,
+    amount: u128,
+  },
+  Allocated {
+    outpoint: OutPoint,
+    rune_id: RuneId,
+    amount: u128,
+  },
+  Spent {
+    outpoint: OutPoint,
+    rune_id: RuneId,
+    amount: u128,
+  },
+  Burned {
+    rune_id: RuneId,
+    amount: u128,
+  },
+  /// The events in `start..start + length`, all of the block at the record's
+  /// height, were undone by a reorg.
+  Reverted {
+    start: u64,
+    length: u64,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventRecord {
+  pub height: u32,
+  /// Time of the block header, in seconds since the epoch.
+  pub timestamp: u32,
+  /// Hash of the previous event's ICRC-3 block, see `icrc3::block`.
+  pub parent_hash: Option<[u8; 32]>,
+  pub event: RuneEvent,
+}
+
+impl Storable for EventRecord {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(schema::encode(self))
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    schema::decode(&bytes)
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for EventRecord {
+  const VERSION: u8 = 1;
+}
+
+/// Rune changes made by a single transaction, sent to subscribers in block
+/// notifications.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuneTransaction {
+  pub txid: Txid,
+  pub etched: Option<(RuneId, Rune)>,
+  pub minted: Option<(RuneId, u128)>,
+  /// Balances allocated to the transaction's outputs, by output index.
+  pub allocated: Vec<(u32, RuneId, u128)>,
+  pub burned: Vec<(RuneId, u128)>,
+  /// Script pubkeys of the outputs in `allocated`.
+  pub scripts: Vec<(u32, ScriptBuf)>,
+}
+
+impl RuneTransaction {
+  pub fn new(txid: Txid) -> Self {
+    Self {
+      txid,
+      etched: None,
+      minted: None,
+      allocated: Vec::new(),
+      burned: Vec::new(),
+      scripts: Vec::new(),
+    }
+  }
+
+  pub fn script(&self, vout: u32) -> Option<&ScriptBuf> {
+    self
+      .scripts
+      .iter()
+      .find(|(output, _)| *output == vout)
+      .map(|(_, script)| script)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.etched.is_none()
+      && self.minted.is_none()
+      && self.allocated.is_empty()
+      && self.burned.is_empty()
+  }
+}
+
+/// Rune transactions of a block, in block order.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RuneTransactions {
+  pub transactions: Vec<RuneTransaction>,
+}
+
+impl Storable for RuneTransactions {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(schema::encode(self))
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    schema::decode(&bytes)
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for RuneTransactions {
+  const VERSION: u8 = 1;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+  pub header: Header,
+  pub runes: u64,
+  pub reserved_runes: u64,
+  /// Undoes every block indexed after the checkpoint up to the next one.
+  pub change_record: ChangeRecord,
+}
+
+impl Storable for Checkpoint {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(schema::encode(self))
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    schema::decode(&bytes)
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for Checkpoint {
+  const VERSION: u8 = 1;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct IndexProgress {
+  pub(crate) block_hash: BlockHash,
+  pub(crate) next_tx: u32,
+  pub(crate) runes: u64,
+  pub(crate) burned: Vec<(RuneId, u128)>,
+  pub(crate) change_record: ChangeRecord,
+  pub(crate) staging: Staging,
+}
+
+impl Storable for IndexProgress {
   fn to_bytes(&self) -> Cow<[u8]> {
     let vec = bincode::serialize(self).unwrap();
     Cow::Owned(vec)
@@ -428,3 +824,104 @@ impl Storable for ChangeRecord {
 
   const BOUND: Bound = Bound::Unbounded;
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn outpoint(vout: u32) -> OutPoint {
+    OutPoint {
+      txid: Txid::all_zeros(),
+      vout,
+    }
+  }
+
+  fn rune_balances(balance: u128) -> RuneBalances {
+    RuneBalances {
+      balances: vec![RuneBalance {
+        rune_id: RuneId { block: 1, tx: 0 },
+        balance,
+      }],
+    }
+  }
+
+  #[test]
+  fn fold_cancels_outpoints_created_and_spent_in_range() {
+    let mut record = ChangeRecord::new();
+    record.added_outpoints.push(outpoint(1));
+    record
+      .removed_outpoints
+      .push((outpoint(0), rune_balances(10), 5));
+
+    let mut later = ChangeRecord::new();
+    later
+      .removed_outpoints
+      .push((outpoint(1), rune_balances(10), 6));
+    later.added_outpoints.push(outpoint(2));
+
+    record.fold(later);
+
+    assert_eq!(record.added_outpoints, vec![outpoint(2)]);
+    assert_eq!(record.removed_outpoints.len(), 1);
+    assert_eq!(record.removed_outpoints[0].0, outpoint(0));
+  }
+
+  #[test]
+  fn fold_keeps_earliest_burned_and_mints() {
+    let id = RuneId { block: 1, tx: 0 };
+
+    let mut record = ChangeRecord::new();
+    record.burned.insert(id, 1);
+
+    let mut later = ChangeRecord::new();
+    later.burned.insert(id, 2);
+    later.mints.insert(id, 3);
+
+    record.fold(later);
+
+    assert_eq!(record.burned[&id], 1);
+    assert_eq!(record.mints[&id], 3);
+  }
+
+  #[test]
+  fn packed_outpoint_round_trip() {
+    let (packed, overflow) = PackedOutPoint::pack(&rune_balances(1_000), 840_000);
+    assert_eq!(overflow, None);
+    assert!(!packed.is_overflow());
+    // tag, three byte height, one byte block and tx, two byte balance
+    assert_eq!(packed.to_bytes().len(), 8);
+
+    let (unpacked, height) = packed.unpack(|| unreachable!()).unwrap();
+    assert_eq!(height, 840_000);
+    assert_eq!(unpacked.balances.len(), 1);
+    assert_eq!(unpacked.balances[0].rune_id, RuneId { block: 1, tx: 0 });
+    assert_eq!(unpacked.balances[0].balance, 1_000);
+  }
+
+  #[test]
+  fn large_balances_overflow() {
+    let rune_balances = RuneBalances {
+      balances: (0..10)
+        .map(|tx| RuneBalance {
+          rune_id: RuneId { block: 840_000, tx },
+          balance: u128::MAX,
+        })
+        .collect(),
+    };
+    let (packed, overflow) = PackedOutPoint::pack(&rune_balances, 840_000);
+    assert!(packed.is_overflow());
+
+    assert!(packed.unpack(|| None).is_none());
+    let (unpacked, height) = packed.unpack(|| overflow).unwrap();
+    assert_eq!(height, 840_000);
+    assert_eq!(unpacked.balances.len(), 10);
+    assert_eq!(
+      unpacked.balances[9].rune_id,
+      RuneId {
+        block: 840_000,
+        tx: 9
+      }
+    );
+    assert_eq!(unpacked.balances[9].balance, u128::MAX);
+  }
+}
@@ -0,0 +1,245 @@
+//! Log of the rune events of every indexed block.
+//!
+//! Events are numbered from 0 in the order they are appended. The events of a
+//! block are derived from its `ChangeRecord` when the block is committed. The
+//! log is append-only: each event records the hash of the previous one, see
+//! `icrc3`, and a block reverted by a reorg is undone by a `Reverted` event.
+//! Older events are moved to archive canisters, see `archive`, so only a
+//! suffix of the log is stored here.
+
+use super::*;
+use crate::index::entry::RuneEvent;
+use crate::logs::WARNING;
+
+/// Events returned by a single request, locally or from an archive.
+pub const MAX_EVENTS_PER_REQUEST: u64 = 2_000;
+
+/// Number of events logged so far, including the archived ones.
+pub fn log_length() -> u64 {
+  EVENTS
+    .with(|m| m.borrow().iter().rev().next().map(|(index, _)| index + 1))
+    .unwrap_or_else(archive::archived_length)
+}
+
+/// Index of the first event stored by this canister.
+pub fn first_index() -> u64 {
+  EVENTS
+    .with(|m| m.borrow().iter().next().map(|(index, _)| index))
+    .unwrap_or_else(log_length)
+}
+
+/// Events stored by this canister in `start..end`.
+pub fn get(start: u64, end: u64) -> Vec<(u64, EventRecord)> {
+  if start >= end {
+    return Vec::new();
+  }
+  EVENTS.with(|m| m.borrow().range(start..end).collect())
+}
+
+/// Index of the first event of the first block at or above `height`.
+pub(crate) fn first_event_from(height: u32) -> Option<u64> {
+  HEIGHT_TO_FIRST_EVENT.with(|m| m.borrow().range(height..).next().map(|(_, index)| index))
+}
+
+/// Hash of the last event's ICRC-3 block.
+pub fn last_hash() -> Option<[u8; 32]> {
+  LAST_EVENT_HASH.with(|c| c.borrow().get().as_slice().try_into().ok())
+}
+
+/// Appends the events of the block at `height`, chaining each one to the
+/// previous event.
+pub(crate) fn append(height: u32, timestamp: u32, events: Vec<RuneEvent>) {
+  if events.is_empty() {
+    return;
+  }
+  let start = log_length();
+  HEIGHT_TO_FIRST_EVENT.with(|m| m.borrow_mut().insert(height, start));
+  push(start, height, timestamp, events);
+}
+
+fn push(start: u64, height: u32, timestamp: u32, events: Vec<RuneEvent>) {
+  let mut parent_hash = last_hash();
+  EVENTS.with(|m| {
+    let mut m = m.borrow_mut();
+    for (index, event) in (start..).zip(events) {
+      let record = EventRecord {
+        height,
+        timestamp,
+        parent_hash,
+        event,
+      };
+      parent_hash = Some(icrc3::block(&record).hash());
+      m.insert(index, record);
+    }
+  });
+  if let Some(hash) = parent_hash {
+    LAST_EVENT_HASH
+      .with(|c| c.borrow_mut().set(hash.to_vec()))
+      .unwrap();
+  }
+}
+
+/// Appends a `Reverted` event undoing the events of the block at `height`,
+/// which must be the last block with events, and whose header must still be
+/// stored.
+pub(crate) fn revert_block(height: u32) {
+  let Some(first) = HEIGHT_TO_FIRST_EVENT.with(|m| m.borrow_mut().remove(&height)) else {
+    return;
+  };
+  // the block's events are contiguous, later ones belong to other blocks or
+  // revert them
+  let stored = EVENTS.with(|m| {
+    m.borrow()
+      .range(first..)
+      .take_while(|(_, record)| {
+        record.height == height && !matches!(record.event, RuneEvent::Reverted { .. })
+      })
+      .count() as u64
+  });
+  // `archive_depth` keeps this from happening, but if the first events were
+  // archived, everything from `first` up to the stored ones is the block's
+  let archived = first_index().saturating_sub(first);
+  if archived > 0 {
+    log!(
+      WARNING,
+      "reverted block {} has {} events in an archive from index {}",
+      height,
+      archived,
+      first
+    );
+  }
+  let timestamp = mem_get_block_header(height).map_or(0, |header| header.time);
+  push(
+    log_length(),
+    height,
+    timestamp,
+    vec![RuneEvent::Reverted {
+      start: first,
+      length: archived + stored,
+    }],
+  );
+}
+
+/// Removes the events in `..end` once they are archived.
+pub(crate) fn remove_archived(end: u64) {
+  EVENTS.with(|m| {
+    let mut m = m.borrow_mut();
+    let indices = m.range(..end).map(|(index, _)| index).collect::<Vec<u64>>();
+    for index in indices {
+      m.remove(&index);
+    }
+  });
+  HEIGHT_TO_FIRST_EVENT.with(|m| {
+    let mut m = m.borrow_mut();
+    // keep the last archived block, whose events may continue past `end`
+    let heights = m
+      .iter()
+      .take_while(|(_, index)| *index < end)
+      .map(|(height, _)| height)
+      .collect::<Vec<u32>>();
+    for height in heights.iter().take(heights.len().saturating_sub(1)) {
+      m.remove(height);
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn burned(amount: u128) -> RuneEvent {
+    RuneEvent::Burned {
+      rune_id: RuneId {
+        block: 840_000,
+        tx: 1,
+      },
+      amount,
+    }
+  }
+
+  #[test]
+  fn blocks_are_appended_and_reverted() {
+    append(100, 1, vec![burned(1), burned(2)]);
+    append(101, 2, Vec::new());
+    append(102, 3, vec![burned(3)]);
+    assert_eq!(log_length(), 3);
+    assert_eq!(first_event_from(101), Some(2));
+
+    revert_block(102);
+    revert_block(101);
+    assert_eq!(log_length(), 4);
+    assert_eq!(first_event_from(101), None);
+
+    append(101, 4, vec![burned(4)]);
+    let records = get(0, 10);
+    assert_eq!(
+      records
+        .iter()
+        .map(|(index, record)| (*index, record.height))
+        .collect::<Vec<(u64, u32)>>(),
+      vec![(0, 100), (1, 100), (2, 102), (3, 102), (4, 101)]
+    );
+    assert_eq!(
+      records[3].1.event,
+      RuneEvent::Reverted {
+        start: 2,
+        length: 1
+      }
+    );
+  }
+
+  #[test]
+  fn events_are_chained() {
+    append(100, 1, vec![burned(1), burned(2)]);
+    revert_block(100);
+
+    let records = get(0, 10);
+    assert_eq!(records[0].1.parent_hash, None);
+    for pair in records.windows(2) {
+      assert_eq!(pair[1].1.parent_hash, Some(icrc3::block(&pair[0].1).hash()));
+    }
+    assert_eq!(last_hash(), Some(icrc3::block(&records[2].1).hash()));
+  }
+
+  #[test]
+  fn archived_blocks_are_reverted() {
+    append(100, 1, vec![burned(1)]);
+    append(101, 2, vec![burned(2), burned(3), burned(4)]);
+    archive::set_archives(vec![archive::Archive {
+      canister_id: candid::Principal::anonymous(),
+      start: 0,
+      length: 3,
+    }]);
+    remove_archived(3);
+
+    revert_block(101);
+    assert_eq!(log_length(), 5);
+    assert_eq!(
+      get(0, 10).pop().unwrap().1.event,
+      RuneEvent::Reverted {
+        start: 1,
+        length: 3
+      }
+    );
+  }
+
+  #[test]
+  fn archived_events_are_removed() {
+    append(100, 1, vec![burned(1), burned(2)]);
+    append(101, 2, vec![burned(3)]);
+    remove_archived(1);
+    assert_eq!(first_index(), 1);
+    assert_eq!(log_length(), 3);
+    assert_eq!(first_event_from(100), Some(0));
+
+    archive::set_archives(vec![archive::Archive {
+      canister_id: candid::Principal::anonymous(),
+      start: 0,
+      length: 3,
+    }]);
+    remove_archived(3);
+    assert_eq!(first_index(), 3);
+    assert_eq!(log_length(), 3);
+    assert_eq!(first_event_from(100), Some(2));
+  }
+}
@@ -0,0 +1,345 @@
+//! Chunked export of the stable state for backups and migrations.
+//!
+//! Every entry is exported as the `Storable` bytes of its key and value, the
+//! same encoding the canister stores it with. Cells are exported as a single
+//! entry with an empty key. A chunk's checksum is the sha256 of its version
+//! followed by each entry, all lengths and the version being little-endian u32:
+//!
+//! `version || len(key_0) || key_0 || len(value_0) || value_0 || ...`
+//!
+//! Values of the versioned maps start with their version byte, values of the
+//! legacy maps are untagged, see `schema`. `OutPointToRuneBalances` holds
+//! `PackedOutPoint` values, with the packed balances of large outputs in
+//! `OverflowBalances`.
+//!
+//! Webhooks are exported with only the hosts of their urls and empty secrets,
+//! the urls and secrets have to be set again when the webhooks are restored.
+//!
+//! `ArchiveWasm` is exported in pieces of at most `CHUNK_BYTES`, each keyed
+//! by its offset as a big-endian u64.
+//!
+//! Pass a chunk's `next_cursor` back to get the following chunk of the same map.
+//! The export is complete when `next_cursor` is `null`.
+
+use super::*;
+use candid::CandidType;
+use ic_stable_structures::storable::{Bound as StorableBound, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::ops::Bound;
+use std::thread::LocalKey;
+
+/// Version of the export format, bumped when the chunk layout or the encoding
+/// of any exported map changes.
+pub const EXPORT_FORMAT_VERSION: u32 = 3;
+
+/// Size of the keys and values of a chunk, keeping the response under the
+/// query reply limit. A larger entry is exported in a chunk of its own.
+const CHUNK_BYTES: usize = 1_500_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateMap {
+  Config,
+  BlockHeaders,
+  StatisticReservedRunes,
+  StatisticRunes,
+  OutPointToRuneBalances,
+  RuneIdToRuneEntry,
+  RuneToRuneId,
+  TransactionIdToRune,
+  OutPointToHeight,
+  ChangeRecords,
+  TaprootOutPointHeights,
+  TaprootOutPoints,
+  IndexProgress,
+  Checkpoints,
+  BlockHashToHeight,
+  ChainWork,
+  TxProofs,
+  ProofTxids,
+  SnapshotProgress,
+  SchemaVersion,
+  LegacyOutPointToRuneBalances,
+  LegacyRuneIdToRuneEntry,
+  LegacyChangeRecords,
+  OverflowBalances,
+  Events,
+  EventHeights,
+  Archives,
+  LastEventHash,
+  RuneTransactions,
+  Outbox,
+  OutboxCursors,
+  Subscriptions,
+  SubscriberSettings,
+  Webhooks,
+  Roles,
+  AuditLog,
+  ArchiveWasm,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportEntry {
+  pub key: Vec<u8>,
+  pub value: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportChunk {
+  pub version: u32,
+  pub map: StateMap,
+  pub entries: Vec<ExportEntry>,
+  /// Hex-encoded sha256 checksum of the chunk.
+  pub checksum: String,
+  pub next_cursor: Option<Vec<u8>>,
+}
+
+/// Fails on a cursor that wasn't returned for the map.
+pub fn export_state(map: StateMap, cursor: Option<Vec<u8>>) -> Result<ExportChunk, String> {
+  let (entries, next_cursor) = match map {
+    StateMap::Config => Ok(export_cell(&CONFIG)),
+    StateMap::BlockHeaders => export_map(&HEIGHT_TO_BLOCK_HEADER, cursor),
+    StateMap::StatisticReservedRunes => export_map(&HEIGHT_TO_STATISTIC_RESERVED_RUNES, cursor),
+    StateMap::StatisticRunes => export_map(&HEIGHT_TO_STATISTIC_RUNES, cursor),
+    StateMap::OutPointToRuneBalances => export_map(&OUTPOINT_TO_BALANCES, cursor),
+    StateMap::RuneIdToRuneEntry => export_map(&RUNE_ID_TO_RUNE_ENTRY, cursor),
+    StateMap::RuneToRuneId => export_map(&RUNE_TO_RUNE_ID, cursor),
+    StateMap::TransactionIdToRune => export_map(&TRANSACTION_ID_TO_RUNE, cursor),
+    StateMap::OutPointToHeight => export_map(&LEGACY_OUTPOINT_TO_HEIGHT, cursor),
+    StateMap::ChangeRecords => export_map(&HEIGHT_TO_CHANGE_RECORD, cursor),
+    StateMap::TaprootOutPointHeights => export_map(&OUTPOINT_TO_TAPROOT_HEIGHT, cursor),
+    StateMap::TaprootOutPoints => export_map(&HEIGHT_TO_TAPROOT_OUTPOINTS, cursor),
+    StateMap::IndexProgress => export_map(&HEIGHT_TO_INDEX_PROGRESS, cursor),
+    StateMap::Checkpoints => export_map(&HEIGHT_TO_CHECKPOINT, cursor),
+    StateMap::BlockHashToHeight => export_map(&BLOCK_HASH_TO_HEIGHT, cursor),
+    StateMap::ChainWork => export_map(&HEIGHT_TO_CHAIN_WORK, cursor),
+    StateMap::TxProofs => export_map(&TXID_TO_TX_PROOF, cursor),
+    StateMap::ProofTxids => export_map(&HEIGHT_TO_PROOF_TXIDS, cursor),
+    StateMap::SnapshotProgress => Ok(export_cell(&SNAPSHOT_PROGRESS)),
+    StateMap::SchemaVersion => Ok(export_cell(&STORED_SCHEMA_VERSION)),
+    StateMap::LegacyOutPointToRuneBalances => export_map(&LEGACY_OUTPOINT_TO_RUNE_BALANCES, cursor),
+    StateMap::LegacyRuneIdToRuneEntry => export_map(&LEGACY_RUNE_ID_TO_RUNE_ENTRY, cursor),
+    StateMap::LegacyChangeRecords => export_map(&LEGACY_HEIGHT_TO_CHANGE_RECORD, cursor),
+    StateMap::OverflowBalances => export_map(&OUTPOINT_TO_OVERFLOW_BALANCES, cursor),
+    StateMap::Events => export_map(&EVENTS, cursor),
+    StateMap::EventHeights => export_map(&HEIGHT_TO_FIRST_EVENT, cursor),
+    StateMap::Archives => Ok(export_cell(&ARCHIVES)),
+    StateMap::LastEventHash => Ok(export_cell(&LAST_EVENT_HASH)),
+    StateMap::RuneTransactions => export_map(&HEIGHT_TO_RUNE_TRANSACTIONS, cursor),
+    StateMap::Outbox => export_map(&OUTBOX, cursor),
+    StateMap::OutboxCursors => Ok(export_cell(&OUTBOX_CURSORS)),
+    StateMap::Subscriptions => Ok(export_cell(&SUBSCRIPTIONS)),
+    StateMap::SubscriberSettings => Ok(export_cell(&SUBSCRIBER_SETTINGS)),
+    StateMap::Webhooks => Ok(export_value(&webhooks::redacted())),
+    StateMap::Roles => Ok(export_cell(&ROLES)),
+    StateMap::AuditLog => export_map(&AUDIT_LOG, cursor),
+    StateMap::ArchiveWasm => ARCHIVE_WASM.with(|c| export_bytes(c.borrow().get(), cursor)),
+  }?;
+
+  Ok(ExportChunk {
+    version: EXPORT_FORMAT_VERSION,
+    map,
+    checksum: hex::encode(checksum(EXPORT_FORMAT_VERSION, &entries)),
+    entries,
+    next_cursor,
+  })
+}
+
+fn export_map<K, V>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+  cursor: Option<Vec<u8>>,
+) -> Result<(Vec<ExportEntry>, Option<Vec<u8>>), String>
+where
+  K: Storable + Ord + Clone,
+  V: Storable,
+{
+  let start = match cursor {
+    Some(cursor) => Bound::Excluded(parse_cursor::<K>(cursor)?),
+    None => Bound::Unbounded,
+  };
+
+  Ok(map.with(|m| {
+    let mut entries = Vec::new();
+    let mut bytes = 0;
+    for (key, value) in m.borrow().range((start, Bound::Unbounded)) {
+      let entry = ExportEntry {
+        key: key.to_bytes().into_owned(),
+        value: value.to_bytes().into_owned(),
+      };
+      let size = entry.key.len() + entry.value.len();
+      if !entries.is_empty() && bytes + size > CHUNK_BYTES {
+        let next_cursor = entries.last().map(|entry: &ExportEntry| entry.key.clone());
+        return (entries, next_cursor);
+      }
+      bytes += size;
+      entries.push(entry);
+    }
+    (entries, None)
+  }))
+}
+
+/// Decodes a cursor, refusing bytes that no key of the map is stored as
+/// instead of trapping on them.
+fn parse_cursor<K: Storable>(cursor: Vec<u8>) -> Result<K, String> {
+  let valid = match K::BOUND {
+    StorableBound::Bounded {
+      max_size,
+      is_fixed_size,
+    } => cursor.len() == max_size as usize || (!is_fixed_size && cursor.len() < max_size as usize),
+    StorableBound::Unbounded => true,
+  };
+  if !valid {
+    return Err(format!("invalid cursor of {} bytes", cursor.len()));
+  }
+  Ok(K::from_bytes(Cow::Owned(cursor)))
+}
+
+/// Pieces of `bytes` from the offset in `cursor` on.
+fn export_bytes(
+  bytes: &[u8],
+  cursor: Option<Vec<u8>>,
+) -> Result<(Vec<ExportEntry>, Option<Vec<u8>>), String> {
+  let start = match cursor {
+    Some(cursor) => parse_cursor::<u64>(cursor)?,
+    None => 0,
+  };
+  let start = usize::try_from(start)
+    .ok()
+    .filter(|start| *start <= bytes.len())
+    .ok_or_else(|| format!("offset {} is past the end", start))?;
+  let end = bytes.len().min(start + CHUNK_BYTES);
+  let entries = if start < end {
+    vec![ExportEntry {
+      key: (start as u64).to_be_bytes().to_vec(),
+      value: bytes[start..end].to_vec(),
+    }]
+  } else {
+    Vec::new()
+  };
+  let next_cursor = (end < bytes.len()).then(|| (end as u64).to_be_bytes().to_vec());
+  Ok((entries, next_cursor))
+}
+
+fn export_cell<V: Storable>(
+  cell: &'static LocalKey<RefCell<StableCell<V, Memory>>>,
+) -> (Vec<ExportEntry>, Option<Vec<u8>>) {
+  cell.with(|c| export_value(c.borrow().get()))
+}
+
+fn export_value<V: Storable>(value: &V) -> (Vec<ExportEntry>, Option<Vec<u8>>) {
+  (
+    vec![ExportEntry {
+      key: Vec::new(),
+      value: value.to_bytes().into_owned(),
+    }],
+    None,
+  )
+}
+
+fn checksum(version: u32, entries: &[ExportEntry]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update(version.to_le_bytes());
+  for entry in entries {
+    hasher.update(u32::try_from(entry.key.len()).unwrap().to_le_bytes());
+    hasher.update(&entry.key);
+    hasher.update(u32::try_from(entry.value.len()).unwrap().to_le_bytes());
+    hasher.update(&entry.value);
+  }
+  hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn checksum_covers_entry_boundaries() {
+    let split = |at: usize| {
+      let data = b"abcd".to_vec();
+      vec![ExportEntry {
+        key: data[..at].to_vec(),
+        value: data[at..].to_vec(),
+      }]
+    };
+    assert_ne!(
+      checksum(EXPORT_FORMAT_VERSION, &split(1)),
+      checksum(EXPORT_FORMAT_VERSION, &split(2))
+    );
+    assert_ne!(
+      checksum(EXPORT_FORMAT_VERSION, &split(1)),
+      checksum(EXPORT_FORMAT_VERSION + 1, &split(1))
+    );
+  }
+
+  use bitcoin::hashes::Hash;
+  use bitcoin::Txid;
+
+  #[test]
+  fn malformed_cursors_are_refused() {
+    assert!(export_state(StateMap::BlockHeaders, Some(vec![1, 2, 3])).is_err());
+    assert!(export_state(StateMap::OutPointToRuneBalances, Some(vec![0; 4])).is_err());
+    let chunk = export_state(StateMap::BlockHeaders, Some(vec![0; 4])).unwrap();
+    assert!(chunk.entries.is_empty());
+    assert_eq!(chunk.next_cursor, None);
+  }
+
+  #[test]
+  fn chunks_stay_below_the_size_limit() {
+    for height in 0..4 {
+      crate::index::mem_insert_rune_transactions(
+        height,
+        RuneTransactions {
+          transactions: (0..20_000)
+            .map(|_| crate::index::entry::RuneTransaction::new(Txid::all_zeros()))
+            .collect(),
+        },
+      );
+    }
+    let mut cursor = None;
+    let mut entries = 0;
+    let mut chunks = 0;
+    loop {
+      let chunk = export_state(StateMap::RuneTransactions, cursor).unwrap();
+      let size = chunk
+        .entries
+        .iter()
+        .map(|entry| entry.key.len() + entry.value.len())
+        .sum::<usize>();
+      assert!(size <= CHUNK_BYTES);
+      entries += chunk.entries.len();
+      chunks += 1;
+      cursor = chunk.next_cursor;
+      if cursor.is_none() {
+        break;
+      }
+    }
+    assert_eq!(entries, 4);
+    assert!(chunks >= 2);
+  }
+
+  #[test]
+  fn archive_wasm_is_exported_in_pieces() {
+    let wasm = (0..CHUNK_BYTES + 10).map(|i| i as u8).collect::<Vec<u8>>();
+    archive::set_archive_wasm(wasm.clone());
+    let first = export_state(StateMap::ArchiveWasm, None).unwrap();
+    let rest = export_state(StateMap::ArchiveWasm, first.next_cursor.clone()).unwrap();
+    assert_eq!(rest.next_cursor, None);
+    assert_eq!(
+      [
+        first.entries[0].value.clone(),
+        rest.entries[0].value.clone()
+      ]
+      .concat(),
+      wasm
+    );
+    assert!(export_state(StateMap::ArchiveWasm, Some(u64::MAX.to_be_bytes().to_vec())).is_err());
+  }
+
+  #[test]
+  fn empty_chunk_checksum() {
+    assert_eq!(
+      hex::encode(checksum(1, &[])),
+      "67abdd721024f0ff4e0b3f4c2fc13bc5bad42d0b7851d456d88d203d15aaa450"
+    );
+  }
+}
@@ -0,0 +1,370 @@
+use crate::chain::ChainParams;
+use bitcoin::block::Header;
+use bitcoin::pow::CompactTarget;
+use std::fmt::{self, Display, Formatter};
+
+/// Number of ancestors whose median timestamp a header must exceed.
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// Maximum time a block on testnet4 may be dated before the last block of the
+/// previous difficulty period (BIP94).
+const MAX_TIMEWARP: u32 = 600;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Error {
+  BadPrevBlockhash,
+  TimeTooOld { time: u32, median_time_past: u32 },
+  Timewarp,
+  BadDifficultyBits { expected: u32, actual: u32 },
+  TargetTooHigh,
+  BadProofOfWork,
+}
+
+impl Display for Error {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self {
+      Self::BadPrevBlockhash => write!(f, "previous block hash does not match the indexed chain"),
+      Self::TimeTooOld {
+        time,
+        median_time_past,
+      } => write!(
+        f,
+        "block time {time} is not after median time past {median_time_past}"
+      ),
+      Self::Timewarp => write!(f, "block time violates the timewarp rule"),
+      Self::BadDifficultyBits { expected, actual } => {
+        write!(
+          f,
+          "incorrect difficulty bits {actual:#010x}, expected {expected:#010x}"
+        )
+      }
+      Self::TargetTooHigh => write!(f, "target is above the proof of work limit"),
+      Self::BadProofOfWork => write!(f, "block hash does not meet the target"),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+fn interval(chain: &ChainParams) -> u32 {
+  chain.consensus.difficulty_adjustment_interval() as u32
+}
+
+fn pow_limit(chain: &ChainParams) -> CompactTarget {
+  chain.consensus.max_attainable_target.to_compact_lossy()
+}
+
+/// Validates the header at `height` against the indexed headers.
+pub(crate) fn validate_header(
+  chain: &ChainParams,
+  height: u32,
+  header: &Header,
+) -> Result<(), Error> {
+  check_header(chain, height, header, crate::index::mem_get_block_header)
+}
+
+/// Checks a header against its ancestors, returned by `ancestor` for each
+/// height. The indexed chain doesn't start at genesis, so checks that need
+/// ancestors older than the first indexed header are skipped.
+pub(crate) fn check_header(
+  chain: &ChainParams,
+  height: u32,
+  header: &Header,
+  ancestor: impl Fn(u32) -> Option<Header>,
+) -> Result<(), Error> {
+  let prev = height.checked_sub(1).and_then(&ancestor);
+
+  if let Some(prev) = prev {
+    if header.prev_blockhash != prev.block_hash() {
+      return Err(Error::BadPrevBlockhash);
+    }
+
+    if let Some(median_time_past) = median_time_past(height, &ancestor) {
+      if header.time <= median_time_past {
+        return Err(Error::TimeTooOld {
+          time: header.time,
+          median_time_past,
+        });
+      }
+    }
+
+    if chain.enforce_bip94
+      && height % interval(chain) == 0
+      && header.time < prev.time.saturating_sub(MAX_TIMEWARP)
+    {
+      return Err(Error::Timewarp);
+    }
+
+    if let Some(expected) = required_bits(chain, height, header, &prev, &ancestor) {
+      if header.bits != expected {
+        return Err(Error::BadDifficultyBits {
+          expected: expected.to_consensus(),
+          actual: header.bits.to_consensus(),
+        });
+      }
+    }
+  }
+
+  if header.target() > chain.consensus.max_attainable_target {
+    return Err(Error::TargetTooHigh);
+  }
+
+  header
+    .validate_pow(header.target())
+    .map_err(|_| Error::BadProofOfWork)?;
+
+  Ok(())
+}
+
+/// Returns the median time of the `MEDIAN_TIME_SPAN` blocks before `height`,
+/// or of all of them near genesis, or `None` if any of them is not indexed.
+fn median_time_past(height: u32, ancestor: impl Fn(u32) -> Option<Header>) -> Option<u32> {
+  let mut times = (1..=MEDIAN_TIME_SPAN.min(height))
+    .map(|depth| ancestor(height - depth).map(|header| header.time))
+    .collect::<Option<Vec<u32>>>()?;
+  times.sort_unstable();
+  times.get(times.len() / 2).copied()
+}
+
+/// Returns the bits the header at `height` must carry, or `None` if the
+/// ancestors needed to tell are not indexed.
+fn required_bits(
+  chain: &ChainParams,
+  height: u32,
+  header: &Header,
+  prev: &Header,
+  ancestor: impl Fn(u32) -> Option<Header>,
+) -> Option<CompactTarget> {
+  let interval = interval(chain);
+  let params = &chain.consensus;
+
+  if height % interval != 0 {
+    if !params.allow_min_difficulty_blocks {
+      return Some(prev.bits);
+    }
+
+    // a block more than twice the target spacing after its parent may be
+    // mined at minimum difficulty
+    if u64::from(header.time) > u64::from(prev.time) + params.pow_target_spacing * 2 {
+      return Some(pow_limit(chain));
+    }
+
+    // otherwise it carries the bits of the last block not mined at minimum
+    // difficulty in the current period
+    let mut h = height - 1;
+    let mut bits = prev.bits;
+    while h % interval != 0 && bits == pow_limit(chain) {
+      h -= 1;
+      bits = ancestor(h)?.bits;
+    }
+    return Some(bits);
+  }
+
+  if params.no_pow_retargeting {
+    return Some(prev.bits);
+  }
+
+  let first = ancestor(height - interval)?;
+  let timespan = u64::from(prev.time.saturating_sub(first.time));
+  let bits = if chain.enforce_bip94 {
+    first.bits
+  } else {
+    prev.bits
+  };
+
+  Some(CompactTarget::from_next_work_required(
+    bits, timespan, params,
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::Chain;
+  use bitcoin::blockdata::constants::genesis_block;
+  use bitcoin::consensus::encode::deserialize_hex;
+
+  const BLOCK_1: &str = "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299";
+
+  fn mainnet() -> ChainParams {
+    ChainParams::new(&Chain::Mainnet)
+  }
+
+  fn headers() -> (Header, Header) {
+    (
+      genesis_block(bitcoin::Network::Bitcoin).header,
+      deserialize_hex(BLOCK_1).unwrap(),
+    )
+  }
+
+  #[test]
+  fn valid_header() {
+    let (genesis, block_1) = headers();
+    assert_eq!(
+      check_header(&mainnet(), 1, &block_1, |h| (h == 0).then_some(genesis)),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn first_indexed_header_only_checks_proof_of_work() {
+    let (_, block_1) = headers();
+    assert_eq!(check_header(&mainnet(), 1, &block_1, |_| None), Ok(()));
+  }
+
+  #[test]
+  fn bad_prev_blockhash() {
+    let (genesis, mut block_1) = headers();
+    block_1.prev_blockhash = block_1.block_hash();
+    assert_eq!(
+      check_header(&mainnet(), 1, &block_1, |h| (h == 0).then_some(genesis)),
+      Err(Error::BadPrevBlockhash)
+    );
+  }
+
+  /// Ancestors of a header at height 11 whose parent is the genesis block,
+  /// with the median time 5 seconds after the genesis block's.
+  fn ancestors(genesis: Header) -> impl Fn(u32) -> Option<Header> {
+    move |h| match h {
+      10 => Some(genesis),
+      0..=9 => Some(Header {
+        time: genesis.time + h + 1,
+        ..genesis
+      }),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn time_too_old() {
+    let (genesis, mut block_1) = headers();
+    block_1.time = genesis.time + 5;
+    assert_eq!(
+      check_header(&mainnet(), 11, &block_1, ancestors(genesis)),
+      Err(Error::TimeTooOld {
+        time: genesis.time + 5,
+        median_time_past: genesis.time + 5,
+      })
+    );
+
+    block_1.time = genesis.time + 6;
+    assert_ne!(
+      check_header(&mainnet(), 11, &block_1, ancestors(genesis)),
+      Err(Error::TimeTooOld {
+        time: genesis.time + 6,
+        median_time_past: genesis.time + 5,
+      })
+    );
+  }
+
+  #[test]
+  fn median_time_past_needs_every_ancestor() {
+    let (genesis, mut block_1) = headers();
+    block_1.time = genesis.time;
+    let ancestors = ancestors(genesis);
+    assert_eq!(median_time_past(11, &ancestors), Some(genesis.time + 5));
+    assert_eq!(median_time_past(12, &ancestors), None);
+    // e.g. right after a snapshot, only the parent is indexed
+    assert_eq!(median_time_past(11, |h| (h == 10).then_some(genesis)), None);
+    assert_ne!(
+      check_header(&mainnet(), 11, &block_1, |h| (h == 10).then_some(genesis)),
+      Err(Error::TimeTooOld {
+        time: genesis.time,
+        median_time_past: genesis.time,
+      })
+    );
+  }
+
+  #[test]
+  fn bad_difficulty_bits() {
+    let (mut genesis, mut block_1) = headers();
+    genesis.bits = CompactTarget::from_consensus(0x1c00ffff);
+    block_1.prev_blockhash = genesis.block_hash();
+    assert_eq!(
+      check_header(&mainnet(), 1, &block_1, |h| (h == 0).then_some(genesis)),
+      Err(Error::BadDifficultyBits {
+        expected: 0x1c00ffff,
+        actual: 0x1d00ffff,
+      })
+    );
+  }
+
+  #[test]
+  fn bad_proof_of_work() {
+    let (genesis, mut block_1) = headers();
+    block_1.nonce += 1;
+    assert_eq!(
+      check_header(&mainnet(), 1, &block_1, |h| (h == 0).then_some(genesis)),
+      Err(Error::BadProofOfWork)
+    );
+  }
+
+  #[test]
+  fn retarget_uses_period_timespan() {
+    let chain = mainnet();
+    let (genesis, _) = headers();
+    let prev = Header {
+      time: genesis.time + 2016 * 600 / 2,
+      ..genesis
+    };
+    let ancestor = |h| match h {
+      0 => Some(genesis),
+      2015 => Some(prev),
+      _ => None,
+    };
+    let header = Header {
+      prev_blockhash: prev.block_hash(),
+      time: prev.time + 600,
+      ..genesis
+    };
+    assert_eq!(
+      required_bits(&chain, 2016, &header, &prev, ancestor),
+      Some(CompactTarget::from_next_work_required(
+        genesis.bits,
+        u64::from(prev.time - genesis.time),
+        &chain.consensus,
+      ))
+    );
+    assert_ne!(
+      required_bits(&chain, 2016, &header, &prev, ancestor),
+      Some(genesis.bits)
+    );
+  }
+
+  #[test]
+  fn min_difficulty_blocks() {
+    let chain = ChainParams::new(&Chain::Testnet4);
+    let (genesis, _) = headers();
+    let prev = Header {
+      bits: CompactTarget::from_consensus(0x1c00ffff),
+      ..genesis
+    };
+    let late = Header {
+      time: prev.time + 1201,
+      ..prev
+    };
+    assert_eq!(
+      required_bits(&chain, 5, &late, &prev, |_| None),
+      Some(pow_limit(&chain))
+    );
+
+    let on_time = Header {
+      time: prev.time + 600,
+      ..prev
+    };
+    assert_eq!(
+      required_bits(&chain, 5, &on_time, &prev, |_| None),
+      Some(prev.bits)
+    );
+
+    let min_difficulty = Header {
+      bits: pow_limit(&chain),
+      ..prev
+    };
+    assert_eq!(
+      required_bits(&chain, 5, &on_time, &min_difficulty, |h| (h == 3)
+        .then_some(prev)),
+      Some(prev.bits)
+    );
+  }
+}
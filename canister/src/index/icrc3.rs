@@ -0,0 +1,304 @@
+//! ICRC-3 view of the event log.
+//!
+//! Every event is exposed as an ICRC-3 block whose `phash` is the hash of the
+//! previous block, so the log can be verified from any certified tip. Blocks
+//! are maps of the form
+//!
+//! ```text
+//! { btype: text; phash: blob; ts: nat; tx: { height: nat; ... } }
+//! ```
+//!
+//! with the fields of `tx` depending on the block type, see `BLOCK_TYPES`.
+//! The hash of the last block and its index are certified as the labels
+//! `last_block_hash` and `last_block_index` of a hash tree, as the standard
+//! requires.
+
+use super::*;
+use crate::index::entry::RuneEvent;
+#[cfg(not(test))]
+use ic_cdk::api::set_certified_data;
+use sha2::{Digest, Sha256};
+
+/// Block types of the log with the events they record.
+pub const BLOCK_TYPES: [&str; 6] = [
+  "runes_etch",
+  "runes_mint",
+  "runes_alloc",
+  "runes_spend",
+  "runes_burn",
+  "runes_revert",
+];
+
+/// ICRC-3 generic value, limited to the variants blocks are built from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+  Blob(Vec<u8>),
+  Text(String),
+  Nat(u128),
+  Map(Vec<(String, Value)>),
+}
+
+impl Value {
+  /// Representation-independent hash of the value, as defined by ICRC-3.
+  pub fn hash(&self) -> [u8; 32] {
+    match self {
+      Value::Blob(bytes) => sha256(bytes),
+      Value::Text(text) => sha256(text.as_bytes()),
+      Value::Nat(n) => sha256(&leb128(*n)),
+      Value::Map(entries) => {
+        let mut pairs = entries
+          .iter()
+          .map(|(key, value)| {
+            let mut pair = sha256(key.as_bytes()).to_vec();
+            pair.extend_from_slice(&value.hash());
+            pair
+          })
+          .collect::<Vec<Vec<u8>>>();
+        pairs.sort();
+        sha256(&pairs.concat())
+      }
+    }
+  }
+}
+
+pub fn block(record: &EventRecord) -> Value {
+  let nat = |key: &str, n: u128| (key.to_string(), Value::Nat(n));
+  let text = |key: &str, text: String| (key.to_string(), Value::Text(text));
+
+  let (btype, fields) = match &record.event {
+    RuneEvent::Etched {
+      rune_id,
+      rune,
+      txid,
+    } => (
+      "runes_etch",
+      vec![
+        text("rune_id", rune_id.to_string()),
+        text("rune", rune.to_string()),
+        text("txid", txid.to_string()),
+      ],
+    ),
+    RuneEvent::Minted {
+      rune_id,
+      mints,
+      amount,
+    } => (
+      "runes_mint",
+      vec![
+        text("rune_id", rune_id.to_string()),
+        nat("mints", *mints),
+        nat("amount", *amount),
+      ],
+    ),
+    RuneEvent::Allocated {
+      outpoint,
+      rune_id,
+      amount,
+    } => (
+      "runes_alloc",
+      vec![
+        text("outpoint", outpoint.to_string()),
+        text("rune_id", rune_id.to_string()),
+        nat("amount", *amount),
+      ],
+    ),
+    RuneEvent::Spent {
+      outpoint,
+      rune_id,
+      amount,
+    } => (
+      "runes_spend",
+      vec![
+        text("outpoint", outpoint.to_string()),
+        text("rune_id", rune_id.to_string()),
+        nat("amount", *amount),
+      ],
+    ),
+    RuneEvent::Burned { rune_id, amount } => (
+      "runes_burn",
+      vec![text("rune_id", rune_id.to_string()), nat("amount", *amount)],
+    ),
+    RuneEvent::Reverted { start, length } => (
+      "runes_revert",
+      vec![
+        nat("start", (*start).into()),
+        nat("length", (*length).into()),
+      ],
+    ),
+  };
+
+  let mut tx = vec![nat("height", record.height.into())];
+  tx.extend(fields);
+
+  let mut block = vec![text("btype", btype.to_string())];
+  if let Some(parent_hash) = record.parent_hash {
+    block.push(("phash".to_string(), Value::Blob(parent_hash.to_vec())));
+  }
+  block.push(nat("ts", u128::from(record.timestamp) * 1_000_000_000));
+  block.push(("tx".to_string(), Value::Map(tx)));
+  Value::Map(block)
+}
+
+/// Index and hash of the last block, if any.
+pub fn tip() -> Option<(u64, [u8; 32])> {
+  let hash = events::last_hash()?;
+  Some((events::log_length().checked_sub(1)?, hash))
+}
+
+/// Certifies the tip of the log, or nothing while the log is empty. Must be
+/// called whenever the log changes and after upgrades.
+pub fn certify_tip() {
+  let root = tip()
+    .map(|(index, hash)| tip_tree(index, hash).0)
+    .unwrap_or_default();
+  set_certified_data(&root);
+}
+
+/// Certified data only exists inside a canister.
+#[cfg(test)]
+fn set_certified_data(_data: &[u8]) {}
+
+/// Certificate of the tip with the CBOR encoded hash tree it certifies.
+pub fn tip_certificate() -> Option<(Vec<u8>, Vec<u8>)> {
+  let certificate = ic_cdk::api::data_certificate()?;
+  let (index, hash) = tip()?;
+  Some((certificate, tip_tree(index, hash).1))
+}
+
+/// Root hash and CBOR encoding of the hash tree
+/// `fork(label("last_block_hash", leaf(hash)), label("last_block_index", leaf(leb128(index))))`.
+fn tip_tree(index: u64, hash: [u8; 32]) -> ([u8; 32], Vec<u8>) {
+  let index = leb128(index.into());
+  let root = fork_hash(
+    labeled_hash(b"last_block_hash", leaf_hash(&hash)),
+    labeled_hash(b"last_block_index", leaf_hash(&index)),
+  );
+
+  // self-described CBOR, with forks as [1, l, r], labels as [2, label, t]
+  // and leaves as [3, value]
+  let mut cbor = vec![0xd9, 0xd9, 0xf7, 0x83, 0x01];
+  for (label, value) in [
+    (&b"last_block_hash"[..], &hash[..]),
+    (b"last_block_index", &index),
+  ] {
+    cbor.extend([0x83, 0x02]);
+    cbor_bytes(&mut cbor, label);
+    cbor.extend([0x82, 0x03]);
+    cbor_bytes(&mut cbor, value);
+  }
+  (root, cbor)
+}
+
+fn cbor_bytes(cbor: &mut Vec<u8>, bytes: &[u8]) {
+  // only short byte strings are encoded, all of them under 256 bytes
+  match bytes.len() {
+    len @ 0..=23 => cbor.push(0x40 + len as u8),
+    len => cbor.extend([0x58, len as u8]),
+  }
+  cbor.extend_from_slice(bytes);
+}
+
+fn leaf_hash(value: &[u8]) -> [u8; 32] {
+  sha256(&[&domain_separator("ic-hashtree-leaf"), value].concat())
+}
+
+fn labeled_hash(label: &[u8], subtree: [u8; 32]) -> [u8; 32] {
+  sha256(&[&domain_separator("ic-hashtree-labeled"), label, &subtree].concat())
+}
+
+fn fork_hash(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+  sha256(&[&domain_separator("ic-hashtree-fork"), &left[..], &right].concat())
+}
+
+fn domain_separator(name: &str) -> Vec<u8> {
+  let mut separator = vec![name.len() as u8];
+  separator.extend_from_slice(name.as_bytes());
+  separator
+}
+
+fn leb128(n: u128) -> Vec<u8> {
+  let mut bytes = Vec::new();
+  varint::encode_to_vec(n, &mut bytes);
+  bytes
+}
+
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+  Sha256::digest(bytes).into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn values_hash_as_in_the_standard() {
+    let blob = |hex: &str| Value::Blob(hex::decode(hex).unwrap());
+    // examples from the ICRC-3 specification
+    assert_eq!(
+      hex::encode(Value::Nat(42).hash()),
+      "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+    );
+    assert_eq!(
+      hex::encode(Value::Text("Hello, World!".to_string()).hash()),
+      "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+    );
+    assert_eq!(
+      hex::encode(blob("01020304").hash()),
+      "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+    );
+    assert_eq!(
+      hex::encode(
+        Value::Map(vec![
+          (
+            "from".to_string(),
+            blob("00abcdef0012340056789a00bcdef000012345678900abcdef01")
+          ),
+          (
+            "to".to_string(),
+            blob("00ab0def0012340056789a00bcdef000012345678900abcdef01")
+          ),
+          ("amount".to_string(), Value::Nat(42)),
+          ("created_at".to_string(), Value::Nat(1_699_218_263)),
+          ("memo".to_string(), Value::Nat(0)),
+        ])
+        .hash()
+      ),
+      "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75"
+    );
+  }
+
+  #[test]
+  fn blocks_are_chained() {
+    let record = |parent_hash| EventRecord {
+      height: 840_000,
+      timestamp: 1_713_571_767,
+      parent_hash,
+      event: RuneEvent::Reverted {
+        start: 0,
+        length: 2,
+      },
+    };
+
+    let Value::Map(first) = block(&record(None)) else {
+      panic!("block is not a map");
+    };
+    assert!(first.iter().all(|(key, _)| key != "phash"));
+
+    let hash = block(&record(None)).hash();
+    let Value::Map(second) = block(&record(Some(hash))) else {
+      panic!("block is not a map");
+    };
+    assert!(second.contains(&("phash".to_string(), Value::Blob(hash.to_vec()))));
+    assert!(second.contains(&("btype".to_string(), Value::Text("runes_revert".to_string()))));
+    assert!(second.contains(&("ts".to_string(), Value::Nat(1_713_571_767_000_000_000))));
+  }
+
+  #[test]
+  fn tip_tree_is_encoded() {
+    let (root, cbor) = tip_tree(300, [7; 32]);
+    assert_eq!(&cbor[..5], &[0xd9, 0xd9, 0xf7, 0x83, 0x01]);
+    // the index leaf is the last value, 300 as LEB128
+    assert_eq!(&cbor[cbor.len() - 4..], &[0x03, 0x42, 0xac, 0x02]);
+    assert_ne!(root, tip_tree(301, [7; 32]).0);
+  }
+}
@@ -0,0 +1,632 @@
+//! Durable outbox of the notifications sent to subscribers.
+//!
+//! Notifications are appended to a log with increasing sequence numbers,
+//! addressed to some or all subscribers, and delivered in the background, so
+//! indexing never waits on subscribers. Each subscriber is delivered to by a
+//! task of its own, so a slow subscriber doesn't delay the others. Each
+//! subscriber has a cursor with the next sequence number to deliver, which
+//! moves once the subscriber accepts a notification, so every notification is
+//! delivered at least once and in order. A failed delivery is retried with
+//! exponential backoff. Subscribers can also pull notifications with
+//! `get_notifications` and move their cursor with `ack_notifications`.
+//! Notifications are removed once every subscriber has them, or when the log
+//! outgrows `MAX_OUTBOX_LENGTH`.
+//!
+//! Webhooks are delivered to the same way, under the principals given by
+//! `webhooks::recipient`.
+
+use super::*;
+use crate::logs::WARNING;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use runes_indexer_interface::{Notification, SequencedNotification};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+/// Notifications kept for subscribers that fall behind.
+const MAX_OUTBOX_LENGTH: u64 = 10_000;
+
+/// Delay before the first retry, doubled on every further failure.
+const RETRY_BASE_SECS: u64 = 10;
+const MAX_RETRY_SECS: u64 = 3_600;
+
+/// Encoded size of the notifications returned by one `get_notifications`.
+const MAX_PULL_BYTES: usize = 1_000_000;
+
+thread_local! {
+  /// Subscribers being delivered to, with the id of the running attempt.
+  static IN_FLIGHT: RefCell<BTreeMap<Principal, u64>> = const { RefCell::new(BTreeMap::new()) };
+  /// Subscribers with a call still waiting for an answer, including the calls
+  /// of attempts that timed out.
+  static OPEN_CALLS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+  static NEXT_ATTEMPT: Cell<u64> = const { Cell::new(0) };
+  static RETRY_TIMER: Cell<Option<ic_cdk_timers::TimerId>> = const { Cell::new(None) };
+  static STATS: RefCell<BTreeMap<Principal, DeliveryStats>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct OutboxEntry {
+  pub recipients: Vec<Principal>,
+  pub notification: Notification,
+}
+
+impl Storable for OutboxEntry {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+  /// Sequence number of the next notification to deliver.
+  pub next: u64,
+  /// Failed deliveries since the last successful one.
+  pub failures: u32,
+  /// Time of the next delivery attempt, in nanoseconds.
+  pub retry_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Cursors {
+  next_seq: u64,
+  cursors: BTreeMap<Principal, Cursor>,
+}
+
+impl Storable for Cursors {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+fn cursors() -> Cursors {
+  OUTBOX_CURSORS.with(|c| c.borrow().get().clone())
+}
+
+fn set_cursors(cursors: Cursors) {
+  OUTBOX_CURSORS
+    .with(|c| c.borrow_mut().set(cursors))
+    .unwrap();
+}
+
+pub fn cursor(subscriber: Principal) -> Option<Cursor> {
+  cursors().cursors.get(&subscriber).cloned()
+}
+
+/// Sequence number of the next notification.
+pub fn next_seq() -> u64 {
+  cursors().next_seq
+}
+
+/// Sequence number of the oldest notification kept.
+pub fn oldest_seq() -> u64 {
+  OUTBOX
+    .with(|m| m.borrow().iter().next().map(|(seq, _)| seq))
+    .unwrap_or_else(next_seq)
+}
+
+/// Subscribers and webhooks, which are all delivered to from the outbox.
+pub(crate) fn recipients() -> Vec<Principal> {
+  let mut recipients = mem_get_config().subscribers;
+  recipients.extend(webhooks::recipients(None));
+  recipients
+}
+
+/// Keeps cursors for the current subscribers only. New subscribers start from
+/// the next notification.
+pub(crate) fn sync(subscribers: &[Principal]) {
+  let mut cursors = cursors();
+  let seq = cursors.next_seq;
+  cursors
+    .cursors
+    .retain(|subscriber, _| subscribers.contains(subscriber));
+  for subscriber in subscribers {
+    cursors.cursors.entry(*subscriber).or_insert(Cursor {
+      next: seq,
+      failures: 0,
+      retry_at: 0,
+    });
+  }
+  set_cursors(cursors);
+}
+
+/// Appends a notification for `recipients`, which must have been synced.
+pub(crate) fn push(recipients: Vec<Principal>, notification: Notification) {
+  let mut cursors = cursors();
+  let seq = cursors.next_seq;
+  cursors.next_seq += 1;
+  set_cursors(cursors);
+  OUTBOX.with(|m| {
+    m.borrow_mut().insert(
+      seq,
+      OutboxEntry {
+        recipients,
+        notification,
+      },
+    )
+  });
+  prune();
+}
+
+/// First notification for the subscriber from `since` on.
+fn next_for(subscriber: &Principal, since: u64) -> Option<(u64, Notification)> {
+  OUTBOX.with(|m| {
+    m.borrow()
+      .range(since..)
+      .find(|(_, entry)| entry.recipients.contains(subscriber))
+      .map(|(seq, entry)| (seq, entry.notification))
+  })
+}
+
+/// Removes the notifications every subscriber has, and the oldest ones beyond
+/// `MAX_OUTBOX_LENGTH`.
+fn prune() {
+  let mut cursors = cursors();
+  let end = cursors
+    .cursors
+    .values()
+    .map(|cursor| cursor.next)
+    .min()
+    .unwrap_or(cursors.next_seq)
+    .max(cursors.next_seq.saturating_sub(MAX_OUTBOX_LENGTH));
+
+  OUTBOX.with(|m| {
+    let mut m = m.borrow_mut();
+    let seqs = m.range(..end).map(|(seq, _)| seq).collect::<Vec<u64>>();
+    for seq in seqs {
+      m.remove(&seq);
+    }
+  });
+
+  let mut dropped = false;
+  for (subscriber, cursor) in cursors.cursors.iter_mut() {
+    if cursor.next < end {
+      log!(
+        WARNING,
+        "subscriber {} missed notifications {}..{}",
+        subscriber,
+        cursor.next,
+        end
+      );
+      cursor.next = end;
+      dropped = true;
+    }
+  }
+  if dropped {
+    set_cursors(cursors);
+  }
+}
+
+/// Notifications for the subscriber from `since` on, as many as fit in a
+/// response.
+pub fn get(subscriber: &Principal, since: u64) -> Vec<SequencedNotification> {
+  let mut notifications = Vec::new();
+  let mut bytes = 0;
+  OUTBOX.with(|m| {
+    for (seq, entry) in m.borrow().range(since..) {
+      if !entry.recipients.contains(subscriber) {
+        continue;
+      }
+      let notification = entry.notification;
+      let notification = SequencedNotification { seq, notification };
+      let size = candid::encode_one(&notification).unwrap().len();
+      if !notifications.is_empty() && bytes + size > MAX_PULL_BYTES {
+        break;
+      }
+      bytes += size;
+      notifications.push(notification);
+    }
+  });
+  notifications
+}
+
+/// Moves the subscriber's cursor past `seq` after it pulled the
+/// notifications up to it.
+pub fn ack(subscriber: Principal, seq: u64) -> Result<(), String> {
+  let mut cursors = cursors();
+  let next_seq = cursors.next_seq;
+  let cursor = cursors
+    .cursors
+    .get_mut(&subscriber)
+    .ok_or_else(|| "Not a subscriber".to_string())?;
+  if seq >= next_seq {
+    return Err(format!("notification {} does not exist yet", seq));
+  }
+  if seq >= cursor.next {
+    cursor.next = seq + 1;
+    cursor.failures = 0;
+    cursor.retry_at = 0;
+  }
+  set_cursors(cursors);
+  prune();
+  Ok(())
+}
+
+fn backoff(failures: u32) -> Duration {
+  let secs = RETRY_BASE_SECS.saturating_mul(1 << failures.saturating_sub(1).min(20));
+  Duration::from_secs(secs.min(MAX_RETRY_SECS))
+}
+
+/// Delivery statistics of a subscriber since the last upgrade.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+  pub delivered: u64,
+  pub failed: u64,
+  pub timed_out: u64,
+  /// Latencies of the successful calls, in nanoseconds.
+  pub last_latency: Option<u64>,
+  pub max_latency: u64,
+  total_latency: u64,
+  pub last_error: Option<String>,
+}
+
+impl DeliveryStats {
+  fn record_delivery(&mut self, latency: u64) {
+    self.delivered += 1;
+    self.last_latency = Some(latency);
+    self.max_latency = self.max_latency.max(latency);
+    self.total_latency = self.total_latency.saturating_add(latency);
+  }
+
+  fn record_failure(&mut self, error: String) {
+    self.failed += 1;
+    self.last_error = Some(error);
+  }
+
+  fn record_timeout(&mut self, error: String) {
+    self.timed_out += 1;
+    self.last_error = Some(error);
+  }
+
+  pub fn average_latency(&self) -> Option<u64> {
+    self.total_latency.checked_div(self.delivered)
+  }
+}
+
+/// Delivery state of a subscriber, see `subscriber_stats`.
+pub struct SubscriberStats {
+  pub subscriber: Principal,
+  /// Notifications the subscriber hasn't received yet.
+  pub pending: u64,
+  pub cursor: Cursor,
+  pub in_flight: bool,
+  pub stats: DeliveryStats,
+}
+
+pub fn subscriber_stats() -> Vec<SubscriberStats> {
+  let cursors = cursors();
+  cursors
+    .cursors
+    .into_iter()
+    .map(|(subscriber, cursor)| SubscriberStats {
+      subscriber,
+      pending: cursors.next_seq - cursor.next,
+      cursor,
+      in_flight: in_flight(&subscriber),
+      stats: STATS.with(|s| s.borrow().get(&subscriber).cloned().unwrap_or_default()),
+    })
+    .collect()
+}
+
+fn update_stats(subscriber: Principal, f: impl FnOnce(&mut DeliveryStats)) {
+  STATS.with(|s| f(s.borrow_mut().entry(subscriber).or_default()));
+}
+
+/// Whether an attempt is running for the subscriber, or the call of one that
+/// timed out is still open.
+fn in_flight(subscriber: &Principal) -> bool {
+  IN_FLIGHT.with(|m| m.borrow().contains_key(subscriber))
+    || OPEN_CALLS.with(|s| s.borrow().contains(subscriber))
+}
+
+/// Whether `attempt` is still the delivery running for the subscriber. An
+/// attempt that timed out is replaced, and the answer it eventually gets is
+/// ignored.
+fn is_current(subscriber: &Principal, attempt: u64) -> bool {
+  IN_FLIGHT.with(|m| m.borrow().get(subscriber) == Some(&attempt))
+}
+
+/// Whether the subscriber has pending notifications and is neither paused nor
+/// being delivered to.
+fn waiting(subscriber: &Principal, cursor: &Cursor, next_seq: u64) -> bool {
+  cursor.next < next_seq && !in_flight(subscriber) && !subscriptions::settings(subscriber).paused
+}
+
+/// Waiting subscribers whose retry is due.
+fn due(cursors: &Cursors, now: u64) -> Vec<Principal> {
+  cursors
+    .cursors
+    .iter()
+    .filter(|(subscriber, cursor)| {
+      waiting(subscriber, cursor, cursors.next_seq) && cursor.retry_at <= now
+    })
+    .map(|(subscriber, _)| *subscriber)
+    .collect()
+}
+
+/// Starts delivering to every subscriber with pending notifications, each in
+/// a task of its own. Called after every pushed notification and when a retry
+/// is due.
+pub fn schedule() {
+  for subscriber in due(&cursors(), ic_cdk::api::time()) {
+    let attempt = NEXT_ATTEMPT.get();
+    NEXT_ATTEMPT.set(attempt + 1);
+    IN_FLIGHT.with(|m| m.borrow_mut().insert(subscriber, attempt));
+    ic_cdk::spawn(deliver(subscriber, attempt));
+  }
+  set_retry_timer();
+}
+
+/// Retries every subscriber waiting for its backoff to expire right away.
+pub fn retry_now() {
+  let mut cursors = cursors();
+  for cursor in cursors.cursors.values_mut() {
+    cursor.retry_at = 0;
+  }
+  set_cursors(cursors);
+  schedule();
+}
+
+/// Wakes up for the earliest retry of a subscriber still behind.
+fn set_retry_timer() {
+  if let Some(timer) = RETRY_TIMER.take() {
+    ic_cdk_timers::clear_timer(timer);
+  }
+  let cursors = cursors();
+  if let Some(retry_at) = cursors
+    .cursors
+    .iter()
+    .filter(|(subscriber, cursor)| waiting(subscriber, cursor, cursors.next_seq))
+    .map(|(_, cursor)| cursor.retry_at)
+    .min()
+  {
+    let delay = Duration::from_nanos(retry_at.saturating_sub(ic_cdk::api::time()));
+    RETRY_TIMER.set(Some(ic_cdk_timers::set_timer(delay, schedule)));
+  }
+}
+
+/// Delivers the pending notifications of the subscriber in order, stopping at
+/// the first failure until its retry is due. Each call has
+/// `notification_timeout` to answer, after which it counts as failed. The
+/// call itself cannot be canceled, so the retry doesn't start before it
+/// returns: a subscriber holding a call open gets no further notifications,
+/// however long it holds it.
+async fn deliver(subscriber: Principal, attempt: u64) {
+  let timeout = mem_get_config().notification_timeout();
+  let callback = subscriptions::callback(&subscriber);
+  loop {
+    let Some(cursor) = cursor(subscriber) else {
+      break;
+    };
+    if subscriptions::settings(&subscriber).paused {
+      break;
+    }
+    let Some((seq, notification)) = next_for(&subscriber, cursor.next) else {
+      // nothing else is addressed to the subscriber
+      advance(subscriber, cursor.next, next_seq());
+      break;
+    };
+    advance(subscriber, cursor.next, seq);
+
+    let started_at = ic_cdk::api::time();
+    let deadline = ic_cdk_timers::set_timer(timeout, move || {
+      if !is_current(&subscriber, attempt) {
+        return;
+      }
+      IN_FLIGHT.with(|m| m.borrow_mut().remove(&subscriber));
+      let error = format!("timed out after {}s", timeout.as_secs());
+      update_stats(subscriber, |stats| stats.record_timeout(error.clone()));
+      fail(subscriber, seq, error);
+      set_retry_timer();
+    });
+    OPEN_CALLS.with(|s| s.borrow_mut().insert(subscriber));
+    let result = match webhooks::id(&subscriber) {
+      Some(id) => webhooks::post(id, seq, &notification).await,
+      None => crate::notifier::notify(subscriber, &notification, callback.as_deref()).await,
+    };
+    OPEN_CALLS.with(|s| s.borrow_mut().remove(&subscriber));
+    ic_cdk_timers::clear_timer(deadline);
+    if !is_current(&subscriber, attempt) {
+      // the retry was held back until the call returned
+      set_retry_timer();
+      return;
+    }
+
+    match result {
+      Ok(()) => {
+        let latency = ic_cdk::api::time() - started_at;
+        update_stats(subscriber, |stats| stats.record_delivery(latency));
+        advance(subscriber, seq, seq + 1);
+      }
+      Err(e) => {
+        update_stats(subscriber, |stats| stats.record_failure(e.clone()));
+        fail(subscriber, seq, e);
+        break;
+      }
+    }
+  }
+  IN_FLIGHT.with(|m| m.borrow_mut().remove(&subscriber));
+  prune();
+  set_retry_timer();
+}
+
+/// Moves the cursor from `from` to `to` unless the subscriber acknowledged
+/// notifications meanwhile.
+fn advance(subscriber: Principal, from: u64, to: u64) {
+  let mut cursors = cursors();
+  if let Some(cursor) = cursors.cursors.get_mut(&subscriber) {
+    if cursor.next == from && from < to {
+      cursor.next = to;
+      cursor.failures = 0;
+      cursor.retry_at = 0;
+      set_cursors(cursors);
+    }
+  }
+}
+
+fn fail(subscriber: Principal, seq: u64, error: String) {
+  let mut cursors = cursors();
+  let Some(cursor) = cursors.cursors.get_mut(&subscriber) else {
+    return;
+  };
+  if cursor.next != seq {
+    return;
+  }
+  cursor.failures += 1;
+  let delay = backoff(cursor.failures);
+  cursor.retry_at = ic_cdk::api::time() + delay.as_nanos() as u64;
+  log!(
+    WARNING,
+    "failed to deliver notification {} to {} ({} failures), retrying in {}s: {}",
+    seq,
+    subscriber,
+    cursor.failures,
+    delay.as_secs(),
+    error
+  );
+  set_cursors(cursors);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use runes_indexer_interface::NewBlockRequest;
+
+  fn notification(block_height: u32) -> Notification {
+    Notification::NewBlock(NewBlockRequest {
+      block_height,
+      block_hash: String::new(),
+      tx_ids: Vec::new(),
+    })
+  }
+
+  fn push_to(subscribers: &[Principal], block_height: u32) {
+    sync(subscribers);
+    push(subscribers.to_vec(), notification(block_height));
+  }
+
+  fn seqs(subscriber: Principal, since: u64) -> Vec<u64> {
+    get(&subscriber, since).into_iter().map(|n| n.seq).collect()
+  }
+
+  #[test]
+  fn notifications_are_kept_until_acknowledged() {
+    let a = Principal::from_slice(&[1]);
+    let b = Principal::from_slice(&[2]);
+    push_to(&[a, b], 1);
+    push_to(&[a, b], 2);
+    assert_eq!(seqs(a, 0), vec![0, 1]);
+
+    ack(a, 1).unwrap();
+    assert_eq!(cursor(a).unwrap().next, 2);
+    assert_eq!(oldest_seq(), 0);
+
+    ack(b, 0).unwrap();
+    assert_eq!(oldest_seq(), 1);
+    assert_eq!(seqs(b, 0), vec![1]);
+
+    assert!(ack(b, 2).is_err());
+    assert!(ack(Principal::from_slice(&[3]), 0).is_err());
+  }
+
+  #[test]
+  fn new_subscribers_start_at_the_next_notification() {
+    let a = Principal::from_slice(&[1]);
+    let b = Principal::from_slice(&[2]);
+    push_to(&[a], 1);
+    push_to(&[a, b], 2);
+    assert_eq!(cursor(a).unwrap().next, 0);
+    assert_eq!(cursor(b).unwrap().next, 1);
+
+    // removed subscribers no longer hold back pruning
+    push_to(&[b], 3);
+    assert_eq!(cursor(a), None);
+    assert_eq!(oldest_seq(), 1);
+  }
+
+  #[test]
+  fn notifications_are_addressed() {
+    let a = Principal::from_slice(&[1]);
+    let b = Principal::from_slice(&[2]);
+    sync(&[a, b]);
+    push(vec![a], notification(1));
+    push(vec![a, b], notification(2));
+    assert_eq!(seqs(a, 0), vec![0, 1]);
+    assert_eq!(seqs(b, 0), vec![1]);
+    assert_eq!(next_for(&b, 0).map(|(seq, _)| seq), Some(1));
+    assert_eq!(next_for(&b, 2).map(|(seq, _)| seq), None);
+  }
+
+  #[test]
+  fn due_subscribers_are_behind_and_idle() {
+    let a = Principal::from_slice(&[1]);
+    let b = Principal::from_slice(&[2]);
+    let c = Principal::from_slice(&[3]);
+    push_to(&[a, b, c], 1);
+
+    let mut cursors = cursors();
+    cursors.cursors.get_mut(&b).unwrap().retry_at = 100;
+    cursors.cursors.get_mut(&c).unwrap().next = 1;
+    assert_eq!(due(&cursors, 50), vec![a]);
+    assert_eq!(due(&cursors, 100), vec![a, b]);
+
+    IN_FLIGHT.with(|m| m.borrow_mut().insert(a, 0));
+    assert_eq!(due(&cursors, 100), vec![b]);
+
+    // a timed out attempt is retried only once its call returns
+    IN_FLIGHT.with(|m| m.borrow_mut().remove(&a));
+    OPEN_CALLS.with(|s| s.borrow_mut().insert(a));
+    assert_eq!(due(&cursors, 100), vec![b]);
+    OPEN_CALLS.with(|s| s.borrow_mut().remove(&a));
+    assert_eq!(due(&cursors, 100), vec![a, b]);
+    IN_FLIGHT.with(|m| m.borrow_mut().insert(a, 0));
+
+    subscriptions::add_subscriber(
+      b,
+      subscriptions::SubscriberSettings {
+        paused: true,
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(due(&cursors, 100), Vec::<Principal>::new());
+  }
+
+  #[test]
+  fn latencies_are_recorded() {
+    let mut stats = DeliveryStats::default();
+    assert_eq!(stats.average_latency(), None);
+    stats.record_delivery(10);
+    stats.record_delivery(30);
+    stats.record_failure("rejected".to_string());
+    stats.record_timeout("timed out".to_string());
+    assert_eq!(stats.delivered, 2);
+    assert_eq!(stats.failed, 1);
+    assert_eq!(stats.timed_out, 1);
+    assert_eq!(stats.last_latency, Some(30));
+    assert_eq!(stats.max_latency, 30);
+    assert_eq!(stats.average_latency(), Some(20));
+    assert_eq!(stats.last_error, Some("timed out".to_string()));
+  }
+
+  #[test]
+  fn retries_back_off() {
+    assert_eq!(backoff(1), Duration::from_secs(10));
+    assert_eq!(backoff(2), Duration::from_secs(20));
+    assert_eq!(backoff(4), Duration::from_secs(80));
+    assert_eq!(backoff(30), Duration::from_secs(MAX_RETRY_SECS));
+  }
+}
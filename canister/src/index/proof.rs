@@ -0,0 +1,140 @@
+use crate::into_usize::IntoUsize;
+use bitcoin::hash_types::TxMerkleNode;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::Txid;
+
+/// Returns the merkle branch of the transaction at each of `indices` in a
+/// block with the given transactions, building each level of the tree once.
+pub(crate) fn merkle_branches(txids: &[Txid], indices: &[u32]) -> Vec<Vec<TxMerkleNode>> {
+  let mut branches = vec![Vec::new(); indices.len()];
+  let mut positions = indices
+    .iter()
+    .map(|index| index.into_usize())
+    .collect::<Vec<usize>>();
+  let mut level = txids
+    .iter()
+    .map(|txid| TxMerkleNode::from_raw_hash(txid.to_raw_hash()))
+    .collect::<Vec<TxMerkleNode>>();
+
+  while level.len() > 1 {
+    for (branch, position) in branches.iter_mut().zip(positions.iter_mut()) {
+      // the last node of an odd level is paired with itself
+      branch.push(level[(*position ^ 1).min(level.len() - 1)]);
+      *position /= 2;
+    }
+    level = level
+      .chunks(2)
+      .map(|pair| hash_pair(pair[0], pair[pair.len() - 1]))
+      .collect();
+  }
+
+  branches
+}
+
+/// Folds the merkle branch of the transaction at `index` into the merkle root
+/// it commits to.
+pub(crate) fn merkle_root(txid: Txid, index: u32, branch: &[TxMerkleNode]) -> TxMerkleNode {
+  let mut node = TxMerkleNode::from_raw_hash(txid.to_raw_hash());
+  let mut index = index;
+  for sibling in branch {
+    node = if index & 1 == 0 {
+      hash_pair(node, *sibling)
+    } else {
+      hash_pair(*sibling, node)
+    };
+    index >>= 1;
+  }
+  node
+}
+
+/// Checks the merkle branch of the transaction at `index` in a block of
+/// `tx_count` transactions against the block's merkle root.
+///
+/// The branch must reach from a leaf: an inner node is the hash of 64 bytes,
+/// which could be passed off as a transaction, so a shorter branch starting
+/// from it would also fold into the root (CVE-2012-2459).
+pub(crate) fn verify(
+  txid: Txid,
+  index: u32,
+  branch: &[TxMerkleNode],
+  tx_count: u32,
+  root: TxMerkleNode,
+) -> bool {
+  let depth = tx_count.next_power_of_two().trailing_zeros();
+  index < tx_count && branch.len() == depth.into_usize() && merkle_root(txid, index, branch) == root
+}
+
+fn hash_pair(left: TxMerkleNode, right: TxMerkleNode) -> TxMerkleNode {
+  let mut engine = TxMerkleNode::engine();
+  engine.input(left.as_byte_array());
+  engine.input(right.as_byte_array());
+  TxMerkleNode::from_engine(engine)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn txids(n: u8) -> Vec<Txid> {
+    (0..n).map(|i| Txid::from_byte_array([i; 32])).collect()
+  }
+
+  fn root(txids: &[Txid]) -> TxMerkleNode {
+    bitcoin::merkle_tree::calculate_root(txids.iter().map(|txid| txid.to_raw_hash()))
+      .map(TxMerkleNode::from_raw_hash)
+      .unwrap()
+  }
+
+  #[test]
+  fn branches_fold_into_block_merkle_root() {
+    for n in 1..=9 {
+      let txids = txids(n);
+      let indices = (0..u32::from(n)).collect::<Vec<u32>>();
+      let branches = merkle_branches(&txids, &indices);
+      for (index, branch) in indices.into_iter().zip(branches) {
+        assert_eq!(
+          merkle_root(txids[index.into_usize()], index, &branch),
+          root(&txids),
+          "{n} transactions, index {index}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn branch_for_wrong_index_does_not_match() {
+    let txids = txids(5);
+    let branch = merkle_branches(&txids, &[2]).remove(0);
+    assert_ne!(merkle_root(txids[2], 3, &branch), root(&txids));
+    assert_ne!(merkle_root(txids[3], 2, &branch), root(&txids));
+  }
+
+  #[test]
+  fn branches_are_verified_against_the_tree_depth() {
+    let txids = txids(5);
+    let branch = merkle_branches(&txids, &[4]).remove(0);
+    assert!(verify(txids[4], 4, &branch, 5, root(&txids)));
+    assert!(!verify(txids[4], 4, &branch, 9, root(&txids)));
+    assert!(!verify(txids[4], 5, &branch, 5, root(&txids)));
+  }
+
+  #[test]
+  fn proofs_from_inner_nodes_are_rejected() {
+    let txids = txids(4);
+    let inner = hash_pair(
+      TxMerkleNode::from_raw_hash(txids[0].to_raw_hash()),
+      TxMerkleNode::from_raw_hash(txids[1].to_raw_hash()),
+    );
+    let txid = Txid::from_raw_hash(inner.to_raw_hash());
+    let branch = merkle_branches(&txids, &[0]).remove(0)[1..].to_vec();
+
+    assert_eq!(merkle_root(txid, 0, &branch), root(&txids));
+    assert!(!verify(txid, 0, &branch, 4, root(&txids)));
+  }
+
+  #[test]
+  fn single_transaction_has_empty_branch() {
+    let txids = txids(1);
+    assert_eq!(merkle_branches(&txids, &[0]), vec![Vec::new()]);
+  }
+}
@@ -1,13 +1,19 @@
-use crate::index::entry::Entry;
+use crate::chain::ChainParams;
+use crate::index::entry::{ChangeRecord, Checkpoint, Entry};
+use crate::index::headers;
 use crate::index::INFO;
 use bitcoin::block::BlockHash;
+use bitcoin::OutPoint;
 use ic_canister_log::log;
-use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use runes_indexer_interface::ReorgNotification;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum Error {
   Recoverable { height: u32, depth: u32 },
+  Checkpoint { height: u32, checkpoint: u32 },
+  InvalidFork { height: u32 },
   Unrecoverable,
 }
 
@@ -17,6 +23,18 @@ impl Display for Error {
       Self::Recoverable { height, depth } => {
         write!(f, "{depth} block deep reorg detected at height {height}")
       }
+      Self::Checkpoint { height, checkpoint } => {
+        write!(
+          f,
+          "deep reorg detected at height {height}, recoverable from checkpoint {checkpoint}"
+        )
+      }
+      Self::InvalidFork { height } => {
+        write!(
+          f,
+          "competing chain at height {height} is invalid or has less work"
+        )
+      }
       Self::Unrecoverable => write!(f, "unrecoverable reorg detected"),
     }
   }
@@ -24,13 +42,73 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
-const MAX_RECOVERABLE_REORG_DEPTH: u32 = 6;
+/// Number of checkpoints kept, the oldest one bounds the deepest recoverable reorg.
+pub(crate) const MAX_CHECKPOINTS: u32 = 4;
+
+/// Reverted outputs per reorg notification, keeping it well below the
+/// message limit. Deeper reorgs are notified in several parts.
+const MAX_REVERTED_OUTPOINTS: usize = 10_000;
+
+/// Blocks and outputs undone by rolling back a reorg, notified to subscribers.
+#[derive(Debug, Default)]
+pub(crate) struct RolledBack {
+  /// Height of the first block rolled back.
+  pub(crate) fork_height: u32,
+  /// Hashes of the rolled back blocks, newest first.
+  pub(crate) block_hashes: Vec<BlockHash>,
+  /// Outputs whose balances were restored or removed.
+  pub(crate) outpoints: BTreeSet<OutPoint>,
+}
+
+impl RolledBack {
+  fn add_outpoints(&mut self, change_record: &ChangeRecord) {
+    self.outpoints.extend(
+      change_record
+        .removed_outpoints
+        .iter()
+        .map(|(outpoint, ..)| *outpoint)
+        .chain(change_record.added_outpoints.iter().copied()),
+    );
+  }
+
+  /// Notifications of the rollback, each with the next part of the reverted
+  /// outputs.
+  pub(crate) fn notifications(&self) -> Vec<ReorgNotification> {
+    let outpoints = self
+      .outpoints
+      .iter()
+      .map(|outpoint| outpoint.to_string())
+      .collect::<Vec<String>>();
+    let mut parts = outpoints
+      .chunks(MAX_REVERTED_OUTPOINTS)
+      .collect::<Vec<&[String]>>();
+    if parts.is_empty() {
+      parts.push(&[]);
+    }
+    let last = parts.len() - 1;
+    parts
+      .into_iter()
+      .enumerate()
+      .map(|(part, reverted_outpoints)| ReorgNotification {
+        fork_height: self.fork_height,
+        depth: self.block_hashes.len() as u32,
+        rolled_back_blocks: self
+          .block_hashes
+          .iter()
+          .map(|block_hash| block_hash.to_string())
+          .collect(),
+        reverted_outpoints: reverted_outpoints.to_vec(),
+        more_outpoints: part < last,
+      })
+      .collect()
+  }
+}
 
 pub struct Reorg {}
 
 impl Reorg {
   pub(crate) async fn detect_reorg(
-    network: BitcoinNetwork,
+    chain: &ChainParams,
     index_prev_blockhash: Option<BlockHash>,
     bitcoind_prev_blockhash: BlockHash,
     height: u32,
@@ -38,105 +116,517 @@ impl Reorg {
     match index_prev_blockhash {
       Some(index_prev_blockhash) if index_prev_blockhash == bitcoind_prev_blockhash => Ok(()),
       Some(index_prev_blockhash) if index_prev_blockhash != bitcoind_prev_blockhash => {
-        for depth in 1..=MAX_RECOVERABLE_REORG_DEPTH {
-          let index_block_hash =
-            crate::index::mem_block_hash(height.checked_sub(depth).expect("height overflow"))
-              .ok_or(Error::Unrecoverable)?;
+        let config = crate::index::mem_get_config();
+        // the chain may be shorter than the reorg window, e.g. on regtest
+        let max_reorg_depth = config.max_reorg_depth().min(height);
+        for depth in 1..=max_reorg_depth {
+          let Some(index_block_hash) = crate::index::mem_block_hash(height - depth) else {
+            break;
+          };
+          // headers outlive the change records, which are pruned with the
+          // reorg window in force when the block was indexed
+          if !Self::revertible(height, depth) {
+            break;
+          }
 
-          let bitcoin_height = height.checked_sub(depth).expect("height overflow");
-          let block_hash = crate::bitcoin_api::get_block_hash(network, bitcoin_height)
+          let bitcoin_height = height - depth;
+          let block_hash = crate::bitcoin_api::get_block_hash(chain, bitcoin_height)
             .await
             .map_err(|_| Error::Unrecoverable)?;
 
           let bitcoin_canister_block_hash = block_hash.ok_or(Error::Unrecoverable)?;
 
           if index_block_hash == bitcoin_canister_block_hash {
+            Self::check_fork(chain, height, depth).await?;
             return Err(Error::Recoverable { height, depth });
           }
         }
 
+        // checkpoints stop being updated once they are disabled
+        let checkpoints = match config.checkpoint_interval() {
+          Some(_) => crate::index::mem_checkpoint_heights(),
+          None => Vec::new(),
+        };
+        for checkpoint in checkpoints.into_iter().rev() {
+          let Some(checkpoint_block_hash) =
+            crate::index::mem_get_checkpoint(checkpoint).map(|c| c.header.block_hash())
+          else {
+            continue;
+          };
+
+          let block_hash = crate::bitcoin_api::get_block_hash(chain, checkpoint)
+            .await
+            .map_err(|_| Error::Unrecoverable)?;
+
+          if block_hash == Some(checkpoint_block_hash) {
+            Self::check_fork(chain, height, height - checkpoint).await?;
+            return Err(Error::Checkpoint { height, checkpoint });
+          }
+        }
+
         Err(Error::Unrecoverable)
       }
       _ => Ok(()),
     }
   }
 
-  pub fn handle_reorg(height: u32, depth: u32) {
+  /// Whether the change records of the blocks a reorg of `depth` at `height`
+  /// rolls back are all kept.
+  fn revertible(height: u32, depth: u32) -> bool {
+    (height - depth + 1..height).all(crate::index::mem_contains_change_record)
+  }
+
+  /// Validates the headers of the competing chain above the common ancestor
+  /// and checks that it carries more work than the indexed chain.
+  async fn check_fork(chain: &ChainParams, height: u32, depth: u32) -> Result<(), Error> {
+    let ancestor_height = height - depth;
+    let mut fork = HashMap::new();
+    let mut fork_work =
+      crate::index::mem_get_chain_work(ancestor_height).ok_or(Error::Unrecoverable)?;
+
+    for h in ancestor_height + 1..=height {
+      let header = crate::bitcoin_api::get_block_header(chain, h)
+        .await
+        .map_err(|_| Error::Unrecoverable)?
+        .ok_or(Error::Unrecoverable)?;
+
+      headers::check_header(chain, h, &header, |a| {
+        fork.get(&a).copied().or_else(|| {
+          if a <= ancestor_height {
+            crate::index::mem_get_block_header(a)
+          } else {
+            None
+          }
+        })
+      })
+      .map_err(|e| {
+        log!(INFO, "invalid header at height {h} on competing chain: {e}");
+        Error::InvalidFork { height }
+      })?;
+
+      fork_work = fork_work + header.work();
+      fork.insert(h, header);
+    }
+
+    let index_work = crate::index::mem_get_chain_work(height - 1).ok_or(Error::Unrecoverable)?;
+    if fork_work <= index_work {
+      return Err(Error::InvalidFork { height });
+    }
+
+    Ok(())
+  }
+
+  pub(crate) fn handle_reorg(height: u32, depth: u32) -> RolledBack {
     log!(
       INFO,
       "rolling back state after reorg of depth {depth} at height {height}"
     );
 
+    let mut rolled_back = RolledBack {
+      fork_height: height - depth + 1,
+      ..Default::default()
+    };
     for h in (height - depth + 1..height).rev() {
       log!(INFO, "rolling back change record at height {h}");
-      if let Some(change_record) = crate::index::mem_get_change_record(h) {
-        change_record
-          .removed_outpoints
-          .iter()
-          .for_each(|(outpoint, rune_balances, height)| {
-            crate::index::mem_insert_outpoint_to_rune_balances(
-              outpoint.store(),
-              rune_balances.clone(),
-            );
-            crate::index::mem_insert_outpoint_to_height(outpoint.store(), *height);
-          });
-        change_record.added_outpoints.iter().for_each(|outpoint| {
-          crate::index::mem_remove_outpoint_to_rune_balances(outpoint.store());
-          crate::index::mem_remove_outpoint_to_height(outpoint.store());
-        });
-        change_record.burned.iter().for_each(|(rune_id, amount)| {
-          let mut entry = crate::index::mem_get_rune_id_to_rune_entry(rune_id.store()).unwrap();
-          entry.burned = *amount;
-          crate::index::mem_insert_rune_id_to_rune_entry(rune_id.store(), entry);
-          log!(
-            INFO,
-            "resetting burned for rune_id: {} to {}",
-            rune_id,
-            amount
-          );
-        });
-        change_record.mints.iter().for_each(|(rune_id, amount)| {
-          let mut entry = crate::index::mem_get_rune_id_to_rune_entry(rune_id.store()).unwrap();
-          entry.mints = *amount;
-          crate::index::mem_insert_rune_id_to_rune_entry(rune_id.store(), entry);
-          log!(
-            INFO,
-            "resetting mints for rune_id: {} to {}",
-            rune_id,
-            amount
-          );
-        });
-        change_record
-          .added_runes
-          .iter()
-          .for_each(|(rune, rune_id, txid)| {
-            crate::index::mem_remove_rune_to_rune_id(rune.store());
-            crate::index::mem_remove_rune_id_to_rune_entry(rune_id.store());
-            crate::index::mem_remove_transaction_id_to_rune(txid.store());
-            log!(INFO, "removing rune_id: {}", rune_id);
-          });
-      }
-      crate::index::mem_remove_change_record(h);
-      crate::index::mem_remove_statistic_runes(h);
-      crate::index::mem_remove_statistic_reserved_runes(h);
-      crate::index::mem_remove_block_header(h);
+      Self::revert_block(h, &mut rolled_back);
     }
+    crate::index::updater::discard_progress();
+
+    crate::index::icrc3::certify_tip();
 
     log!(
       INFO,
       "successfully rolled back state to height {}",
       height - depth,
     );
+
+    rolled_back
+  }
+
+  pub(crate) fn rollback_to_checkpoint(height: u32, checkpoint: u32) -> RolledBack {
+    log!(
+      INFO,
+      "rolling back state to checkpoint {checkpoint} after reorg at height {height}"
+    );
+
+    let mut rolled_back = RolledBack {
+      fork_height: checkpoint + 1,
+      ..Default::default()
+    };
+    for h in (checkpoint + 1..height).rev() {
+      Self::revert_block(h, &mut rolled_back);
+    }
+
+    // checkpoints are reverted newest first, each one undoing the blocks
+    // between it and the next checkpoint
+    for h in crate::index::mem_checkpoint_heights().into_iter().rev() {
+      if h < checkpoint {
+        break;
+      }
+      let Some(Checkpoint {
+        header,
+        runes,
+        reserved_runes,
+        change_record,
+      }) = crate::index::mem_remove_checkpoint(h)
+      else {
+        continue;
+      };
+      log!(INFO, "rolling back checkpoint at height {h}");
+      rolled_back.add_outpoints(&change_record);
+      Self::revert(change_record);
+
+      if h == checkpoint {
+        crate::index::mem_insert_statistic_runes(h, runes);
+        crate::index::mem_insert_statistic_reserved_runes(h, reserved_runes);
+        crate::index::mem_insert_checkpoint(
+          h,
+          Checkpoint {
+            header,
+            runes,
+            reserved_runes,
+            change_record: ChangeRecord::new(),
+          },
+        );
+      }
+    }
+    crate::index::updater::discard_progress();
+
+    crate::index::icrc3::certify_tip();
+
+    log!(
+      INFO,
+      "successfully rolled back state to checkpoint {}",
+      checkpoint
+    );
+
+    rolled_back
+  }
+
+  /// Blocks beyond the reorg window have no change record of their own, they
+  /// are undone by the checkpoints they were folded into.
+  fn revert_block(h: u32, rolled_back: &mut RolledBack) {
+    rolled_back
+      .block_hashes
+      .extend(crate::index::mem_block_hash(h));
+    if let Some(change_record) = crate::index::mem_get_change_record(h) {
+      rolled_back.add_outpoints(&change_record);
+      Self::revert(change_record);
+    }
+    crate::index::mem_remove_change_record(h);
+    crate::index::mem_remove_statistic_runes(h);
+    crate::index::mem_remove_statistic_reserved_runes(h);
+    // the revert event is timestamped with the block's header
+    crate::index::events::revert_block(h);
+    crate::index::mem_remove_block_header(h);
+    crate::index::mem_remove_taproot_outpoints(h);
+    crate::index::mem_remove_tx_proofs(h);
+    crate::index::mem_remove_rune_transactions(h);
+  }
+
+  pub(crate) fn revert(change_record: ChangeRecord) {
+    // the outputs of a coordinator are rolled back by its shards
+    if crate::index::mem_get_config().shards().is_none() {
+      change_record
+        .removed_outpoints
+        .iter()
+        .for_each(|(outpoint, rune_balances, height)| {
+          crate::index::mem_insert_outpoint(outpoint.store(), rune_balances.clone(), *height);
+        });
+      change_record.added_outpoints.iter().for_each(|outpoint| {
+        crate::index::mem_remove_outpoint(outpoint.store());
+      });
+    }
+    change_record.burned.iter().for_each(|(rune_id, amount)| {
+      let mut entry = crate::index::mem_get_rune_id_to_rune_entry(rune_id.store()).unwrap();
+      entry.burned = *amount;
+      crate::index::mem_insert_rune_id_to_rune_entry(rune_id.store(), entry);
+      log!(
+        INFO,
+        "resetting burned for rune_id: {} to {}",
+        rune_id,
+        amount
+      );
+    });
+    change_record.mints.iter().for_each(|(rune_id, amount)| {
+      let mut entry = crate::index::mem_get_rune_id_to_rune_entry(rune_id.store()).unwrap();
+      entry.mints = *amount;
+      crate::index::mem_insert_rune_id_to_rune_entry(rune_id.store(), entry);
+      log!(
+        INFO,
+        "resetting mints for rune_id: {} to {}",
+        rune_id,
+        amount
+      );
+    });
+    change_record
+      .added_runes
+      .iter()
+      .for_each(|(rune, rune_id, txid)| {
+        crate::index::mem_remove_rune_to_rune_id(rune.store());
+        crate::index::mem_remove_rune_id_to_rune_entry(rune_id.store());
+        crate::index::mem_remove_transaction_id_to_rune(txid.store());
+        log!(INFO, "removing rune_id: {}", rune_id);
+      });
+  }
+
+  /// Folds change records that fall out of the reorg window into the latest
+  /// checkpoint, starting a new checkpoint every `interval` blocks.
+  fn checkpoint_change_records(height: u32, interval: u32) {
+    for h in crate::index::mem_change_record_heights(height) {
+      let Some(change_record) = crate::index::mem_get_change_record(h) else {
+        continue;
+      };
+
+      // the latest checkpoint undoes every block up to the next checkpoint,
+      // including the one the next checkpoint is taken at
+      if let Some((checkpoint, mut latest)) = crate::index::mem_latest_checkpoint() {
+        if checkpoint < h {
+          latest.change_record.fold(change_record);
+          crate::index::mem_insert_checkpoint(checkpoint, latest);
+        }
+      }
+
+      if h % interval == 0 {
+        let (Some(header), Some(runes), Some(reserved_runes)) = (
+          crate::index::mem_get_block_header(h),
+          crate::index::mem_get_statistic_runes(h),
+          crate::index::mem_get_statistic_reserved_runes(h),
+        ) else {
+          continue;
+        };
+        log!(INFO, "creating checkpoint at height {h}");
+        crate::index::mem_insert_checkpoint(
+          h,
+          Checkpoint {
+            header,
+            runes,
+            reserved_runes,
+            change_record: ChangeRecord::new(),
+          },
+        );
+
+        let checkpoints = crate::index::mem_checkpoint_heights();
+        for checkpoint in checkpoints
+          .iter()
+          .take(checkpoints.len().saturating_sub(MAX_CHECKPOINTS as usize))
+        {
+          crate::index::mem_remove_checkpoint(*checkpoint);
+        }
+      }
+    }
   }
 
   pub(crate) fn prune_change_record(height: u32) {
-    if height >= MAX_RECOVERABLE_REORG_DEPTH {
-      let h = height - MAX_RECOVERABLE_REORG_DEPTH;
+    let config = crate::index::mem_get_config();
+    let max_reorg_depth = config.max_reorg_depth();
+    if height >= max_reorg_depth {
+      let h = height - max_reorg_depth;
+      if let Some(interval) = config.checkpoint_interval() {
+        Self::checkpoint_change_records(h, interval);
+      }
       log!(INFO, "clearing change record at height {h}");
       crate::index::mem_prune_change_record(h);
       crate::index::mem_prune_statistic_runes(h);
       crate::index::mem_prune_statistic_reserved_runes(h);
-      crate::index::mem_prune_block_header(h);
+      crate::index::mem_prune_rune_transactions(h);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::index::entry::{IndexProgress, RuneBalance, RuneBalances};
+  use bitcoin::block::{Header, Version};
+  use bitcoin::hashes::Hash;
+  use bitcoin::{CompactTarget, TxMerkleNode, Txid};
+  use ordinals::RuneId;
+
+  fn outpoint(vout: u32) -> OutPoint {
+    OutPoint {
+      txid: Txid::all_zeros(),
+      vout,
+    }
+  }
+
+  fn header(nonce: u32) -> Header {
+    Header {
+      version: Version::ONE,
+      prev_blockhash: BlockHash::all_zeros(),
+      merkle_root: TxMerkleNode::all_zeros(),
+      time: 0,
+      bits: CompactTarget::from_consensus(0x207fffff),
+      nonce,
+    }
+  }
+
+  /// Indexes a block at `h` creating output `h`.
+  fn index_block(h: u32) {
+    crate::index::mem_insert_block_header(h, header(h).store());
+    crate::index::mem_insert_statistic_runes(h, h.into());
+    crate::index::mem_insert_statistic_reserved_runes(h, 0);
+    let rune_balances = RuneBalances {
+      balances: vec![RuneBalance {
+        rune_id: RuneId { block: 1, tx: 0 },
+        balance: h.into(),
+      }],
+    };
+    crate::index::mem_insert_outpoint(outpoint(h).store(), rune_balances, h);
+    let mut change_record = ChangeRecord::new();
+    change_record.added_outpoints.push(outpoint(h));
+    crate::index::mem_insert_change_record(h, change_record);
+  }
+
+  #[test]
+  fn reorgs_return_the_rolled_back_blocks() {
+    for h in 1..=5 {
+      index_block(h);
     }
+
+    crate::index::mem_insert_index_progress(
+      6,
+      IndexProgress {
+        block_hash: header(6).block_hash(),
+        next_tx: 1,
+        runes: 0,
+        burned: Vec::new(),
+        change_record: ChangeRecord::new(),
+        staging: Default::default(),
+      },
+    );
+
+    let rolled_back = Reorg::handle_reorg(6, 3);
+    assert_eq!(rolled_back.fork_height, 4);
+    assert_eq!(
+      rolled_back.block_hashes,
+      vec![header(5).block_hash(), header(4).block_hash()]
+    );
+    assert_eq!(
+      rolled_back.outpoints,
+      BTreeSet::from([outpoint(4), outpoint(5)])
+    );
+    assert!(crate::index::mem_get_outpoint(outpoint(3).store()).is_some());
+    assert!(crate::index::mem_get_outpoint(outpoint(4).store()).is_none());
+    assert!(crate::index::mem_get_index_progress().is_none());
+  }
+
+  #[test]
+  fn best_chain_queries_follow_reorgs() {
+    for h in 1..=5 {
+      index_block(h);
+    }
+    let unknown = header(100).block_hash();
+    assert_eq!(
+      crate::index::mem_block_hash(3),
+      Some(header(3).block_hash())
+    );
+    assert_eq!(crate::index::mem_block_hash(6), None);
+    assert_eq!(crate::index::mem_get_block_height(unknown), None);
+    assert_eq!(crate::index::mem_block_confirmations(unknown), None);
+    assert_eq!(
+      crate::index::mem_block_confirmations(header(5).block_hash()),
+      Some(1)
+    );
+    assert_eq!(
+      crate::index::mem_block_confirmations(header(1).block_hash()),
+      Some(5)
+    );
+
+    Reorg::handle_reorg(6, 3);
+    for h in 4..=5 {
+      assert_eq!(crate::index::mem_block_hash(h), None);
+      assert_eq!(
+        crate::index::mem_get_block_height(header(h).block_hash()),
+        None
+      );
+      assert_eq!(
+        crate::index::mem_block_confirmations(header(h).block_hash()),
+        None
+      );
+    }
+    assert_eq!(
+      crate::index::mem_block_confirmations(header(3).block_hash()),
+      Some(1)
+    );
+    assert_eq!(
+      crate::index::mem_block_confirmations(header(1).block_hash()),
+      Some(3)
+    );
+  }
+
+  #[test]
+  fn reorgs_only_reach_the_kept_change_records() {
+    for h in 1..=5 {
+      index_block(h);
+    }
+    crate::index::mem_prune_change_record(2);
+    assert!(Reorg::revertible(6, 1));
+    assert!(Reorg::revertible(6, 4));
+    assert!(!Reorg::revertible(6, 5));
+  }
+
+  #[test]
+  fn large_rollbacks_are_notified_in_parts() {
+    let rolled_back = RolledBack {
+      fork_height: 3,
+      block_hashes: vec![header(3).block_hash()],
+      outpoints: (0..MAX_REVERTED_OUTPOINTS as u32 * 2 + 1)
+        .map(outpoint)
+        .collect(),
+    };
+    let notifications = rolled_back.notifications();
+    assert_eq!(
+      notifications
+        .iter()
+        .map(|n| (n.reverted_outpoints.len(), n.more_outpoints))
+        .collect::<Vec<(usize, bool)>>(),
+      vec![
+        (MAX_REVERTED_OUTPOINTS, true),
+        (MAX_REVERTED_OUTPOINTS, true),
+        (1, false)
+      ]
+    );
+    assert!(notifications
+      .iter()
+      .all(|n| n.fork_height == 3 && n.depth == 1));
+
+    let notifications = RolledBack::default().notifications();
+    assert_eq!(notifications.len(), 1);
+    assert!(!notifications[0].more_outpoints);
+  }
+
+  #[test]
+  fn rollback_undoes_blocks_across_checkpoints() {
+    for h in 1..=6 {
+      index_block(h);
+    }
+    Reorg::checkpoint_change_records(6, 2);
+    crate::index::mem_prune_change_record(6);
+    assert_eq!(crate::index::mem_checkpoint_heights(), vec![2, 4, 6]);
+
+    let rolled_back = Reorg::rollback_to_checkpoint(7, 2);
+    for h in 1..=2 {
+      assert!(crate::index::mem_get_outpoint(outpoint(h).store()).is_some());
+    }
+    for h in 3..=6 {
+      assert!(
+        crate::index::mem_get_outpoint(outpoint(h).store()).is_none(),
+        "block {h} was not undone"
+      );
+    }
+    assert_eq!(crate::index::mem_checkpoint_heights(), vec![2]);
+    assert_eq!(rolled_back.fork_height, 3);
+    assert_eq!(
+      rolled_back.block_hashes,
+      (3..=6)
+        .rev()
+        .map(|h| header(h).block_hash())
+        .collect::<Vec<BlockHash>>()
+    );
+    assert_eq!(
+      rolled_back.outpoints,
+      (3..=6).map(outpoint).collect::<BTreeSet<OutPoint>>()
+    );
   }
 }
@@ -0,0 +1,317 @@
+//! Versioned encodings of the stable values and the migration of values
+//! stored before they were versioned.
+//!
+//! Versioned values are stored as a version byte followed by their bincode
+//! encoding, and are all at version 1. The rune entries, output balances and
+//! heights and change records of schema version 0 are untagged bincode in the
+//! legacy maps, with the same layout as version 1. Reads fall through to the
+//! legacy maps and writes go to the new maps, dropping the legacy entry, so
+//! the index stays usable while `migrate` moves the remaining legacy entries
+//! over in batches. Outputs are moved into a single `PackedOutPoint` each.
+//!
+//! To change a versioned type, bump its `VERSION`, keep the previous layout as
+//! its own struct and convert it in `upgrade`. `Config` is stored as candid,
+//! so new fields only have to be optional.
+
+use super::*;
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::thread::LocalKey;
+
+/// Version of the stable memory layout, bumped with every migration.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Entries moved per map between instruction counter checks.
+const BATCH_SIZE: usize = 1_000;
+
+/// Instructions a migration round may use before yielding to the next one.
+const INSTRUCTION_LIMIT: u64 = 10_000_000_000;
+
+pub trait Versioned: Serialize + DeserializeOwned {
+  const VERSION: u8;
+
+  /// Decodes a value stored with an older `version`.
+  fn upgrade(version: u8, _bytes: &[u8]) -> Self {
+    panic!(
+      "unsupported {} version {}",
+      std::any::type_name::<Self>(),
+      version
+    )
+  }
+}
+
+/// A value stored untagged in a legacy map by schema version 0.
+pub trait LegacyRecord: Serialize + DeserializeOwned {
+  /// Bound of the values in the legacy map.
+  const LEGACY_BOUND: Bound = Bound::Unbounded;
+}
+
+pub(crate) fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+  let mut bytes = vec![T::VERSION];
+  bincode::serialize_into(&mut bytes, value).unwrap();
+  bytes
+}
+
+pub(crate) fn decode<T: Versioned>(bytes: &[u8]) -> T {
+  let (version, bytes) = bytes.split_first().expect("empty versioned value");
+  if *version == T::VERSION {
+    bincode::deserialize(bytes).unwrap()
+  } else {
+    T::upgrade(*version, bytes)
+  }
+}
+
+/// A value of a legacy map, stored without a version byte.
+pub struct Legacy<T>(pub T);
+
+impl<T: LegacyRecord> Storable for Legacy<T> {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(bincode::serialize(&self.0).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    Legacy(bincode::deserialize(&bytes).unwrap())
+  }
+
+  const BOUND: Bound = T::LEGACY_BOUND;
+}
+
+/// Called on `post_upgrade`. Moves the legacy entries left by an older
+/// version with a timer, one round per tick until they are all moved.
+pub fn migrate() {
+  let version = mem_get_schema_version();
+  if version >= SCHEMA_VERSION {
+    return;
+  }
+
+  // the progress of a block indexed halfway holds values in the layout of the
+  // older version, so the block is indexed again from its first transaction
+  mem_clear_index_progress();
+
+  log!(
+    INFO,
+    "migrating stable memory from schema version {} to {}, outputs use {} bytes",
+    version,
+    SCHEMA_VERSION,
+    unpacked_outpoint_bytes()
+  );
+
+  schedule_migration();
+}
+
+fn schedule_migration() {
+  ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+    while ic_cdk::api::instruction_counter() < INSTRUCTION_LIMIT {
+      if migrate_batch(BATCH_SIZE) {
+        mem_set_schema_version(SCHEMA_VERSION).unwrap();
+        log!(
+          INFO,
+          "migrated stable memory to schema version {}, outputs use {} bytes, {} bytes stay allocated to the unpacked maps",
+          SCHEMA_VERSION,
+          packed_outpoint_bytes(),
+          unpacked_outpoint_bytes()
+        );
+        return;
+      }
+    }
+    schedule_migration();
+  });
+}
+
+/// Stable memory allocated to the balance and height maps of outputs that
+/// are not packed. Pages are not released when the maps are emptied.
+fn unpacked_outpoint_bytes() -> u64 {
+  [4, 8].into_iter().map(mem_region_bytes).sum()
+}
+
+fn packed_outpoint_bytes() -> u64 {
+  [21, 24].into_iter().map(mem_region_bytes).sum()
+}
+
+/// Moves up to `limit` entries of every legacy map to its versioned map and
+/// packs up to `limit` outputs, returning true once all are moved.
+pub(crate) fn migrate_batch(limit: usize) -> bool {
+  let done = [
+    migrate_map(&LEGACY_RUNE_ID_TO_RUNE_ENTRY, &RUNE_ID_TO_RUNE_ENTRY, limit),
+    migrate_outpoints(limit),
+    migrate_map(
+      &LEGACY_HEIGHT_TO_CHANGE_RECORD,
+      &HEIGHT_TO_CHANGE_RECORD,
+      limit,
+    ),
+  ];
+  done.into_iter().all(|done| done)
+}
+
+fn migrate_map<K, V>(
+  legacy: &'static LocalKey<RefCell<StableBTreeMap<K, Legacy<V>, Memory>>>,
+  versioned: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+  limit: usize,
+) -> bool
+where
+  K: Storable + Ord + Clone,
+  V: LegacyRecord + Storable,
+{
+  legacy.with(|legacy| {
+    let mut legacy = legacy.borrow_mut();
+    let entries: Vec<(K, Legacy<V>)> = legacy.iter().take(limit).collect();
+    versioned.with(|versioned| {
+      let mut versioned = versioned.borrow_mut();
+      for (key, Legacy(value)) in entries {
+        legacy.remove(&key);
+        // a versioned value was written after the legacy one
+        if !versioned.contains_key(&key) {
+          versioned.insert(key, value);
+        }
+      }
+    });
+    legacy.is_empty()
+  })
+}
+
+/// Packs up to `limit` outputs stored with their balances and height apart,
+/// returning true once all outputs are packed.
+fn migrate_outpoints(limit: usize) -> bool {
+  let outpoints: Vec<OutPointValue> = LEGACY_OUTPOINT_TO_HEIGHT.with(|m| {
+    m.borrow()
+      .iter()
+      .take(limit)
+      .map(|(outpoint, _)| outpoint)
+      .collect()
+  });
+  for outpoint in outpoints {
+    if let Some((rune_balances, height)) = mem_remove_unpacked_outpoint(outpoint) {
+      // a packed output was written after the unpacked one
+      if OUTPOINT_TO_BALANCES.with(|m| !m.borrow().contains_key(&outpoint)) {
+        mem_insert_outpoint(outpoint, rune_balances, height);
+      }
+    }
+  }
+  LEGACY_OUTPOINT_TO_HEIGHT.with(|m| m.borrow().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::index::entry::RuneBalance;
+
+  fn rune_balances(balance: u128) -> RuneBalances {
+    RuneBalances {
+      balances: vec![RuneBalance {
+        rune_id: RuneId {
+          block: 840_000,
+          tx: 1,
+        },
+        balance,
+      }],
+    }
+  }
+
+  fn balance(rune_balances: RuneBalances) -> u128 {
+    rune_balances.balances[0].balance
+  }
+
+  fn output(vout: u32) -> Option<(u128, u32)> {
+    mem_get_outpoint(outpoint(vout)).map(|(rune_balances, height)| (balance(rune_balances), height))
+  }
+
+  fn outpoint(vout: u32) -> OutPointValue {
+    OutPoint {
+      txid: Txid::all_zeros(),
+      vout,
+    }
+    .store()
+  }
+
+  #[test]
+  fn versioned_values_are_tagged() {
+    let mut change_record = ChangeRecord::new();
+    change_record.added_outpoints.push(OutPoint::null());
+    let bytes = change_record.to_bytes();
+    assert_eq!(bytes[0], ChangeRecord::VERSION);
+    assert_eq!(
+      &bytes[1..],
+      bincode::serialize(&change_record).unwrap().as_slice()
+    );
+    assert_eq!(
+      ChangeRecord::from_bytes(bytes).added_outpoints,
+      vec![OutPoint::null()]
+    );
+  }
+
+  #[test]
+  fn legacy_values_are_untagged() {
+    let bytes = bincode::serialize(&rune_balances(7)).unwrap();
+    let Legacy(legacy) = Legacy::<RuneBalances>::from_bytes(Cow::Borrowed(&bytes));
+    assert_eq!(balance(legacy.clone()), 7);
+    assert_eq!(Legacy(legacy).to_bytes().as_ref(), bytes.as_slice());
+  }
+
+  #[test]
+  #[should_panic(expected = "version 9")]
+  fn unknown_versions_are_rejected() {
+    decode::<ChangeRecord>(&[9]);
+  }
+
+  #[test]
+  fn outputs_missing_their_overflow_balances_are_missing() {
+    let rune_balances = RuneBalances {
+      balances: (0..10)
+        .map(|tx| RuneBalance {
+          rune_id: RuneId { block: 840_000, tx },
+          balance: u128::MAX,
+        })
+        .collect(),
+    };
+    mem_insert_outpoint(outpoint(0), rune_balances, 100);
+    assert!(mem_get_outpoint(outpoint(0)).is_some());
+
+    OUTPOINT_TO_OVERFLOW_BALANCES.with(|m| m.borrow_mut().remove(&outpoint(0)));
+    assert!(mem_get_outpoint(outpoint(0)).is_none());
+    assert!(mem_remove_outpoint(outpoint(0)).is_none());
+    assert_eq!(mem_length_outpoints(), 0);
+  }
+
+  #[test]
+  fn legacy_entries_migrate_in_batches() {
+    // outputs stored before they were packed
+    for vout in 0..5 {
+      LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| {
+        m.borrow_mut()
+          .insert(outpoint(vout), Legacy(rune_balances(vout.into())))
+      });
+      LEGACY_OUTPOINT_TO_HEIGHT.with(|m| m.borrow_mut().insert(outpoint(vout), 100 + vout));
+    }
+    LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow_mut().insert(1, Legacy(ChangeRecord::new())));
+
+    // writes replace the unpacked output
+    mem_insert_outpoint(outpoint(4), rune_balances(40), 200);
+    assert_eq!(mem_length_outpoints(), 5);
+
+    assert!(!migrate_batch(2));
+    assert_eq!(mem_length_outpoints(), 5);
+    assert_eq!(mem_length_change_record(), 1);
+    assert_eq!(output(0), Some((0, 100)));
+    assert_eq!(output(3), Some((3, 103)));
+
+    assert!(migrate_batch(2));
+    assert!(LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().is_empty()));
+    assert!(LEGACY_OUTPOINT_TO_HEIGHT.with(|m| m.borrow().is_empty()));
+    assert!(LEGACY_HEIGHT_TO_CHANGE_RECORD.with(|m| m.borrow().is_empty()));
+
+    assert_eq!(mem_length_outpoints(), 5);
+    for vout in 0..4 {
+      assert_eq!(output(vout), Some((vout.into(), 100 + vout)));
+    }
+    assert_eq!(output(4), Some((40, 200)));
+    assert_eq!(mem_change_record_heights(u32::MAX), vec![1]);
+
+    assert_eq!(
+      mem_remove_outpoint(outpoint(0))
+        .map(|(rune_balances, height)| (balance(rune_balances), height)),
+      Some((0, 100))
+    );
+    assert_eq!(output(0), None);
+  }
+}
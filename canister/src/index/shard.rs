@@ -0,0 +1,511 @@
+//! Output balances of a sharded deployment.
+//!
+//! The coordinator indexes blocks with the regular updater and keeps rune
+//! entries, statistics and headers, while the balances of every output are
+//! stored on one of its shards, picked by the hash of the outpoint. Before a
+//! block is indexed the coordinator fetches the balances of the outputs it
+//! spends, and once the block is indexed it sends each shard the outputs the
+//! block added and removed on that shard.
+//!
+//! Shards keep a `ChangeRecord` of outpoints per applied block. Fetching the
+//! outputs of a block first rolls back every block the shard holds at or above
+//! its height, which both retries a block whose apply failed halfway across
+//! the shards and follows the coordinator through reorgs without a separate
+//! call.
+
+use super::*;
+use crate::index::entry::RuneBalance;
+use crate::index::reorg::Reorg;
+use candid::{CandidType, Principal};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::str::FromStr;
+use std::task::Poll;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShardBalance {
+  pub block: u64,
+  pub tx: u32,
+  pub amount: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShardOutput {
+  pub outpoint: String,
+  pub height: u32,
+  pub balances: Vec<ShardBalance>,
+}
+
+/// Outputs a block added to and removed from one shard.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShardBlock {
+  pub height: u32,
+  pub block_hash: String,
+  pub added: Vec<ShardOutput>,
+  pub removed: Vec<String>,
+  /// Change records at or below `height - max_reorg_depth` are pruned.
+  pub max_reorg_depth: u32,
+}
+
+impl ShardOutput {
+  fn new(outpoint: OutPoint, rune_balances: &RuneBalances, height: u32) -> Self {
+    Self {
+      outpoint: outpoint.to_string(),
+      height,
+      balances: rune_balances
+        .balances
+        .iter()
+        .map(|rune_balance| ShardBalance {
+          block: rune_balance.rune_id.block,
+          tx: rune_balance.rune_id.tx,
+          amount: rune_balance.balance,
+        })
+        .collect(),
+    }
+  }
+
+  fn into_parts(self) -> Result<(OutPoint, RuneBalances, u32)> {
+    let outpoint = parse_outpoint(&self.outpoint)?;
+    let balances = self
+      .balances
+      .into_iter()
+      .map(|balance| RuneBalance {
+        rune_id: RuneId {
+          block: balance.block,
+          tx: balance.tx,
+        },
+        balance: balance.amount,
+      })
+      .collect();
+    Ok((outpoint, RuneBalances { balances }, self.height))
+  }
+}
+
+fn parse_outpoint(outpoint: &str) -> Result<OutPoint> {
+  OutPoint::from_str(outpoint).map_err(|e| anyhow!("invalid outpoint {outpoint}: {e}"))
+}
+
+/// Index of the shard storing the balances of `outpoint`.
+pub(crate) fn shard_of(outpoint: OutPoint, shards: usize) -> usize {
+  let hash = Sha256::digest(outpoint.store());
+  let n = u64::from_le_bytes(hash[..8].try_into().unwrap());
+  (n % shards as u64).try_into().unwrap()
+}
+
+/// Balances and heights of `outpoints`, read from the shards when the
+/// canister is a coordinator.
+pub async fn get_outputs(outpoints: &[OutPoint]) -> Result<Vec<Option<(RuneBalances, u32)>>> {
+  let Some(shards) = mem_get_config().shards() else {
+    return Ok(
+      outpoints
+        .iter()
+        .map(|outpoint| mem_get_outpoint(outpoint.store()))
+        .collect(),
+    );
+  };
+
+  fan_out(&shards, outpoints, false, |shard, outpoints| async move {
+    ic_cdk::call::<_, (Vec<Option<ShardOutput>>,)>(shard, "get_shard_outputs", (outpoints,))
+      .await
+      .map(|(outputs,)| outputs)
+      .map_err(|(code, message)| anyhow!("failed to call shard {shard}: {code:?} {message}"))
+  })
+  .await
+}
+
+/// Fetches the outputs spent by the block at `height` from the shards, after
+/// they rolled back any block at or above it.
+pub(crate) async fn prepare_block(
+  shards: &[Principal],
+  height: u32,
+  outpoints: &[OutPoint],
+) -> Result<HashMap<OutPoint, (RuneBalances, u32)>> {
+  // every shard is called to roll back what it holds at or above the height
+  let outputs = fan_out(shards, outpoints, true, |shard, outpoints| async move {
+    ic_cdk::call::<_, (Result<Vec<Option<ShardOutput>>, String>,)>(
+      shard,
+      "prepare_shard_block",
+      (height, outpoints),
+    )
+    .await
+    .map_err(|(code, message)| anyhow!("failed to call shard {shard}: {code:?} {message}"))?
+    .0
+    .map_err(|e| anyhow!("shard {shard} failed to prepare block {height}: {e}"))
+  })
+  .await?;
+
+  Ok(
+    outpoints
+      .iter()
+      .zip(outputs)
+      .filter_map(|(outpoint, output)| output.map(|output| (*outpoint, output)))
+      .collect(),
+  )
+}
+
+/// Sends every shard the outputs the block at `height` added and removed on
+/// it. An error leaves the shards in any state between before and after the
+/// block, which the next `prepare_block` for the height rolls back.
+pub(crate) async fn apply_block(
+  shards: &[Principal],
+  height: u32,
+  block_hash: BlockHash,
+  outpoints: HashMap<OutPoint, Option<(RuneBalances, u32)>>,
+) -> Result<()> {
+  let max_reorg_depth = mem_get_config().max_reorg_depth();
+  let mut blocks = vec![
+    ShardBlock {
+      height,
+      block_hash: block_hash.to_string(),
+      added: Vec::new(),
+      removed: Vec::new(),
+      max_reorg_depth,
+    };
+    shards.len()
+  ];
+
+  for (outpoint, staged) in outpoints {
+    let block = &mut blocks[shard_of(outpoint, shards.len())];
+    match staged {
+      Some((rune_balances, height)) => {
+        block
+          .added
+          .push(ShardOutput::new(outpoint, &rune_balances, height))
+      }
+      None => block.removed.push(outpoint.to_string()),
+    }
+  }
+
+  for (shard, block) in shards.iter().zip(blocks) {
+    if block.added.is_empty() && block.removed.is_empty() {
+      continue;
+    }
+    ic_cdk::call::<_, (Result<(), String>,)>(*shard, "apply_shard_block", (block,))
+      .await
+      .map_err(|(code, message)| anyhow!("failed to call shard {shard}: {code:?} {message}"))?
+      .0
+      .map_err(|e| anyhow!("shard {shard} failed to apply block {height}: {e}"))?;
+  }
+
+  Ok(())
+}
+
+/// Requests the outputs of each shard with `call`, calling the shards at
+/// once, and returns them in the order of `outpoints`. Shards without any of
+/// the outpoints are only called if `every_shard` is set.
+async fn fan_out<F, Fut>(
+  shards: &[Principal],
+  outpoints: &[OutPoint],
+  every_shard: bool,
+  call: F,
+) -> Result<Vec<Option<(RuneBalances, u32)>>>
+where
+  F: Fn(Principal, Vec<String>) -> Fut,
+  Fut: Future<Output = Result<Vec<Option<ShardOutput>>>>,
+{
+  let mut requests = vec![Vec::new(); shards.len()];
+  for (i, outpoint) in outpoints.iter().enumerate() {
+    requests[shard_of(*outpoint, shards.len())].push(i);
+  }
+  let requests = shards
+    .iter()
+    .zip(requests)
+    .filter(|(_, indices)| !indices.is_empty() || every_shard)
+    .collect::<Vec<(&Principal, Vec<usize>)>>();
+
+  let responses = join_all(
+    requests
+      .iter()
+      .map(|(shard, indices)| {
+        call(
+          **shard,
+          indices.iter().map(|i| outpoints[*i].to_string()).collect(),
+        )
+      })
+      .collect(),
+  )
+  .await;
+
+  let mut outputs = vec![None; outpoints.len()];
+  for ((shard, indices), response) in requests.into_iter().zip(responses) {
+    let response = response?;
+    if response.len() != indices.len() {
+      return Err(anyhow!(
+        "shard {shard} returned {} outputs for {} outpoints",
+        response.len(),
+        indices.len()
+      ));
+    }
+    for (i, output) in indices.into_iter().zip(response) {
+      outputs[i] = output
+        .map(ShardOutput::into_parts)
+        .transpose()?
+        .map(|(_, rune_balances, height)| (rune_balances, height));
+    }
+  }
+
+  Ok(outputs)
+}
+
+/// Polls every future until all of them are done, so that their calls are in
+/// flight together.
+async fn join_all<Fut: Future>(futures: Vec<Fut>) -> Vec<Fut::Output> {
+  let mut futures = futures.into_iter().map(Box::pin).collect::<Vec<_>>();
+  let mut outputs = futures.iter().map(|_| None).collect::<Vec<_>>();
+  std::future::poll_fn(|cx| {
+    let mut pending = false;
+    for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+      if output.is_none() {
+        match future.as_mut().poll(cx) {
+          Poll::Ready(result) => *output = Some(result),
+          Poll::Pending => pending = true,
+        }
+      }
+    }
+    if pending {
+      Poll::Pending
+    } else {
+      Poll::Ready(())
+    }
+  })
+  .await;
+  outputs.into_iter().map(Option::unwrap).collect()
+}
+
+/// Outputs stored on this shard, `None` for outpoints without runes.
+pub fn get_shard_outputs(outpoints: Vec<String>) -> Result<Vec<Option<ShardOutput>>> {
+  outpoints
+    .iter()
+    .map(|outpoint| {
+      let outpoint = parse_outpoint(outpoint)?;
+      Ok(
+        mem_get_outpoint(outpoint.store())
+          .map(|(rune_balances, height)| ShardOutput::new(outpoint, &rune_balances, height)),
+      )
+    })
+    .collect()
+}
+
+/// Rolls back the blocks at or above `height` and returns the outputs the
+/// coordinator needs to index it.
+pub fn prepare_shard_block(
+  height: u32,
+  outpoints: Vec<String>,
+) -> Result<Vec<Option<ShardOutput>>> {
+  revert_from(height);
+  get_shard_outputs(outpoints)
+}
+
+pub fn apply_shard_block(block: ShardBlock) -> Result<()> {
+  // parse everything before writing, so a bad request changes nothing
+  let removed = block
+    .removed
+    .iter()
+    .map(|outpoint| parse_outpoint(outpoint))
+    .collect::<Result<Vec<OutPoint>>>()?;
+  let added = block
+    .added
+    .into_iter()
+    .map(ShardOutput::into_parts)
+    .collect::<Result<Vec<(OutPoint, RuneBalances, u32)>>>()?;
+
+  revert_from(block.height);
+
+  let mut change_record = ChangeRecord::new();
+  for outpoint in removed {
+    if let Some((rune_balances, height)) = mem_remove_outpoint(outpoint.store()) {
+      change_record
+        .removed_outpoints
+        .push((outpoint, rune_balances, height));
+    }
+  }
+  for (outpoint, rune_balances, height) in added {
+    mem_insert_outpoint(outpoint.store(), rune_balances, height);
+    change_record.added_outpoints.push(outpoint);
+  }
+  mem_insert_change_record(block.height, change_record);
+
+  if let Some(h) = block.height.checked_sub(block.max_reorg_depth) {
+    mem_prune_change_record(h);
+  }
+
+  log!(
+    INFO,
+    "applied block {} at height {} to shard",
+    block.block_hash,
+    block.height
+  );
+
+  Ok(())
+}
+
+/// Rolls back the change records at or above `height`, newest first.
+fn revert_from(height: u32) {
+  for h in mem_change_record_heights(u32::MAX).into_iter().rev() {
+    if h < height {
+      break;
+    }
+    if let Some(change_record) = mem_remove_change_record(h) {
+      log!(INFO, "rolling back shard block at height {h}");
+      Reorg::revert(change_record);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn outpoint(vout: u32) -> OutPoint {
+    OutPoint {
+      txid: Txid::all_zeros(),
+      vout,
+    }
+  }
+
+  fn output(vout: u32, amount: u128, height: u32) -> ShardOutput {
+    ShardOutput::new(
+      outpoint(vout),
+      &RuneBalances {
+        balances: vec![RuneBalance {
+          rune_id: RuneId {
+            block: 840_000,
+            tx: 1,
+          },
+          balance: amount,
+        }],
+      },
+      height,
+    )
+  }
+
+  fn block(height: u32, added: Vec<ShardOutput>, removed: Vec<u32>) -> ShardBlock {
+    ShardBlock {
+      height,
+      block_hash: BlockHash::all_zeros().to_string(),
+      added,
+      removed: removed
+        .into_iter()
+        .map(|v| outpoint(v).to_string())
+        .collect(),
+      max_reorg_depth: 6,
+    }
+  }
+
+  fn stored(vout: u32) -> Option<ShardOutput> {
+    get_shard_outputs(vec![outpoint(vout).to_string()])
+      .unwrap()
+      .remove(0)
+  }
+
+  /// Runs a future whose pending calls are all ready on the next poll.
+  fn block_on<Fut: Future>(future: Fut) -> Fut::Output {
+    use std::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+    fn raw_waker() -> RawWaker {
+      fn clone(_: *const ()) -> RawWaker {
+        raw_waker()
+      }
+      fn noop(_: *const ()) {}
+      static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+      RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    let waker = unsafe { Waker::from_raw(raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+      if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+        return output;
+      }
+    }
+  }
+
+  /// Stands in for a call: pending on the first poll, ready on the next.
+  async fn call_once() {
+    let mut polled = false;
+    std::future::poll_fn(|_| {
+      if std::mem::replace(&mut polled, true) {
+        Poll::Ready(())
+      } else {
+        Poll::Pending
+      }
+    })
+    .await
+  }
+
+  #[test]
+  fn shards_are_called_at_once() {
+    let shards = (1..=3)
+      .map(|i| Principal::from_slice(&[i]))
+      .collect::<Vec<Principal>>();
+    let outpoints = (0..20).map(outpoint).collect::<Vec<OutPoint>>();
+    let calls = RefCell::new(Vec::new());
+
+    let outputs = block_on(fan_out(&shards, &outpoints, true, |shard, outpoints| {
+      let calls = &calls;
+      async move {
+        calls.borrow_mut().push(("call", shard));
+        call_once().await;
+        calls.borrow_mut().push(("reply", shard));
+        outpoints
+          .iter()
+          .map(|outpoint| Ok(Some(output(parse_outpoint(outpoint)?.vout, 1, 100))))
+          .collect()
+      }
+    }))
+    .unwrap();
+
+    let calls = calls.into_inner();
+    assert!(calls[..3].iter().all(|(kind, _)| *kind == "call"));
+    assert_eq!(calls.len(), 6);
+    assert!(outputs
+      .into_iter()
+      .all(|output| output.is_some_and(|(_, height)| height == 100)));
+  }
+
+  #[test]
+  fn outpoints_spread_over_shards() {
+    let mut counts = [0; 4];
+    for vout in 0..400 {
+      let shard = shard_of(outpoint(vout), 4);
+      assert_eq!(shard, shard_of(outpoint(vout), 4));
+      counts[shard] += 1;
+    }
+    assert!(counts.iter().all(|count| *count > 50));
+  }
+
+  #[test]
+  fn output_round_trip() {
+    let (outpoint, rune_balances, height) = output(3, 7, 100).into_parts().unwrap();
+    assert_eq!(
+      ShardOutput::new(outpoint, &rune_balances, height),
+      output(3, 7, 100)
+    );
+  }
+
+  #[test]
+  fn preparing_a_block_rolls_back_later_blocks() {
+    apply_shard_block(block(100, vec![output(0, 1, 100)], vec![])).unwrap();
+    apply_shard_block(block(101, vec![output(1, 1, 101)], vec![0])).unwrap();
+    assert_eq!(stored(0), None);
+
+    // applying the same block again leaves the same state
+    apply_shard_block(block(101, vec![output(1, 1, 101)], vec![0])).unwrap();
+    assert_eq!(stored(1), Some(output(1, 1, 101)));
+
+    let outputs =
+      prepare_shard_block(101, vec![outpoint(0).to_string(), outpoint(1).to_string()]).unwrap();
+    assert_eq!(outputs, vec![Some(output(0, 1, 100)), None]);
+    assert_eq!(mem_change_record_heights(u32::MAX), vec![100]);
+  }
+
+  #[test]
+  fn invalid_blocks_change_nothing() {
+    let mut invalid = block(100, vec![output(0, 1, 100)], vec![]);
+    invalid.removed.push("not an outpoint".to_string());
+    assert!(apply_shard_block(invalid).is_err());
+    assert_eq!(stored(0), None);
+    assert!(mem_change_record_heights(u32::MAX).is_empty());
+  }
+}
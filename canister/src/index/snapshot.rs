@@ -0,0 +1,229 @@
+//! A snapshot is loaded as the chunks listed in the manifest, in order. Each
+//! chunk is the bincode encoding of a `Vec<SnapshotEntry>`, and is checked
+//! against its sha256 in `SnapshotManifest::chunk_hashes` before it is written
+//! to the index, so a corrupted chunk is refused and can be loaded again.
+//! Finalizing checks the snapshot's block against the chain.
+
+use super::*;
+use crate::config::SnapshotManifest;
+use ic_stable_structures::storable::{Bound, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SnapshotEntry {
+  Rune(RuneId, RuneEntry),
+  OutPoint(OutPoint, RuneBalances, u32),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotProgress {
+  pub next_chunk: u32,
+  pub finalized: bool,
+}
+
+impl Storable for SnapshotProgress {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Returns true if indexing has to wait for a declared snapshot to be loaded.
+pub fn is_pending() -> bool {
+  mem_get_config().snapshot.is_some() && !mem_get_snapshot_progress().finalized
+}
+
+pub fn load_chunk(index: u32, chunk: Vec<u8>) -> Result {
+  let manifest = mem_get_config()
+    .snapshot
+    .ok_or_else(|| anyhow!("no snapshot declared in config"))?;
+  let mut progress = mem_get_snapshot_progress();
+
+  if progress.finalized {
+    return Err(anyhow!("snapshot already finalized"));
+  }
+
+  if index != progress.next_chunk {
+    return Err(anyhow!(
+      "expected chunk {}, got chunk {}",
+      progress.next_chunk,
+      index
+    ));
+  }
+
+  let declared = manifest.chunk_hashes.get(index as usize).ok_or_else(|| {
+    anyhow!(
+      "snapshot only has {} chunks, got chunk {}",
+      manifest.chunk_hashes.len(),
+      index
+    )
+  })?;
+
+  let hash = Sha256::digest(&chunk);
+  if !hex::encode(hash).eq_ignore_ascii_case(declared) {
+    return Err(anyhow!(
+      "chunk {} hash mismatch: got {}, declared {}",
+      index,
+      hex::encode(hash),
+      declared
+    ));
+  }
+
+  let entries: Vec<SnapshotEntry> = bincode::deserialize(&chunk)?;
+
+  for entry in entries {
+    match entry {
+      SnapshotEntry::Rune(id, rune_entry) => {
+        let rune = rune_entry.spaced_rune.rune;
+        mem_insert_rune_to_rune_id(rune.store(), id.store());
+        mem_insert_transaction_id_to_rune(rune_entry.etching.store(), rune.store());
+        mem_insert_rune_id_to_rune_entry(id.store(), rune_entry);
+      }
+      SnapshotEntry::OutPoint(outpoint, rune_balances, height) => {
+        mem_insert_outpoint(outpoint.store(), rune_balances, height);
+      }
+    }
+  }
+
+  progress.next_chunk += 1;
+  mem_set_snapshot_progress(progress)?;
+
+  Ok(())
+}
+
+/// Checks that every chunk is loaded and that the snapshot block is in the
+/// best chain, and records it, returning the height indexing continues from.
+pub async fn finalize() -> Result<u32> {
+  let config = mem_get_config();
+  let manifest = config
+    .snapshot
+    .as_ref()
+    .ok_or_else(|| anyhow!("no snapshot declared in config"))?;
+  let header: Header = consensus::encode::deserialize_hex(&manifest.block_header)?;
+  let start_height = config.start_height();
+  let height = start_height
+    .checked_sub(1)
+    .ok_or_else(|| anyhow!("a snapshot cannot start at height 0"))?;
+
+  let progress = mem_get_snapshot_progress();
+  check_loaded(manifest, &progress)?;
+
+  let block_hash = crate::bitcoin_api::get_block_hash(&config.chain_params(), height)
+    .await?
+    .ok_or_else(|| {
+      anyhow!(
+        "no block at height {} to check the snapshot against",
+        height
+      )
+    })?;
+  if block_hash != header.block_hash() {
+    return Err(anyhow!(
+      "snapshot block {} is not block {} at height {}",
+      header.block_hash(),
+      block_hash,
+      height
+    ));
+  }
+
+  // another call may have finalized the snapshot while waiting for the block
+  let mut progress = mem_get_snapshot_progress();
+  check_loaded(manifest, &progress)?;
+
+  mem_insert_statistic_runes(height, manifest.runes);
+  mem_insert_statistic_reserved_runes(height, manifest.reserved_runes);
+  mem_insert_block_header(height, header.store());
+
+  progress.finalized = true;
+  mem_set_snapshot_progress(progress)?;
+
+  log!(
+    INFO,
+    "snapshot at height {} finalized, indexing continues from height {}",
+    height,
+    start_height
+  );
+
+  Ok(start_height)
+}
+
+fn check_loaded(manifest: &SnapshotManifest, progress: &SnapshotProgress) -> Result {
+  if progress.finalized {
+    return Err(anyhow!("snapshot already finalized"));
+  }
+
+  if progress.next_chunk as usize != manifest.chunk_hashes.len() {
+    return Err(anyhow!(
+      "loaded {} of {} chunks",
+      progress.next_chunk,
+      manifest.chunk_hashes.len()
+    ));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn entries_round_trip() {
+    let outpoint = OutPoint {
+      txid: Txid::all_zeros(),
+      vout: 1,
+    };
+    let chunk = bincode::serialize(&vec![SnapshotEntry::OutPoint(
+      outpoint,
+      RuneBalances { balances: vec![] },
+      840_000,
+    )])
+    .unwrap();
+
+    let entries: Vec<SnapshotEntry> = bincode::deserialize(&chunk).unwrap();
+    assert!(matches!(
+      entries.as_slice(),
+      [SnapshotEntry::OutPoint(o, _, 840_000)] if *o == outpoint
+    ));
+  }
+
+  #[test]
+  fn corrupted_chunks_are_refused() {
+    let chunk = bincode::serialize(&vec![SnapshotEntry::OutPoint(
+      OutPoint {
+        txid: Txid::all_zeros(),
+        vout: 1,
+      },
+      RuneBalances { balances: vec![] },
+      840_000,
+    )])
+    .unwrap();
+    mem_set_config(Config {
+      start_height: Some(840_001),
+      snapshot: Some(SnapshotManifest {
+        block_header: String::new(),
+        runes: 0,
+        reserved_runes: 0,
+        chunk_hashes: vec![hex::encode(Sha256::digest(&chunk))],
+      }),
+      ..Default::default()
+    })
+    .unwrap();
+
+    let mut corrupted = chunk.clone();
+    corrupted.push(0);
+    assert!(load_chunk(0, corrupted).is_err());
+    assert_eq!(mem_length_outpoints(), 0);
+    assert_eq!(mem_get_snapshot_progress().next_chunk, 0);
+
+    load_chunk(0, chunk.clone()).unwrap();
+    assert_eq!(mem_length_outpoints(), 1);
+    assert!(load_chunk(1, chunk).is_err());
+  }
+}
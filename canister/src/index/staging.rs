@@ -0,0 +1,360 @@
+use super::*;
+use crate::index::entry::{RuneEvent, RuneTransaction, RuneTransactions, TxProof};
+
+/// Buffers every write made while indexing a block so that nothing reaches
+/// the stable maps until the whole block has been indexed. Reads fall through
+/// to the stable maps for keys the block has not touched.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Staging {
+  outpoints: HashMap<OutPoint, Option<(RuneBalances, u32)>>,
+  rune_entries: HashMap<RuneId, RuneEntry>,
+  rune_ids: HashMap<Rune, RuneId>,
+  etchings: HashMap<Txid, Rune>,
+  statistic_runes: Option<u64>,
+  statistic_reserved_runes: Option<u64>,
+  change_record: Option<ChangeRecord>,
+  taproot_outpoints: Option<Vec<OutPoint>>,
+  block_header: Option<Header>,
+  transactions: Vec<RuneTransaction>,
+  /// Number of transactions in the block and the inclusion proofs of its
+  /// runestone transactions.
+  tx_proofs: Option<(u32, Vec<(Txid, TxProof)>)>,
+  /// Outputs spent by the block that a coordinator fetched from its shards.
+  /// They are fetched again when indexing resumes.
+  #[serde(skip)]
+  remote_outpoints: HashMap<OutPoint, (RuneBalances, u32)>,
+}
+
+impl Staging {
+  pub(crate) fn insert_outpoint(
+    &mut self,
+    outpoint: OutPoint,
+    rune_balances: RuneBalances,
+    height: u32,
+  ) {
+    self
+      .outpoints
+      .insert(outpoint, Some((rune_balances, height)));
+  }
+
+  pub(crate) fn remove_outpoint(
+    &mut self,
+    outpoint: OutPoint,
+  ) -> Result<Option<(RuneBalances, u32)>> {
+    if let Some(staged) = self.outpoints.get_mut(&outpoint) {
+      return Ok(staged.take());
+    }
+
+    let Some((rune_balances, height)) = self
+      .remote_outpoints
+      .remove(&outpoint)
+      .or_else(|| crate::index::mem_get_outpoint(outpoint.store()))
+    else {
+      return Ok(None);
+    };
+
+    self.outpoints.insert(outpoint, None);
+
+    Ok(Some((rune_balances, height)))
+  }
+
+  pub(crate) fn set_remote_outpoints(&mut self, outpoints: HashMap<OutPoint, (RuneBalances, u32)>) {
+    self.remote_outpoints = outpoints;
+  }
+
+  /// Staged outputs, which a coordinator sends to its shards instead of
+  /// committing them.
+  pub(crate) fn outpoints(&self) -> HashMap<OutPoint, Option<(RuneBalances, u32)>> {
+    self.outpoints.clone()
+  }
+
+  pub(crate) fn get_rune_id_to_rune_entry(&self, id: RuneId) -> Option<RuneEntry> {
+    self
+      .rune_entries
+      .get(&id)
+      .copied()
+      .or_else(|| crate::index::mem_get_rune_id_to_rune_entry(id.store()))
+  }
+
+  pub(crate) fn insert_rune_id_to_rune_entry(&mut self, id: RuneId, entry: RuneEntry) {
+    self.rune_entries.insert(id, entry);
+  }
+
+  pub(crate) fn contains_rune(&self, rune: Rune) -> bool {
+    self.rune_ids.contains_key(&rune)
+      || crate::index::mem_get_rune_to_rune_id(rune.store()).is_some()
+  }
+
+  pub(crate) fn insert_rune(&mut self, rune: Rune, id: RuneId, txid: Txid) {
+    self.rune_ids.insert(rune, id);
+    self.etchings.insert(txid, rune);
+  }
+
+  pub(crate) fn statistic_reserved_runes(&self) -> u64 {
+    self
+      .statistic_reserved_runes
+      .unwrap_or_else(crate::index::mem_statistic_reserved_runes)
+  }
+
+  pub(crate) fn set_statistic_reserved_runes(&mut self, reserved_runes: u64) {
+    self.statistic_reserved_runes = Some(reserved_runes);
+  }
+
+  pub(crate) fn set_statistic_runes(&mut self, runes: u64) {
+    self.statistic_runes = Some(runes);
+  }
+
+  pub(crate) fn set_change_record(&mut self, change_record: ChangeRecord) {
+    self.change_record = Some(change_record);
+  }
+
+  pub(crate) fn set_taproot_outpoints(&mut self, outpoints: Vec<OutPoint>) {
+    self.taproot_outpoints = Some(outpoints);
+  }
+
+  pub(crate) fn set_block_header(&mut self, header: Header) {
+    self.block_header = Some(header);
+  }
+
+  pub(crate) fn push_transaction(&mut self, transaction: RuneTransaction) {
+    self.transactions.push(transaction);
+  }
+
+  pub(crate) fn set_tx_proofs(&mut self, tx_count: u32, tx_proofs: Vec<(Txid, TxProof)>) {
+    self.tx_proofs = Some((tx_count, tx_proofs));
+  }
+
+  /// Events of the block, derived from its change record and the staged
+  /// entries and outputs.
+  fn events(&self) -> Vec<RuneEvent> {
+    let Some(change_record) = &self.change_record else {
+      return Vec::new();
+    };
+    let mut events = Vec::new();
+
+    for (rune, rune_id, txid) in &change_record.added_runes {
+      events.push(RuneEvent::Etched {
+        rune_id: *rune_id,
+        rune: *rune,
+        txid: *txid,
+      });
+    }
+
+    let mut mints = change_record.mints.iter().collect::<Vec<_>>();
+    mints.sort();
+    for (rune_id, previous) in mints {
+      let Some(entry) = self.rune_entries.get(rune_id) else {
+        continue;
+      };
+      let mints = entry.mints - previous;
+      events.push(RuneEvent::Minted {
+        rune_id: *rune_id,
+        mints,
+        amount: mints.saturating_mul(
+          entry
+            .terms
+            .and_then(|terms| terms.amount)
+            .unwrap_or_default(),
+        ),
+      });
+    }
+
+    // outputs spent later in the same block are only in the removed outputs
+    let removed = change_record
+      .removed_outpoints
+      .iter()
+      .map(|(outpoint, rune_balances, _)| (*outpoint, rune_balances))
+      .collect::<HashMap<OutPoint, &RuneBalances>>();
+    for outpoint in &change_record.added_outpoints {
+      let rune_balances = match self.outpoints.get(outpoint) {
+        Some(Some((rune_balances, _))) => Some(rune_balances),
+        _ => removed.get(outpoint).copied(),
+      };
+      for rune_balance in rune_balances.into_iter().flat_map(|b| &b.balances) {
+        events.push(RuneEvent::Allocated {
+          outpoint: *outpoint,
+          rune_id: rune_balance.rune_id,
+          amount: rune_balance.balance,
+        });
+      }
+    }
+
+    for (outpoint, rune_balances, _) in &change_record.removed_outpoints {
+      for rune_balance in &rune_balances.balances {
+        events.push(RuneEvent::Spent {
+          outpoint: *outpoint,
+          rune_id: rune_balance.rune_id,
+          amount: rune_balance.balance,
+        });
+      }
+    }
+
+    let mut burned = change_record.burned.iter().collect::<Vec<_>>();
+    burned.sort();
+    for (rune_id, previous) in burned {
+      let Some(entry) = self.rune_entries.get(rune_id) else {
+        continue;
+      };
+      events.push(RuneEvent::Burned {
+        rune_id: *rune_id,
+        amount: entry.burned - previous,
+      });
+    }
+
+    events
+  }
+
+  /// Writes all staged changes for the block at `height` to the stable maps.
+  pub(crate) fn commit(self, height: u32) {
+    let config = crate::index::mem_get_config();
+
+    if config.event_log.is_some() {
+      let timestamp = self
+        .block_header
+        .map(|header| header.time)
+        .unwrap_or_default();
+      crate::index::events::append(height, timestamp, self.events());
+      crate::index::icrc3::certify_tip();
+    }
+
+    // the outputs of a coordinator were sent to its shards
+    if config.shards().is_none() {
+      for (outpoint, staged) in self.outpoints {
+        match staged {
+          Some((rune_balances, outpoint_height)) => {
+            crate::index::mem_insert_outpoint(outpoint.store(), rune_balances, outpoint_height);
+          }
+          None => {
+            crate::index::mem_remove_outpoint(outpoint.store());
+          }
+        }
+      }
+    }
+
+    for (id, entry) in self.rune_entries {
+      crate::index::mem_insert_rune_id_to_rune_entry(id.store(), entry);
+    }
+
+    for (rune, id) in self.rune_ids {
+      crate::index::mem_insert_rune_to_rune_id(rune.store(), id.store());
+    }
+
+    for (txid, rune) in self.etchings {
+      crate::index::mem_insert_transaction_id_to_rune(txid.store(), rune.store());
+    }
+
+    if let Some(runes) = self.statistic_runes {
+      crate::index::mem_insert_statistic_runes(height, runes);
+    }
+
+    if let Some(reserved_runes) = self.statistic_reserved_runes {
+      crate::index::mem_insert_statistic_reserved_runes(height, reserved_runes);
+    }
+
+    if let Some(change_record) = self.change_record {
+      crate::index::mem_insert_change_record(height, change_record);
+    }
+
+    if let Some(taproot_outpoints) = self.taproot_outpoints {
+      crate::index::mem_insert_taproot_outpoints(height, taproot_outpoints);
+    }
+
+    if !self.transactions.is_empty() {
+      crate::index::mem_insert_rune_transactions(
+        height,
+        RuneTransactions {
+          transactions: self.transactions,
+        },
+      );
+    }
+
+    if let Some((tx_count, tx_proofs)) = self.tx_proofs {
+      crate::index::mem_insert_tx_proofs(height, tx_count, tx_proofs);
+    }
+
+    if let Some(header) = self.block_header {
+      crate::index::mem_insert_block_header(height, header.store());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::index::entry::RuneBalance;
+
+  fn outpoint(vout: u32) -> OutPoint {
+    OutPoint {
+      txid: Txid::all_zeros(),
+      vout,
+    }
+  }
+
+  fn rune_balances(balance: u128) -> RuneBalances {
+    RuneBalances {
+      balances: vec![RuneBalance {
+        rune_id: RuneId { block: 1, tx: 0 },
+        balance,
+      }],
+    }
+  }
+
+  fn balance(vout: u32) -> Option<u128> {
+    crate::index::mem_get_outpoint(outpoint(vout).store())
+      .map(|(rune_balances, _)| rune_balances.balances[0].balance)
+  }
+
+  fn staging(height: u32) -> Staging {
+    let mut staging = Staging::default();
+    staging.insert_outpoint(outpoint(1), rune_balances(10), height);
+    assert_eq!(
+      staging
+        .remove_outpoint(outpoint(0))
+        .unwrap()
+        .map(|(rune_balances, _)| rune_balances.balances[0].balance),
+      Some(5)
+    );
+    staging.set_statistic_runes(1);
+    staging.set_tx_proofs(
+      1,
+      vec![(
+        Txid::all_zeros(),
+        TxProof {
+          block_hash: BlockHash::all_zeros(),
+          index: 0,
+          branch: Vec::new(),
+        },
+      )],
+    );
+    staging
+  }
+
+  #[test]
+  fn staged_writes_are_committed_together() {
+    crate::index::mem_insert_outpoint(outpoint(0), rune_balances(5), 1);
+    let mut staging = staging(2);
+
+    // the stable maps are untouched until the commit
+    assert_eq!(balance(0), Some(5));
+    assert_eq!(balance(1), None);
+    assert!(staging.remove_outpoint(outpoint(0)).unwrap().is_none());
+
+    staging.commit(2);
+    assert_eq!(balance(0), None);
+    assert_eq!(balance(1), Some(10));
+    assert_eq!(crate::index::mem_get_statistic_runes(2), Some(1));
+    assert!(crate::index::mem_get_tx_proof(Txid::all_zeros()).is_some());
+  }
+
+  #[test]
+  fn aborted_blocks_leave_nothing_behind() {
+    crate::index::mem_insert_outpoint(outpoint(0), rune_balances(5), 1);
+    drop(staging(2));
+
+    assert_eq!(balance(0), Some(5));
+    assert_eq!(balance(1), None);
+    assert_eq!(crate::index::mem_get_statistic_runes(2), None);
+    assert!(crate::index::mem_get_tx_proof(Txid::all_zeros()).is_none());
+    assert_eq!(crate::index::mem_length_tx_proofs(), 0);
+  }
+}
@@ -0,0 +1,257 @@
+//! Stable memory usage per region and its growth over recently indexed blocks.
+
+use super::*;
+use crate::logs::WARNING;
+use ic_stable_structures::storable::Storable;
+use std::collections::VecDeque;
+use std::thread::LocalKey;
+
+/// Blocks whose stable memory size is kept to project the growth per block.
+const MAX_SAMPLES: usize = 144;
+
+thread_local! {
+  /// Height and total stable memory size after each recently indexed block.
+  static SAMPLES: RefCell<VecDeque<(u32, u64)>> = RefCell::new(VecDeque::new());
+
+  /// Whether the stable and heap memory thresholds were crossed at the last sample.
+  static THRESHOLDS_CROSSED: RefCell<(bool, bool)> = RefCell::new((false, false));
+}
+
+pub struct Region {
+  pub memory_id: u8,
+  pub name: &'static str,
+  pub entries: u64,
+  pub stable_bytes: u64,
+}
+
+pub struct StorageStats {
+  pub regions: Vec<Region>,
+  pub heap_bytes: u64,
+  pub stable_bytes: u64,
+  pub growth_per_block: Option<u64>,
+  pub sampled_blocks: u32,
+}
+
+pub fn storage_stats() -> StorageStats {
+  let regions = [
+    (0, "config", 1),
+    (1, "height_to_block_header", len(&HEIGHT_TO_BLOCK_HEADER)),
+    (
+      2,
+      "height_to_statistic_reserved_runes",
+      len(&HEIGHT_TO_STATISTIC_RESERVED_RUNES),
+    ),
+    (
+      3,
+      "height_to_statistic_runes",
+      len(&HEIGHT_TO_STATISTIC_RUNES),
+    ),
+    (
+      4,
+      "legacy_outpoint_to_rune_balances",
+      len(&LEGACY_OUTPOINT_TO_RUNE_BALANCES),
+    ),
+    (
+      5,
+      "legacy_rune_id_to_rune_entry",
+      len(&LEGACY_RUNE_ID_TO_RUNE_ENTRY),
+    ),
+    (6, "rune_to_rune_id", len(&RUNE_TO_RUNE_ID)),
+    (7, "transaction_id_to_rune", len(&TRANSACTION_ID_TO_RUNE)),
+    (
+      8,
+      "legacy_outpoint_to_height",
+      len(&LEGACY_OUTPOINT_TO_HEIGHT),
+    ),
+    (
+      9,
+      "legacy_height_to_change_record",
+      len(&LEGACY_HEIGHT_TO_CHANGE_RECORD),
+    ),
+    (
+      10,
+      "outpoint_to_taproot_height",
+      len(&OUTPOINT_TO_TAPROOT_HEIGHT),
+    ),
+    (
+      11,
+      "height_to_taproot_outpoints",
+      len(&HEIGHT_TO_TAPROOT_OUTPOINTS),
+    ),
+    (
+      12,
+      "height_to_index_progress",
+      len(&HEIGHT_TO_INDEX_PROGRESS),
+    ),
+    (13, "height_to_checkpoint", len(&HEIGHT_TO_CHECKPOINT)),
+    (14, "block_hash_to_height", len(&BLOCK_HASH_TO_HEIGHT)),
+    (15, "height_to_chain_work", len(&HEIGHT_TO_CHAIN_WORK)),
+    (16, "txid_to_tx_proof", len(&TXID_TO_TX_PROOF)),
+    (17, "height_to_proof_txids", len(&HEIGHT_TO_PROOF_TXIDS)),
+    (18, "snapshot_progress", 1),
+    (19, "schema_version", 1),
+    (20, "rune_id_to_rune_entry", len(&RUNE_ID_TO_RUNE_ENTRY)),
+    (
+      21,
+      "outpoint_to_overflow_balances",
+      len(&OUTPOINT_TO_OVERFLOW_BALANCES),
+    ),
+    (22, "height_to_change_record", len(&HEIGHT_TO_CHANGE_RECORD)),
+    (24, "outpoint_to_balances", len(&OUTPOINT_TO_BALANCES)),
+    (26, "events", len(&EVENTS)),
+    (27, "height_to_first_event", len(&HEIGHT_TO_FIRST_EVENT)),
+    (28, "archives", 1),
+    (29, "archive_wasm", 1),
+    (30, "last_event_hash", 1),
+    (
+      31,
+      "height_to_rune_transactions",
+      len(&HEIGHT_TO_RUNE_TRANSACTIONS),
+    ),
+    (32, "outbox", len(&OUTBOX)),
+    (33, "outbox_cursors", 1),
+    (34, "subscriptions", 1),
+    (35, "subscriber_settings", 1),
+    (36, "webhooks", 1),
+    (37, "roles", 1),
+    (38, "audit_log", len(&AUDIT_LOG)),
+  ]
+  .into_iter()
+  .map(|(memory_id, name, entries)| Region {
+    memory_id,
+    name,
+    entries,
+    stable_bytes: mem_region_bytes(memory_id),
+  })
+  .collect();
+
+  let (growth_per_block, sampled_blocks) = SAMPLES.with(|samples| {
+    let samples = samples.borrow();
+    (growth_per_block(&samples), samples.len() as u32)
+  });
+
+  StorageStats {
+    regions,
+    heap_bytes: heap_bytes(),
+    stable_bytes: stable_bytes(),
+    growth_per_block,
+    sampled_blocks,
+  }
+}
+
+fn len<K, V>(map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>) -> u64
+where
+  K: Storable + Ord + Clone,
+  V: Storable,
+{
+  map.with(|m| m.borrow().len())
+}
+
+fn stable_bytes() -> u64 {
+  ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE
+}
+
+fn heap_bytes() -> u64 {
+  #[cfg(target_arch = "wasm32")]
+  {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+  }
+  #[cfg(not(target_arch = "wasm32"))]
+  {
+    0
+  }
+}
+
+/// Records the stable memory size after indexing the block at `height` and
+/// logs a warning when it or the heap size first cross the configured
+/// thresholds.
+pub(crate) fn record_block(height: u32) {
+  let stable_bytes = stable_bytes();
+  SAMPLES.with(|samples| push_sample(&mut samples.borrow_mut(), height, stable_bytes));
+
+  let config = mem_get_config();
+  let heap_bytes = heap_bytes();
+  let crossed = (
+    config
+      .stable_memory_warning_bytes
+      .is_some_and(|threshold| stable_bytes >= threshold),
+    config
+      .heap_memory_warning_bytes
+      .is_some_and(|threshold| heap_bytes >= threshold),
+  );
+  let previous = THRESHOLDS_CROSSED.with(|c| c.replace(crossed));
+
+  if crossed.0 && !previous.0 {
+    log!(
+      WARNING,
+      "stable memory size {} bytes at height {} crossed the warning threshold",
+      stable_bytes,
+      height
+    );
+  }
+  if crossed.1 && !previous.1 {
+    log!(
+      WARNING,
+      "heap memory size {} bytes at height {} crossed the warning threshold",
+      heap_bytes,
+      height
+    );
+  }
+}
+
+fn push_sample(samples: &mut VecDeque<(u32, u64)>, height: u32, stable_bytes: u64) {
+  // a reorg rolled the chain back, so earlier samples no longer line up
+  if samples.back().is_some_and(|(last, _)| *last >= height) {
+    samples.clear();
+  }
+  if samples.len() == MAX_SAMPLES {
+    samples.pop_front();
+  }
+  samples.push_back((height, stable_bytes));
+}
+
+/// Average stable memory growth per block between the oldest and the newest
+/// sample.
+fn growth_per_block(samples: &VecDeque<(u32, u64)>) -> Option<u64> {
+  let (first_height, first_bytes) = samples.front()?;
+  let (last_height, last_bytes) = samples.back()?;
+  let blocks = last_height.checked_sub(*first_height).filter(|b| *b > 0)?;
+  Some(last_bytes.saturating_sub(*first_bytes) / u64::from(blocks))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn growth_needs_two_samples() {
+    let mut samples = VecDeque::new();
+    assert_eq!(growth_per_block(&samples), None);
+    push_sample(&mut samples, 100, 1_000);
+    assert_eq!(growth_per_block(&samples), None);
+    push_sample(&mut samples, 101, 1_500);
+    push_sample(&mut samples, 104, 3_000);
+    assert_eq!(growth_per_block(&samples), Some(500));
+  }
+
+  #[test]
+  fn reorg_restarts_samples() {
+    let mut samples = VecDeque::new();
+    push_sample(&mut samples, 100, 1_000);
+    push_sample(&mut samples, 101, 2_000);
+    push_sample(&mut samples, 101, 2_000);
+    assert_eq!(samples.len(), 1);
+    assert_eq!(growth_per_block(&samples), None);
+  }
+
+  #[test]
+  fn samples_are_capped() {
+    let mut samples = VecDeque::new();
+    for height in 0..MAX_SAMPLES as u32 + 10 {
+      push_sample(&mut samples, height, u64::from(height) * 10);
+    }
+    assert_eq!(samples.len(), MAX_SAMPLES);
+    assert_eq!(samples.front(), Some(&(10, 100)));
+    assert_eq!(growth_per_block(&samples), Some(10));
+  }
+}
@@ -0,0 +1,424 @@
+//! Subscribers and their filtered subscriptions.
+//!
+//! Controllers add and remove subscribers at runtime, with settings such as
+//! the callback and notification version. A subscriber without a subscription
+//! gets every block in its notification version. A subscriber that subscribed
+//! with a filter gets `RuneBlock`s holding only the matching events, on the
+//! callback of its choice, and no notification for blocks without any.
+
+use super::*;
+use crate::config::NotificationVersion;
+use crate::index::entry::RuneTransaction;
+use bitcoin::ScriptBuf;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+/// Longest accepted callback method name and label.
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventKind {
+  Etch,
+  Mint,
+  /// Balances allocated to outputs.
+  Transfer,
+  Burn,
+}
+
+/// Empty lists match everything.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Subscription {
+  pub rune_ids: Vec<String>,
+  /// Hex-encoded script pubkeys of outputs receiving runes.
+  pub script_pubkeys: Vec<String>,
+  pub events: Vec<EventKind>,
+  /// Called instead of `new_block_detected_v2`.
+  pub callback: Option<String>,
+}
+
+impl Subscription {
+  pub fn validate(&self) -> Result<(), String> {
+    for rune_id in &self.rune_ids {
+      RuneId::from_str(rune_id).map_err(|e| format!("invalid rune id {}: {}", rune_id, e))?;
+    }
+    for script_pubkey in &self.script_pubkeys {
+      ScriptBuf::from_hex(script_pubkey)
+        .map_err(|e| format!("invalid script pubkey {}: {}", script_pubkey, e))?;
+    }
+    validate_name("callback", self.callback.as_deref())
+  }
+
+  pub fn filter(&self) -> Filter {
+    Filter {
+      rune_ids: self
+        .rune_ids
+        .iter()
+        .map(|rune_id| RuneId::from_str(rune_id).expect("validated on subscribe"))
+        .collect(),
+      script_pubkeys: self
+        .script_pubkeys
+        .iter()
+        .map(|script| ScriptBuf::from_hex(script).expect("validated on subscribe"))
+        .collect(),
+      events: self.events.iter().copied().collect(),
+    }
+  }
+}
+
+pub struct Filter {
+  rune_ids: BTreeSet<RuneId>,
+  script_pubkeys: BTreeSet<ScriptBuf>,
+  events: BTreeSet<EventKind>,
+}
+
+impl Filter {
+  fn matches_rune(&self, rune_id: &RuneId) -> bool {
+    self.rune_ids.is_empty() || self.rune_ids.contains(rune_id)
+  }
+
+  fn matches_event(&self, kind: EventKind) -> bool {
+    self.events.is_empty() || self.events.contains(&kind)
+  }
+
+  /// The matching events of the transaction, or `None` if there are none.
+  /// With script pubkeys, only transactions allocating runes to one of them
+  /// match, and only those allocations are kept.
+  pub fn apply(&self, transaction: &RuneTransaction) -> Option<RuneTransaction> {
+    let to_script = |vout: &u32| {
+      self.script_pubkeys.is_empty()
+        || transaction
+          .script(*vout)
+          .is_some_and(|script| self.script_pubkeys.contains(script))
+    };
+    if !self.script_pubkeys.is_empty()
+      && !transaction
+        .allocated
+        .iter()
+        .any(|(vout, _, _)| to_script(vout))
+    {
+      return None;
+    }
+
+    let mut filtered = RuneTransaction::new(transaction.txid);
+    filtered.etched = transaction
+      .etched
+      .filter(|(rune_id, _)| self.matches_event(EventKind::Etch) && self.matches_rune(rune_id));
+    filtered.minted = transaction
+      .minted
+      .filter(|(rune_id, _)| self.matches_event(EventKind::Mint) && self.matches_rune(rune_id));
+    if self.matches_event(EventKind::Transfer) {
+      filtered.allocated = transaction
+        .allocated
+        .iter()
+        .filter(|(vout, rune_id, _)| to_script(vout) && self.matches_rune(rune_id))
+        .copied()
+        .collect();
+    }
+    if self.matches_event(EventKind::Burn) {
+      filtered.burned = transaction
+        .burned
+        .iter()
+        .filter(|(rune_id, _)| self.matches_rune(rune_id))
+        .copied()
+        .collect();
+    }
+    filtered.scripts = transaction
+      .scripts
+      .iter()
+      .filter(|(vout, _)| {
+        filtered
+          .allocated
+          .iter()
+          .any(|(output, _, _)| output == vout)
+      })
+      .cloned()
+      .collect();
+
+    (!filtered.is_empty()).then_some(filtered)
+  }
+}
+
+pub(super) fn validate_name(field: &str, name: Option<&str>) -> Result<(), String> {
+  match name {
+    Some(name) if name.is_empty() || name.len() > MAX_NAME_LENGTH => Err(format!(
+      "the {} must have 1 to {} characters",
+      field, MAX_NAME_LENGTH
+    )),
+    _ => Ok(()),
+  }
+}
+
+/// Settings of a subscriber, managed by controllers.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubscriberSettings {
+  pub label: Option<String>,
+  /// Called with blocks unless the subscriber chose a callback with
+  /// `subscribe`.
+  pub callback: Option<String>,
+  /// Overrides `Config::notification_version` when there is no subscription.
+  pub notification_version: Option<NotificationVersion>,
+  /// Notifications of a paused subscriber are queued but not delivered.
+  pub paused: bool,
+}
+
+impl SubscriberSettings {
+  pub fn validate(&self) -> Result<(), String> {
+    validate_name("label", self.label.as_deref())?;
+    validate_name("callback", self.callback.as_deref())
+  }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Subscriber {
+  pub canister_id: Principal,
+  pub settings: SubscriberSettings,
+  pub subscription: Option<Subscription>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Subscriptions(BTreeMap<Principal, Subscription>);
+
+impl Storable for Subscriptions {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Settings(BTreeMap<Principal, SubscriberSettings>);
+
+impl Storable for Settings {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(self).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    candid::decode_one(bytes.as_ref()).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn get(subscriber: &Principal) -> Option<Subscription> {
+  SUBSCRIPTIONS.with(|c| c.borrow().get().0.get(subscriber).cloned())
+}
+
+pub fn settings(subscriber: &Principal) -> SubscriberSettings {
+  SUBSCRIBER_SETTINGS
+    .with(|c| c.borrow().get().0.get(subscriber).cloned())
+    .unwrap_or_default()
+}
+
+/// Method called with blocks, if not the default one.
+pub fn callback(subscriber: &Principal) -> Option<String> {
+  get(subscriber)
+    .and_then(|subscription| subscription.callback)
+    .or_else(|| settings(subscriber).callback)
+}
+
+/// Adds a subscriber, or updates the settings of an existing one.
+pub fn add_subscriber(subscriber: Principal, settings: SubscriberSettings) -> Result<(), String> {
+  settings.validate()?;
+  let mut config = mem_get_config();
+  if !config.subscribers.contains(&subscriber) {
+    config.subscribers.push(subscriber);
+    mem_set_config(config).map_err(|e| e.to_string())?;
+    outbox::sync(&outbox::recipients());
+  }
+  log!(INFO, "subscriber {} set to {:?}", subscriber, settings);
+  update_settings(|all| {
+    all.insert(subscriber, settings);
+  });
+  Ok(())
+}
+
+pub fn remove_subscriber(subscriber: Principal) -> Result<(), String> {
+  let mut config = mem_get_config();
+  if !config.subscribers.contains(&subscriber) {
+    return Err("Not a subscriber".to_string());
+  }
+  config.subscribers.retain(|s| *s != subscriber);
+  mem_set_config(config.clone()).map_err(|e| e.to_string())?;
+  retain(&config.subscribers);
+  outbox::sync(&outbox::recipients());
+  log!(INFO, "subscriber {} removed", subscriber);
+  Ok(())
+}
+
+pub fn list_subscribers() -> Vec<Subscriber> {
+  mem_get_config()
+    .subscribers
+    .into_iter()
+    .map(|canister_id| Subscriber {
+      canister_id,
+      settings: settings(&canister_id),
+      subscription: get(&canister_id),
+    })
+    .collect()
+}
+
+/// Drops the settings and subscriptions of former subscribers.
+pub fn retain(subscribers: &[Principal]) {
+  update_settings(|all| all.retain(|subscriber, _| subscribers.contains(subscriber)));
+  update(|all| all.retain(|subscriber, _| subscribers.contains(subscriber)));
+}
+
+pub fn subscribe(subscriber: Principal, subscription: Subscription) -> Result<(), String> {
+  if !mem_get_config().subscribers.contains(&subscriber) {
+    return Err("Not a subscriber".to_string());
+  }
+  subscription.validate()?;
+  update(|subscriptions| {
+    subscriptions.insert(subscriber, subscription);
+  });
+  Ok(())
+}
+
+/// Goes back to receiving every block.
+pub fn unsubscribe(subscriber: Principal) -> Result<(), String> {
+  let removed = update(|subscriptions| subscriptions.remove(&subscriber));
+  removed
+    .map(|_| ())
+    .ok_or_else(|| "Not subscribed".to_string())
+}
+
+fn update<T>(f: impl FnOnce(&mut BTreeMap<Principal, Subscription>) -> T) -> T {
+  SUBSCRIPTIONS.with(|c| {
+    let mut subscriptions = c.borrow().get().clone();
+    let result = f(&mut subscriptions.0);
+    c.borrow_mut().set(subscriptions).unwrap();
+    result
+  })
+}
+
+fn update_settings(f: impl FnOnce(&mut BTreeMap<Principal, SubscriberSettings>)) {
+  SUBSCRIBER_SETTINGS.with(|c| {
+    let mut settings = c.borrow().get().clone();
+    f(&mut settings.0);
+    c.borrow_mut().set(settings).unwrap();
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SCRIPT: &str = "0014000102030405060708090a0b0c0d0e0f10111213";
+
+  fn rune_id(tx: u32) -> RuneId {
+    RuneId { block: 840_000, tx }
+  }
+
+  fn transaction() -> RuneTransaction {
+    let mut transaction = RuneTransaction::new(Txid::all_zeros());
+    transaction.minted = Some((rune_id(1), 100));
+    transaction.allocated = vec![(0, rune_id(1), 100), (1, rune_id(2), 5)];
+    transaction.burned = vec![(rune_id(2), 1)];
+    transaction.scripts = vec![
+      (0, ScriptBuf::from_hex(SCRIPT).unwrap()),
+      (1, ScriptBuf::new()),
+    ];
+    transaction
+  }
+
+  #[test]
+  fn empty_filters_match_everything() {
+    let filter = Subscription::default().filter();
+    assert_eq!(filter.apply(&transaction()), Some(transaction()));
+  }
+
+  #[test]
+  fn events_are_filtered_by_rune_and_kind() {
+    let filter = Subscription {
+      rune_ids: vec![rune_id(2).to_string()],
+      events: vec![EventKind::Transfer, EventKind::Mint],
+      ..Default::default()
+    }
+    .filter();
+    let filtered = filter.apply(&transaction()).unwrap();
+    assert_eq!(filtered.minted, None);
+    assert_eq!(filtered.allocated, vec![(1, rune_id(2), 5)]);
+    assert!(filtered.burned.is_empty());
+    assert_eq!(filtered.scripts, vec![(1, ScriptBuf::new())]);
+
+    let filter = Subscription {
+      events: vec![EventKind::Etch],
+      ..Default::default()
+    }
+    .filter();
+    assert_eq!(filter.apply(&transaction()), None);
+  }
+
+  #[test]
+  fn transactions_are_filtered_by_receiving_script() {
+    let filter = Subscription {
+      script_pubkeys: vec![SCRIPT.to_string()],
+      ..Default::default()
+    }
+    .filter();
+    let filtered = filter.apply(&transaction()).unwrap();
+    assert_eq!(filtered.minted, Some((rune_id(1), 100)));
+    assert_eq!(filtered.allocated, vec![(0, rune_id(1), 100)]);
+
+    let mut unrelated = transaction();
+    unrelated.scripts.clear();
+    assert_eq!(filter.apply(&unrelated), None);
+  }
+
+  #[test]
+  fn invalid_subscriptions_are_rejected() {
+    for subscription in [
+      Subscription {
+        rune_ids: vec!["840000".to_string()],
+        ..Default::default()
+      },
+      Subscription {
+        script_pubkeys: vec!["zz".to_string()],
+        ..Default::default()
+      },
+      Subscription {
+        callback: Some(String::new()),
+        ..Default::default()
+      },
+    ] {
+      assert!(subscription.validate().is_err());
+    }
+  }
+
+  #[test]
+  fn subscription_callback_takes_precedence() {
+    let subscriber = Principal::from_slice(&[7]);
+    add_subscriber(
+      subscriber,
+      SubscriberSettings {
+        callback: Some("on_block".to_string()),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(callback(&subscriber).as_deref(), Some("on_block"));
+
+    subscribe(
+      subscriber,
+      Subscription {
+        callback: Some("on_runes".to_string()),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(callback(&subscriber).as_deref(), Some("on_runes"));
+
+    remove_subscriber(subscriber).unwrap();
+    assert_eq!(callback(&subscriber), None);
+    assert!(remove_subscriber(subscriber).is_err());
+  }
+}
@@ -1,12 +1,29 @@
 use self::rune_updater::RuneUpdater;
 use super::*;
-use crate::index::reorg::Reorg;
+use crate::chain::ChainParams;
+use crate::config::NotificationVersion;
+use crate::index::entry::TxProof;
+use crate::index::reorg::{Reorg, RolledBack};
+use crate::index::staging::Staging;
+use crate::index::webhooks::WebhookEvent;
+use crate::into_usize::IntoUsize;
 use crate::logs::{CRITICAL, INFO};
 use crate::timestamp;
 use candid::Principal;
+use runes_indexer_interface::{NewBlockRequest, Notification};
 
 mod rune_updater;
 
+/// Instructions a single message may spend on a block before the progress is
+/// saved and indexing continues in the next tick. The limit for an update
+/// message is 40B, leaving room to finish the block after the last transaction.
+const INSTRUCTIONS_PER_MESSAGE: u64 = 20_000_000_000;
+
+thread_local! {
+  // block being indexed across multiple messages, refetched if lost on upgrade
+  static PENDING_BLOCK: RefCell<Option<(BlockHash, BlockData)>> = const { RefCell::new(None) };
+}
+
 pub(crate) struct BlockData {
   pub(crate) header: Header,
   pub(crate) txdata: Vec<(Transaction, Txid)>,
@@ -28,92 +45,101 @@ impl From<Block> for BlockData {
   }
 }
 
-pub fn update_index(network: BitcoinNetwork, subscribers: Vec<Principal>) -> Result {
+pub fn update_index(chain: ChainParams) -> Result {
   ic_cdk_timers::set_timer(std::time::Duration::from_secs(10), move || {
     ic_cdk::spawn(async move {
-      let (height, index_prev_blockhash) = crate::index::next_block(network);
-      match crate::bitcoin_api::get_block_hash(network, height).await {
-        Ok(Some(block_hash)) => match crate::rpc::get_block(block_hash).await {
-          Ok(block) => {
-            match Reorg::detect_reorg(
-              network,
-              index_prev_blockhash,
-              block.header.prev_blockhash,
-              height,
-            )
-            .await
-            {
-              Ok(()) => {
-                let txids: Vec<String> = block
-                  .txdata
-                  .iter()
-                  .map(|(_, txid)| txid.to_string())
-                  .collect();
-                if let Err(e) = index_block(height, block).await {
-                  log!(
-                    CRITICAL,
-                    "failed to index_block at height {}: {:?}",
-                    height,
-                    e
-                  );
-                  return;
-                }
-                Reorg::prune_change_record(height);
-                for subscriber in subscribers.iter() {
-                  let _ = crate::notifier::notify_new_block(
-                    *subscriber,
-                    height,
-                    block_hash.to_string(),
-                    txids.clone(),
-                  )
-                  .await;
-                  log!(
-                    INFO,
-                    "notified subscriber: {:?} with block_height: {:?} block_hash: {:?}",
-                    subscriber.to_text(),
-                    height,
-                    block_hash
-                  );
+      let (height, index_prev_blockhash) = crate::index::next_block();
+      match crate::index::mem_get_index_progress() {
+        Some((progress_height, progress)) if progress_height == height => {
+          let block_hash = progress.block_hash;
+          let block = match PENDING_BLOCK.with_borrow_mut(|pending| pending.take()) {
+            Some((pending_hash, block)) if pending_hash == block_hash => Ok(block),
+            _ => crate::rpc::get_block(block_hash).await,
+          };
+          match block {
+            Ok(block) => index_and_notify(&chain, height, block_hash, block, Some(progress)).await,
+            Err(e) => {
+              log!(
+                CRITICAL,
+                "failed to get_block: {:?} error: {:?}",
+                block_hash,
+                e
+              );
+            }
+          }
+        }
+        _ => match crate::bitcoin_api::get_block_hash(&chain, height).await {
+          Ok(Some(block_hash)) => match crate::rpc::get_block(block_hash).await {
+            Ok(block) => {
+              match Reorg::detect_reorg(
+                &chain,
+                index_prev_blockhash,
+                block.header.prev_blockhash,
+                height,
+              )
+              .await
+              {
+                Ok(()) => {
+                  match crate::index::headers::validate_header(&chain, height, &block.header) {
+                    Ok(()) => index_and_notify(&chain, height, block_hash, block, None).await,
+                    Err(e) => {
+                      log!(
+                        CRITICAL,
+                        "invalid block header {:?} at height {}: {}",
+                        block_hash,
+                        height,
+                        e
+                      );
+                    }
+                  }
                 }
+                Err(e) => match e {
+                  reorg::Error::Recoverable { height, depth } => {
+                    let rolled_back = Reorg::handle_reorg(height, depth);
+                    notify_reorg(rolled_back);
+                  }
+                  reorg::Error::Checkpoint { height, checkpoint } => {
+                    let rolled_back = Reorg::rollback_to_checkpoint(height, checkpoint);
+                    notify_reorg(rolled_back);
+                  }
+                  reorg::Error::InvalidFork { .. } => {
+                    log!(CRITICAL, "{}", e);
+                  }
+                  reorg::Error::Unrecoverable => {
+                    log!(
+                      CRITICAL,
+                      "unrecoverable reorg detected at height {}",
+                      height
+                    );
+                    return;
+                  }
+                },
               }
-              Err(e) => match e {
-                reorg::Error::Recoverable { height, depth } => {
-                  Reorg::handle_reorg(height, depth);
-                }
-                reorg::Error::Unrecoverable => {
-                  log!(
-                    CRITICAL,
-                    "unrecoverable reorg detected at height {}",
-                    height
-                  );
-                  return;
-                }
-              },
             }
-          }
+            Err(e) => {
+              log!(
+                CRITICAL,
+                "failed to get_block: {:?} error: {:?}",
+                block_hash,
+                e
+              );
+            }
+          },
+          Ok(None) => {}
           Err(e) => {
-            log!(
-              CRITICAL,
-              "failed to get_block: {:?} error: {:?}",
-              block_hash,
-              e
-            );
+            let message = format!("failed to get_block_hash at height {}: {:?}", height, e);
+            let is_new_message = CRITICAL.with_borrow(|sink| {
+              sink.iter().last().map_or(true, |entry| {
+                log!(INFO, "last_message: {:?}", entry.message);
+                entry.message != message
+              })
+            });
+
+            if is_new_message {
+              log!(CRITICAL, "{}", message);
+            }
           }
         },
-        Ok(None) => {}
-        Err(e) => {
-          let message = format!("failed to get_block_hash at height {}: {:?}", height, e);
-          let is_new_message = CRITICAL.with_borrow(|sink| {
-            sink.iter().last().map_or(true, |entry| {
-              log!(INFO, "last_message: {:?}", entry.message);
-              entry.message != message
-            })
-          });
-
-          if is_new_message {
-            log!(CRITICAL, "{}", message);
-          }
-        }
       }
       if is_shutting_down() {
         log!(
@@ -122,7 +148,7 @@ pub fn update_index(network: BitcoinNetwork, subscribers: Vec<Principal>) -> Res
           height
         );
       } else {
-        let _ = update_index(network, subscribers);
+        let _ = update_index(chain);
       }
     });
   });
@@ -130,7 +156,293 @@ pub fn update_index(network: BitcoinNetwork, subscribers: Vec<Principal>) -> Res
   Ok(())
 }
 
-async fn index_block(height: u32, block: BlockData) -> Result<()> {
+async fn index_and_notify(
+  chain: &ChainParams,
+  height: u32,
+  block_hash: BlockHash,
+  block: BlockData,
+  progress: Option<IndexProgress>,
+) {
+  let txids: Vec<String> = block
+    .txdata
+    .iter()
+    .map(|(_, txid)| txid.to_string())
+    .collect();
+  match index_block(chain, height, block_hash, block, progress).await {
+    Ok(true) => {}
+    Ok(false) => {
+      log!(
+        INFO,
+        "instruction budget reached at height {}, resuming in the next tick",
+        height
+      );
+      return;
+    }
+    Err(e) => {
+      log!(
+        CRITICAL,
+        "failed to index_block at height {}: {:?}",
+        height,
+        e
+      );
+      crate::index::mem_remove_index_progress(height);
+      PENDING_BLOCK.with_borrow_mut(|pending| *pending = None);
+      return;
+    }
+  }
+  Reorg::prune_change_record(height);
+  let recipients = outbox::recipients();
+  if recipients.is_empty() {
+    return;
+  }
+  outbox::sync(&recipients);
+  let config = crate::index::mem_get_config();
+
+  // subscribers with a filter get the matching events, if any
+  let (filtered, unfiltered): (Vec<Principal>, Vec<Principal>) = config
+    .subscribers
+    .iter()
+    .copied()
+    .partition(|subscriber| subscriptions::get(subscriber).is_some());
+  for subscriber in filtered {
+    let Some(subscription) = subscriptions::get(&subscriber) else {
+      continue;
+    };
+    if let Some(rune_block) =
+      crate::notifier::rune_block(height, Default::default(), Some(&subscription.filter()))
+        .filter(|rune_block| !rune_block.transactions.is_empty())
+    {
+      outbox::push(vec![subscriber], Notification::RuneBlock(rune_block));
+    }
+  }
+
+  // the others get every block, in their own or the configured version
+  let (v1, v2): (Vec<Principal>, Vec<Principal>) = unfiltered.into_iter().partition(|subscriber| {
+    subscriptions::settings(subscriber)
+      .notification_version
+      .unwrap_or_else(|| config.notification_version())
+      == NotificationVersion::V1
+  });
+  // webhooks get the events they chose, rune blocks only with rune transactions
+  let rune_webhooks = webhooks::recipients(Some(WebhookEvent::RuneBlock));
+  let rune_block = if v2.is_empty() && rune_webhooks.is_empty() {
+    None
+  } else {
+    crate::notifier::rune_block(height, Default::default(), None)
+  };
+  let mut new_block = v1;
+  new_block.extend(webhooks::recipients(Some(WebhookEvent::NewBlock)));
+  match rune_block {
+    Some(rune_block) => {
+      let mut recipients = v2;
+      if !rune_block.transactions.is_empty() {
+        recipients.extend(rune_webhooks);
+      }
+      if !recipients.is_empty() {
+        outbox::push(recipients, Notification::RuneBlock(rune_block));
+      }
+    }
+    None => new_block.extend(v2),
+  }
+  if !new_block.is_empty() {
+    outbox::push(
+      new_block,
+      Notification::NewBlock(NewBlockRequest {
+        block_height: height,
+        block_hash: block_hash.to_string(),
+        tx_ids: txids,
+      }),
+    );
+  }
+  outbox::schedule();
+}
+
+/// Tells subscribers about a rolled back reorg. Blocks are indexed in later
+/// ticks, so the replacement blocks are always notified after this.
+fn notify_reorg(rolled_back: RolledBack) {
+  let mut recipients = crate::index::mem_get_config().subscribers;
+  recipients.extend(webhooks::recipients(Some(WebhookEvent::Reorg)));
+  if recipients.is_empty() {
+    return;
+  }
+  outbox::sync(&outbox::recipients());
+  for reorg in rolled_back.notifications() {
+    outbox::push(recipients.clone(), Notification::Reorg(reorg));
+  }
+  outbox::schedule();
+}
+
+/// Indexes the block, returning `false` if the instruction budget ran out and
+/// the remaining transactions will be indexed from the saved progress.
+async fn index_block(
+  chain: &ChainParams,
+  height: u32,
+  block_hash: BlockHash,
+  block: BlockData,
+  progress: Option<IndexProgress>,
+) -> Result<bool> {
+  let mut rune_updater = match progress {
+    Some(progress) => {
+      log!(
+        INFO,
+        "Resuming block {} at transaction {}/{}…",
+        height,
+        progress.next_tx,
+        block.txdata.len()
+      );
+
+      RuneUpdater {
+        block_time: block.header.time,
+        burned: progress
+          .burned
+          .into_iter()
+          .map(|(id, amount)| (id, Lot(amount)))
+          .collect(),
+        height,
+        minimum: chain.minimum_at_height(height),
+        runes: progress.runes,
+        change_record: progress.change_record,
+        next_tx: progress.next_tx,
+        selection: crate::index::mem_get_config().rune_selection(),
+        staging: progress.staging,
+      }
+    }
+    None => start_block(chain, height, &block),
+  };
+
+  if let Some(shards) = crate::index::mem_get_config().shards() {
+    let spent = block
+      .txdata
+      .iter()
+      .filter(|(tx, _)| !tx.is_coinbase())
+      .flat_map(|(tx, _)| tx.input.iter().map(|input| input.previous_output))
+      .collect::<Vec<OutPoint>>();
+    let outputs = crate::index::shard::prepare_block(&shards, height, &spent).await?;
+    rune_updater.staging.set_remote_outpoints(outputs);
+  }
+
+  while let Some((tx, txid)) = block.txdata.get(rune_updater.next_tx.into_usize()) {
+    if ic_cdk::api::instruction_counter() > INSTRUCTIONS_PER_MESSAGE {
+      crate::index::mem_insert_index_progress(
+        height,
+        IndexProgress {
+          block_hash,
+          next_tx: rune_updater.next_tx,
+          runes: rune_updater.runes,
+          burned: rune_updater
+            .burned
+            .iter()
+            .map(|(id, amount)| (*id, amount.n()))
+            .collect(),
+          change_record: rune_updater.change_record,
+          staging: rune_updater.staging,
+        },
+      );
+      PENDING_BLOCK.with_borrow_mut(|pending| *pending = Some((block_hash, block)));
+      return Ok(false);
+    }
+
+    rune_updater
+      .index_runes(rune_updater.next_tx, tx, *txid)
+      .await?;
+    rune_updater.next_tx += 1;
+  }
+
+  let mut staging = rune_updater.update()?;
+
+  let taproot_outpoints = block
+    .txdata
+    .iter()
+    .flat_map(|(tx, txid)| {
+      tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, tx_out)| tx_out.script_pubkey.is_p2tr())
+        .map(move |(vout, _)| OutPoint {
+          txid: *txid,
+          vout: vout.try_into().unwrap(),
+        })
+    })
+    .collect::<Vec<OutPoint>>();
+  staging.set_taproot_outpoints(taproot_outpoints);
+  staging.set_block_header(block.header);
+  if crate::index::mem_get_config().tx_inclusion_proofs() {
+    staging.set_tx_proofs(
+      block.txdata.len().try_into().unwrap(),
+      tx_proofs(block_hash, &block),
+    );
+  }
+
+  if let Some(shards) = crate::index::mem_get_config().shards() {
+    crate::index::shard::apply_block(&shards, height, block_hash, staging.outpoints()).await?;
+  }
+
+  // nothing has been written for this block until here, so an error above
+  // leaves the index untouched and the height can be retried from scratch
+  staging.commit(height);
+  crate::index::mem_remove_index_progress(height);
+
+  prune_taproot_outpoints(height);
+
+  crate::index::storage::record_block(height);
+  crate::index::archive::schedule();
+
+  Ok(true)
+}
+
+/// Drops a partly indexed block and its fetched copy, which came from the
+/// abandoned chain of a reorg and would otherwise be resumed once indexing
+/// gets back to its height.
+pub(crate) fn discard_progress() {
+  crate::index::mem_clear_index_progress();
+  PENDING_BLOCK.with_borrow_mut(|pending| *pending = None);
+}
+
+/// Drops the cached P2TR outputs of the blocks beyond the cache depth.
+fn prune_taproot_outpoints(height: u32) {
+  let depth = crate::index::mem_get_config().taproot_cache_depth();
+  if height >= depth {
+    crate::index::mem_prune_taproot_outpoints(height - depth);
+  }
+}
+
+/// Builds the inclusion proofs of the block's runestone transactions.
+fn tx_proofs(block_hash: BlockHash, block: &BlockData) -> Vec<(Txid, TxProof)> {
+  let indices = block
+    .txdata
+    .iter()
+    .enumerate()
+    .filter(|(_, (tx, _))| Runestone::decipher(tx).is_some())
+    .map(|(index, _)| index.try_into().unwrap())
+    .collect::<Vec<u32>>();
+
+  if indices.is_empty() {
+    return Vec::new();
+  }
+
+  let txids = block
+    .txdata
+    .iter()
+    .map(|(_, txid)| *txid)
+    .collect::<Vec<Txid>>();
+
+  crate::index::proof::merkle_branches(&txids, &indices)
+    .into_iter()
+    .zip(indices)
+    .map(|(branch, index)| {
+      (
+        txids[index.into_usize()],
+        TxProof {
+          block_hash,
+          index,
+          branch,
+        },
+      )
+    })
+    .collect()
+}
+
+fn start_block(chain: &ChainParams, height: u32, block: &BlockData) -> RuneUpdater {
   log!(
     INFO,
     "Block {} at {} with {} transactions…",
@@ -145,7 +457,7 @@ async fn index_block(height: u32, block: BlockData) -> Result<()> {
   if height % 10 == 0 {
     log!(
       INFO,
-      "Index statistics at height {}: latest_block: {:?}, reserved_runes: {}, runes: {}, rune_to_rune_id: {}, rune_entry: {}, transaction_id_to_rune: {}, outpoint_to_rune_balances: {}, outpoint_to_height: {}",
+      "Index statistics at height {}: latest_block: {:?}, reserved_runes: {}, runes: {}, rune_to_rune_id: {}, rune_entry: {}, transaction_id_to_rune: {}, outpoints: {}, taproot_outpoints: {}",
       height,
       crate::index::mem_latest_block(),
       reserved_runes,
@@ -153,33 +465,87 @@ async fn index_block(height: u32, block: BlockData) -> Result<()> {
       crate::index::mem_length_rune_to_rune_id(),
       crate::index::mem_length_rune_id_to_rune_entry(),
       crate::index::mem_length_transaction_id_to_rune(),
-      crate::index::mem_length_outpoint_to_rune_balances(),
-      crate::index::mem_length_outpoint_to_height(),
+      crate::index::mem_length_outpoints(),
+      crate::index::mem_length_taproot_outpoints(),
     );
   }
 
   // init statistic runes/reserved_runes for new height
-  crate::index::mem_insert_statistic_runes(height, runes);
-  crate::index::mem_insert_statistic_reserved_runes(height, reserved_runes);
+  let mut staging = Staging::default();
+  staging.set_statistic_runes(runes);
+  staging.set_statistic_reserved_runes(reserved_runes);
 
-  let mut rune_updater = RuneUpdater {
+  RuneUpdater {
     block_time: block.header.time,
     burned: HashMap::new(),
     height,
-    minimum: Rune::minimum_at_height(bitcoin::Network::Bitcoin, Height(height)),
+    minimum: chain.minimum_at_height(height),
     runes,
     change_record: ChangeRecord::new(),
-  };
+    next_tx: 0,
+    selection: crate::index::mem_get_config().rune_selection(),
+    staging,
+  }
+}
 
-  for (i, (tx, txid)) in block.txdata.iter().enumerate() {
-    rune_updater
-      .index_runes(u32::try_from(i).unwrap(), tx, *txid)
-      .await?;
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::index::entry::Entry;
+  use bitcoin::hashes::Hash;
+
+  fn outpoint(height: u32) -> OutPoint {
+    OutPoint {
+      txid: Txid::all_zeros(),
+      vout: height,
+    }
   }
 
-  rune_updater.update()?;
+  fn cached_height(height: u32) -> Option<u32> {
+    crate::index::mem_get_taproot_outpoint_height(outpoint(height).store())
+  }
 
-  crate::index::mem_insert_block_header(height, block.header.store());
+  #[test]
+  fn taproot_outputs_are_cached_for_the_configured_depth() {
+    crate::index::mem_set_config(crate::config::Config {
+      taproot_cache_depth: Some(2),
+      ..Default::default()
+    })
+    .unwrap();
+    for height in 1..=3 {
+      crate::index::mem_insert_taproot_outpoints(height, vec![outpoint(height)]);
+      prune_taproot_outpoints(height);
+    }
+    assert_eq!(cached_height(1), None);
+    assert_eq!(cached_height(2), Some(2));
+    assert_eq!(cached_height(3), Some(3));
 
-  Ok(())
+    crate::index::mem_insert_taproot_outpoints(4, vec![outpoint(4)]);
+    prune_taproot_outpoints(4);
+    assert_eq!(cached_height(2), None);
+    assert_eq!(cached_height(4), Some(4));
+    assert_eq!(crate::index::mem_length_taproot_outpoints(), 2);
+  }
+
+  #[test]
+  fn discarded_progress_drops_the_pending_block() {
+    let block = bitcoin::constants::genesis_block(bitcoin::Network::Bitcoin);
+    let block_hash = block.block_hash();
+    crate::index::mem_insert_index_progress(
+      1,
+      IndexProgress {
+        block_hash,
+        next_tx: 1,
+        runes: 0,
+        burned: Vec::new(),
+        change_record: ChangeRecord::new(),
+        staging: Default::default(),
+      },
+    );
+    PENDING_BLOCK.with_borrow_mut(|pending| *pending = Some((block_hash, block.into())));
+
+    discard_progress();
+    assert!(crate::index::mem_get_index_progress().is_none());
+    assert!(PENDING_BLOCK.with_borrow(|pending| pending.is_none()));
+  }
 }
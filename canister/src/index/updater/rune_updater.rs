@@ -1,6 +1,8 @@
 use super::*;
-use crate::index::entry::RuneBalance;
+use crate::index::entry::{RuneBalance, RuneTransaction};
+use crate::index::staging::Staging;
 use crate::into_usize::IntoUsize;
+use crate::selection::RuneSelection;
 
 pub(super) struct RuneUpdater {
   pub(super) block_time: u32,
@@ -9,6 +11,10 @@ pub(super) struct RuneUpdater {
   pub(super) minimum: Rune,
   pub(super) runes: u64,
   pub(super) change_record: ChangeRecord,
+  pub(super) next_tx: u32,
+  /// Runes whose balances are indexed, all of them when `None`.
+  pub(super) selection: Option<RuneSelection>,
+  pub(super) staging: Staging,
 }
 
 impl RuneUpdater {
@@ -24,10 +30,13 @@ impl RuneUpdater {
 
     let mut allocated: Vec<HashMap<RuneId, Lot>> = vec![HashMap::new(); tx.output.len()];
 
+    let mut transaction = RuneTransaction::new(txid);
+
     if let Some(artifact) = &artifact {
       if let Some(id) = artifact.mint() {
         if let Some(amount) = self.mint(id)? {
           *unallocated.entry(id).or_default() += amount;
+          transaction.minted = Some((id, amount.n()));
 
           // log!(
           //   INFO,
@@ -123,6 +132,7 @@ impl RuneUpdater {
 
       if let Some((id, rune)) = etched {
         self.create_rune_entry(txid, artifact, id, rune)?;
+        transaction.etched = Some((id, rune));
       }
     }
 
@@ -167,17 +177,34 @@ impl RuneUpdater {
       }
     }
 
-    // update outpoint balances
-    for (vout, balances) in allocated.into_iter().enumerate() {
-      if balances.is_empty() {
-        continue;
-      }
-
-      // increment burned balances
+    // increment burned balances
+    for (vout, balances) in allocated.iter().enumerate() {
       if tx.output[vout].script_pubkey.is_op_return() {
-        for (id, balance) in &balances {
+        for (id, balance) in balances {
           *burned.entry(*id).or_default() += *balance;
         }
+      }
+    }
+
+    // increment entries with burned runes
+    for (id, amount) in burned {
+      *self.burned.entry(id).or_default() += amount;
+      transaction.burned.push((id, amount.n()));
+
+      log!(
+        INFO,
+        "Rune burned: block_height: {}, txid: {:?}, rune_id: {:?}, amount: {:?}",
+        self.height,
+        txid,
+        id,
+        amount.n()
+      );
+    }
+
+    // update outpoint balances, once every burn is accounted for, since
+    // balances of runes that are not indexed are dropped here
+    for (vout, balances) in allocated.into_iter().enumerate() {
+      if balances.is_empty() || tx.output[vout].script_pubkey.is_op_return() {
         continue;
       }
 
@@ -194,51 +221,72 @@ impl RuneUpdater {
       let mut rune_balances = RuneBalances { balances: vec![] };
 
       for (id, balance) in balances {
+        if !self.is_indexed(id) {
+          continue;
+        }
+
         rune_balances.balances.push(RuneBalance {
           rune_id: id,
           balance: balance.n(),
         });
+        transaction.allocated.push((outpoint.vout, id, balance.n()));
 
         // log!(INFO, "Rune transferred: outpoint: {:?}, block_height: {}, txid: {:?}, rune_id: {:?}, amount: {:?}", outpoint, self.height, txid, id, balance.n());
       }
-      crate::index::mem_insert_outpoint_to_rune_balances(outpoint.store(), rune_balances);
-      crate::index::mem_insert_outpoint_to_height(outpoint.store(), self.height);
+
+      if rune_balances.balances.is_empty() {
+        continue;
+      }
+
+      transaction
+        .scripts
+        .push((outpoint.vout, tx.output[vout].script_pubkey.clone()));
+
+      self
+        .staging
+        .insert_outpoint(outpoint, rune_balances, self.height);
 
       self.change_record.added_outpoints.push(outpoint);
     }
 
-    // increment entries with burned runes
-    for (id, amount) in burned {
-      *self.burned.entry(id).or_default() += amount;
-
-      log!(
-        INFO,
-        "Rune burned: block_height: {}, txid: {:?}, rune_id: {:?}, amount: {:?}",
-        self.height,
-        txid,
-        id,
-        amount.n()
-      );
+    if !transaction.is_empty() {
+      // balances come out of hash maps, sorted for a stable notification order
+      transaction.allocated.sort();
+      transaction.burned.sort();
+      self.staging.push_transaction(transaction);
     }
 
     Ok(())
   }
 
-  pub(super) fn update(mut self) -> Result {
+  /// Whether balances of `id` are kept. Balances of other runes are dropped
+  /// when outputs are stored, after the transaction's burns are counted, so
+  /// only their later transfers and burns are never seen.
+  fn is_indexed(&self, id: RuneId) -> bool {
+    let Some(selection) = &self.selection else {
+      return true;
+    };
+    self
+      .staging
+      .get_rune_id_to_rune_entry(id)
+      .is_some_and(|entry| selection.contains(id, entry.spaced_rune.rune))
+  }
+
+  pub(super) fn update(mut self) -> Result<Staging> {
     for (rune_id, burned) in self.burned {
-      let mut entry = crate::index::mem_get_rune_id_to_rune_entry(rune_id.store()).unwrap();
+      let mut entry = self.staging.get_rune_id_to_rune_entry(rune_id).unwrap();
 
       if !self.change_record.burned.contains_key(&rune_id) {
         self.change_record.burned.insert(rune_id, entry.burned);
       }
 
       entry.burned = entry.burned.checked_add(burned.n()).unwrap();
-      crate::index::mem_insert_rune_id_to_rune_entry(rune_id.store(), entry);
+      self.staging.insert_rune_id_to_rune_entry(rune_id, entry);
     }
 
-    crate::index::mem_insert_change_record(self.height, self.change_record);
+    self.staging.set_change_record(self.change_record);
 
-    Ok(())
+    Ok(self.staging)
   }
 
   fn create_rune_entry(
@@ -248,13 +296,12 @@ impl RuneUpdater {
     id: RuneId,
     rune: Rune,
   ) -> Result {
-    crate::index::mem_insert_rune_to_rune_id(rune.store(), id.store());
-    crate::index::mem_insert_transaction_id_to_rune(txid.store(), rune.store());
+    self.staging.insert_rune(rune, id, txid);
 
     let number = self.runes;
     self.runes += 1;
 
-    crate::index::mem_insert_statistic_runes(self.height, self.runes);
+    self.staging.set_statistic_runes(self.runes);
 
     let entry = match artifact {
       Artifact::Cenotaph(_) => RuneEntry {
@@ -302,7 +349,7 @@ impl RuneUpdater {
       }
     };
 
-    crate::index::mem_insert_rune_id_to_rune_entry(id.store(), entry);
+    self.staging.insert_rune_id_to_rune_entry(id, entry);
 
     self.change_record.added_runes.push((rune, id, txid));
 
@@ -337,16 +384,18 @@ impl RuneUpdater {
     let rune = if let Some(rune) = rune {
       if rune < self.minimum
         || rune.is_reserved()
-        || crate::index::mem_get_rune_to_rune_id(rune.store()).is_some()
+        || self.staging.contains_rune(rune)
         || !self.tx_commits_to_rune(tx, rune).await?
       {
         return Ok(None);
       }
       rune
     } else {
-      let reserved_runes = crate::index::mem_statistic_reserved_runes();
+      let reserved_runes = self.staging.statistic_reserved_runes();
 
-      crate::index::mem_insert_statistic_reserved_runes(self.height, reserved_runes + 1);
+      self
+        .staging
+        .set_statistic_reserved_runes(reserved_runes + 1);
 
       Rune::reserved(self.height.into(), tx_index)
     };
@@ -361,7 +410,7 @@ impl RuneUpdater {
   }
 
   fn mint(&mut self, id: RuneId) -> Result<Option<Lot>> {
-    let Some(mut rune_entry) = crate::index::mem_get_rune_id_to_rune_entry(id.store()) else {
+    let Some(mut rune_entry) = self.staging.get_rune_id_to_rune_entry(id) else {
       return Ok(None);
     };
 
@@ -375,7 +424,7 @@ impl RuneUpdater {
 
     rune_entry.mints += 1;
 
-    crate::index::mem_insert_rune_id_to_rune_entry(id.store(), rune_entry);
+    self.staging.insert_rune_id_to_rune_entry(id, rune_entry);
 
     Ok(Some(Lot(amount)))
   }
@@ -405,27 +454,33 @@ impl RuneUpdater {
           continue;
         }
 
-        let tx_info =
-          crate::rpc::get_raw_transaction_info(&input.previous_output.txid, None).await?;
-
-        let taproot = tx_info.vout[input.previous_output.vout.into_usize()]
-          .script_pub_key
-          .script()?
-          .is_p2tr();
-
-        if !taproot {
-          continue;
-        }
+        // recent taproot outputs are indexed locally, so only fall back to
+        // the rpc (which requires `txindex`) for commitments outside the window
+        let commit_tx_height =
+          match crate::index::mem_get_taproot_outpoint_height(input.previous_output.store()) {
+            Some(height) => height,
+            None => {
+              let tx_info =
+                crate::rpc::get_raw_transaction_info(&input.previous_output.txid, None).await?;
+
+              let taproot = tx_info.vout[input.previous_output.vout.into_usize()]
+                .script_pub_key
+                .script()?
+                .is_p2tr();
+
+              if !taproot {
+                continue;
+              }
 
-        let commit_tx_height = crate::rpc::get_block_header_info(&tx_info.blockhash.unwrap())
-          .await?
-          .height;
+              crate::rpc::get_block_header_info(&tx_info.blockhash.unwrap())
+                .await?
+                .height
+                .try_into()
+                .unwrap()
+            }
+          };
 
-        let confirmations = self
-          .height
-          .checked_sub(commit_tx_height.try_into().unwrap())
-          .unwrap()
-          + 1;
+        let confirmations = self.height.checked_sub(commit_tx_height).unwrap() + 1;
 
         if confirmations >= Runestone::COMMIT_CONFIRMATIONS as u32 {
           return Ok(true);
@@ -442,19 +497,10 @@ impl RuneUpdater {
 
     // increment unallocated runes with the runes in tx inputs
     for input in &tx.input {
-      if let Some(rune_balances) =
-        crate::index::mem_remove_outpoint_to_rune_balances(input.previous_output.store())
-      {
-        for rune_balance in rune_balances.balances.clone() {
+      if let Some((rune_balances, height)) = self.staging.remove_outpoint(input.previous_output)? {
+        for rune_balance in rune_balances.balances.iter() {
           *unallocated.entry(rune_balance.rune_id).or_default() += rune_balance.balance;
         }
-        let height = crate::index::mem_remove_outpoint_to_height(input.previous_output.store())
-          .ok_or_else(|| {
-            anyhow!(
-              "Outpoint not found in outpoint_to_height: {:?}",
-              input.previous_output
-            )
-          })?;
 
         self
           .change_record
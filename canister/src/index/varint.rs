@@ -0,0 +1,64 @@
+//! LEB128 varints, as used by ord to pack rune balances.
+
+pub(crate) fn encode_to_vec(mut n: u128, v: &mut Vec<u8>) {
+  while n >> 7 > 0 {
+    v.push(n.to_le_bytes()[0] | 0b1000_0000);
+    n >>= 7;
+  }
+  v.push(n.to_le_bytes()[0]);
+}
+
+/// Decodes the varint at the start of `buffer`, returning it and the number
+/// of bytes it takes up, or `None` if it is unterminated or overflows.
+pub(crate) fn decode(buffer: &[u8]) -> Option<(u128, usize)> {
+  let mut n = 0u128;
+  for (i, &byte) in buffer.iter().enumerate() {
+    if i > 18 {
+      return None;
+    }
+    let value = u128::from(byte) & 0b0111_1111;
+    if i == 18 && value & 0b0111_1100 != 0 {
+      return None;
+    }
+    n |= value << (7 * i);
+    if byte & 0b1000_0000 == 0 {
+      return Some((n, i + 1));
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    for n in [0, 1, 127, 128, 840_000, u64::MAX.into(), u128::MAX] {
+      let mut v = Vec::new();
+      encode_to_vec(n, &mut v);
+      assert_eq!(decode(&v), Some((n, v.len())));
+    }
+  }
+
+  #[test]
+  fn lengths() {
+    let len = |n| {
+      let mut v = Vec::new();
+      encode_to_vec(n, &mut v);
+      v.len()
+    };
+    assert_eq!(len(127), 1);
+    assert_eq!(len(128), 2);
+    assert_eq!(len(u128::MAX), 19);
+  }
+
+  #[test]
+  fn invalid() {
+    assert_eq!(decode(&[0x80]), None);
+    assert_eq!(decode(&[0xff; 19]), None);
+    let mut overflow = vec![0xff; 18];
+    overflow.push(0x04);
+    assert_eq!(decode(&overflow), None);
+  }
+}
@@ -1,10 +1,12 @@
 mod bitcoin_api;
+pub mod chain;
 pub mod config;
 pub mod index;
 mod into_usize;
 pub mod logs;
-mod notifier;
+pub mod notifier;
 pub mod rpc;
+pub mod selection;
 
 use anyhow::Error;
 use chrono::{DateTime, TimeZone, Utc};
@@ -1,12 +1,26 @@
-use bitcoin::{OutPoint, Txid};
+use bitcoin::{block::Header, BlockHash, OutPoint, TxMerkleNode, Txid};
 use candid::{candid_method, Principal};
 use ic_canister_log::log;
 use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
 use ic_cdk_macros::{init, post_upgrade, query, update};
 use runes_indexer::config::RunesIndexerArgs;
-use runes_indexer::index::entry::Entry;
+use runes_indexer::index::access::{self, AuditRecord, Role};
+use runes_indexer::index::entry::{Entry, EventRecord, RuneBalances};
+use runes_indexer::index::events::MAX_EVENTS_PER_REQUEST;
+use runes_indexer::index::export::{ExportChunk, StateMap};
+use runes_indexer::index::icrc3::Value;
+use runes_indexer::index::shard::{ShardBlock, ShardOutput};
+use runes_indexer::index::subscriptions::{self, Subscriber, SubscriberSettings, Subscription};
+use runes_indexer::index::webhooks::{Webhook, WebhookInfo};
 use runes_indexer::logs::{CRITICAL, INFO, WARNING};
-use runes_indexer_interface::{Error, GetEtchingResult, RuneBalance, RuneEntry, Terms};
+use runes_indexer_interface::{
+  ArchiveInfo, ArchivedBlocks, ArchivedEvents, ArchivedEventsCallback, BlockWithId, Error, Event,
+  EventKind, GetArchivesArgs, GetBlocksArgs, GetBlocksCallback, GetBlocksResult, GetEtchingResult,
+  GetEventsRequest, GetEventsResult, GetNotificationsResult, Icrc3ArchiveInfo,
+  Icrc3DataCertificate, Icrc3Value, MapStats, RuneBalance, RuneBlock, RuneBlockCursor, RuneEntry,
+  RuneEvent, StorageStats, SubscriberStats, SubscriptionFilter, SupportedBlockType, Terms,
+  TxInclusionProof,
+};
 use std::str::FromStr;
 
 #[query]
@@ -16,6 +30,35 @@ pub fn get_latest_block() -> (u32, String) {
   (height, hash.to_string())
 }
 
+#[query]
+#[candid_method(query)]
+pub fn get_block_header(height: u32) -> Option<String> {
+  runes_indexer::index::mem_get_block_header(height)
+    .map(|header| bitcoin::consensus::encode::serialize_hex(&header))
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_block_hash(height: u32) -> Option<String> {
+  runes_indexer::index::mem_block_hash(height).map(|hash| hash.to_string())
+}
+
+#[query]
+#[candid_method(query)]
+pub fn is_in_best_chain(block_hash: String) -> bool {
+  BlockHash::from_str(&block_hash)
+    .ok()
+    .and_then(runes_indexer::index::mem_get_block_height)
+    .is_some()
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_block_confirmations(block_hash: String) -> Option<u32> {
+  let block_hash = BlockHash::from_str(&block_hash).ok()?;
+  runes_indexer::index::mem_block_confirmations(block_hash)
+}
+
 #[query]
 #[candid_method(query)]
 pub fn get_etching(txid: String) -> Option<GetEtchingResult> {
@@ -89,55 +132,456 @@ pub fn get_rune_by_id(str_rune_id: String) -> Option<RuneEntry> {
 
 #[query]
 #[candid_method(query)]
-pub fn get_rune_balances_for_outputs(
+pub fn get_storage_stats() -> Result<StorageStats, String> {
+  access::check(&ic_cdk::api::caller(), Role::Metrics)?;
+
+  let stats = runes_indexer::index::storage::storage_stats();
+  Ok(StorageStats {
+    maps: stats
+      .regions
+      .into_iter()
+      .map(|region| MapStats {
+        memory_id: region.memory_id,
+        name: region.name.to_string(),
+        entries: region.entries,
+        stable_bytes: region.stable_bytes,
+      })
+      .collect(),
+    heap_bytes: stats.heap_bytes,
+    stable_bytes: stats.stable_bytes,
+    growth_per_block: stats.growth_per_block,
+    sampled_blocks: stats.sampled_blocks,
+  })
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_events(request: GetEventsRequest) -> GetEventsResult {
+  let log_length = runes_indexer::index::events::log_length();
+  let first_index = runes_indexer::index::events::first_index();
+  let end = request
+    .start
+    .saturating_add(request.length.min(MAX_EVENTS_PER_REQUEST))
+    .min(log_length);
+
+  GetEventsResult {
+    log_length,
+    events: runes_indexer::index::events::get(request.start.max(first_index), end)
+      .into_iter()
+      .map(|(index, record)| into_event(index, record))
+      .collect(),
+    archived_events: runes_indexer::index::archive::archived_ranges(
+      request.start,
+      end.min(first_index),
+    )
+    .into_iter()
+    .map(|(canister_id, start, length)| ArchivedEvents {
+      start,
+      length,
+      callback: ArchivedEventsCallback::new(canister_id, "get_archived_events".to_string()),
+    })
+    .collect(),
+  }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_archived_events(request: GetEventsRequest) -> Vec<Event> {
+  let end = request
+    .start
+    .saturating_add(request.length.min(MAX_EVENTS_PER_REQUEST));
+  runes_indexer::index::events::get(request.start, end)
+    .into_iter()
+    .map(|(index, record)| into_event(index, record))
+    .collect()
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_archives() -> Vec<ArchiveInfo> {
+  runes_indexer::index::archive::archives()
+    .into_iter()
+    .map(|archive| ArchiveInfo {
+      canister_id: archive.canister_id,
+      start: archive.start,
+      length: archive.length,
+    })
+    .collect()
+}
+
+fn into_event(index: u64, record: EventRecord) -> Event {
+  use runes_indexer::index::entry::RuneEvent as Stored;
+
+  Event {
+    index,
+    height: record.height,
+    event: match record.event {
+      Stored::Etched {
+        rune_id,
+        rune,
+        txid,
+      } => RuneEvent::Etched {
+        rune_id: rune_id.to_string(),
+        rune: rune.to_string(),
+        txid: txid.to_string(),
+      },
+      Stored::Minted {
+        rune_id,
+        mints,
+        amount,
+      } => RuneEvent::Minted {
+        rune_id: rune_id.to_string(),
+        mints,
+        amount,
+      },
+      Stored::Allocated {
+        outpoint,
+        rune_id,
+        amount,
+      } => RuneEvent::Allocated {
+        outpoint: outpoint.to_string(),
+        rune_id: rune_id.to_string(),
+        amount,
+      },
+      Stored::Spent {
+        outpoint,
+        rune_id,
+        amount,
+      } => RuneEvent::Spent {
+        outpoint: outpoint.to_string(),
+        rune_id: rune_id.to_string(),
+        amount,
+      },
+      Stored::Burned { rune_id, amount } => RuneEvent::Burned {
+        rune_id: rune_id.to_string(),
+        amount,
+      },
+      Stored::Reverted { start, length } => RuneEvent::Reverted { start, length },
+    },
+  }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+  let log_length = runes_indexer::index::events::log_length();
+  let first_index = runes_indexer::index::events::first_index();
+  let mut remaining = MAX_EVENTS_PER_REQUEST;
+  let mut blocks = Vec::new();
+  let mut archived_blocks: Vec<ArchivedBlocks> = Vec::new();
+
+  for arg in args {
+    let start = u64::try_from(arg.start.0).unwrap_or(u64::MAX);
+    let length = u64::try_from(arg.length.0).unwrap_or(u64::MAX);
+    let end = start.saturating_add(length.min(remaining)).min(log_length);
+
+    for (index, record) in runes_indexer::index::events::get(start.max(first_index), end) {
+      blocks.push(BlockWithId {
+        id: index.into(),
+        block: into_value(runes_indexer::index::icrc3::block(&record)),
+      });
+      remaining -= 1;
+    }
+
+    for (canister_id, start, length) in
+      runes_indexer::index::archive::archived_ranges(start, end.min(first_index))
+    {
+      let args = GetBlocksArgs {
+        start: start.into(),
+        length: length.into(),
+      };
+      match archived_blocks
+        .iter_mut()
+        .find(|archived| archived.callback.0.principal == canister_id)
+      {
+        Some(archived) => archived.args.push(args),
+        None => archived_blocks.push(ArchivedBlocks {
+          args: vec![args],
+          callback: GetBlocksCallback::new(canister_id, "icrc3_get_blocks".to_string()),
+        }),
+      }
+    }
+  }
+
+  GetBlocksResult {
+    log_length: log_length.into(),
+    blocks,
+    archived_blocks,
+  }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn icrc3_get_tip_certificate() -> Option<Icrc3DataCertificate> {
+  let (certificate, hash_tree) = runes_indexer::index::icrc3::tip_certificate()?;
+  Some(Icrc3DataCertificate {
+    certificate,
+    hash_tree,
+  })
+}
+
+#[query]
+#[candid_method(query)]
+pub fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<Icrc3ArchiveInfo> {
+  runes_indexer::index::archive::archives()
+    .into_iter()
+    .skip_while(|archive| args.from.is_some_and(|from| archive.canister_id != from))
+    .skip(usize::from(args.from.is_some()))
+    .filter(|archive| archive.length > 0)
+    .map(|archive| Icrc3ArchiveInfo {
+      canister_id: archive.canister_id,
+      start: archive.start.into(),
+      end: (archive.start + archive.length - 1).into(),
+    })
+    .collect()
+}
+
+#[query]
+#[candid_method(query)]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+  runes_indexer::index::icrc3::BLOCK_TYPES
+    .iter()
+    .map(|block_type| SupportedBlockType {
+      block_type: block_type.to_string(),
+      url: "https://github.com/octopus-network/runes-indexer#icrc3_get_blocks".to_string(),
+    })
+    .collect()
+}
+
+fn into_value(value: Value) -> Icrc3Value {
+  match value {
+    Value::Blob(bytes) => Icrc3Value::Blob(bytes),
+    Value::Text(text) => Icrc3Value::Text(text),
+    Value::Nat(n) => Icrc3Value::Nat(n.into()),
+    Value::Map(entries) => Icrc3Value::Map(
+      entries
+        .into_iter()
+        .map(|(key, value)| (key, into_value(value)))
+        .collect(),
+    ),
+  }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_rune_transactions(
+  block_height: u32,
+  start: Option<RuneBlockCursor>,
+) -> Option<RuneBlock> {
+  // subscribers continue their filtered blocks with the same filter
+  let filter = subscriptions::get(&ic_cdk::api::caller()).map(|subscription| subscription.filter());
+  runes_indexer::notifier::rune_block(block_height, start.unwrap_or_default(), filter.as_ref())
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_notifications(since_seq: u64) -> GetNotificationsResult {
+  let notifications = runes_indexer::index::outbox::get(&ic_cdk::api::caller(), since_seq);
+  let next_seq = notifications
+    .last()
+    .map(|notification| notification.seq + 1)
+    .unwrap_or_else(|| since_seq.max(runes_indexer::index::outbox::oldest_seq()))
+    .min(runes_indexer::index::outbox::next_seq());
+  GetNotificationsResult {
+    oldest_seq: runes_indexer::index::outbox::oldest_seq(),
+    notifications,
+    next_seq,
+  }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_subscriber_stats() -> Result<Vec<SubscriberStats>, String> {
+  access::check(&ic_cdk::api::caller(), Role::Metrics)?;
+
+  const NANOS_PER_MS: u64 = 1_000_000;
+  Ok(
+    runes_indexer::index::outbox::subscriber_stats()
+      .into_iter()
+      .map(|s| SubscriberStats {
+        subscriber: s.subscriber,
+        pending: s.pending,
+        consecutive_failures: s.cursor.failures,
+        retry_at: Some(s.cursor.retry_at).filter(|_| s.cursor.failures > 0),
+        in_flight: s.in_flight,
+        delivered: s.stats.delivered,
+        failed: s.stats.failed,
+        timed_out: s.stats.timed_out,
+        last_latency_ms: s.stats.last_latency.map(|ns| ns / NANOS_PER_MS),
+        average_latency_ms: s.stats.average_latency().map(|ns| ns / NANOS_PER_MS),
+        max_latency_ms: s.stats.max_latency / NANOS_PER_MS,
+        last_error: s.stats.last_error,
+      })
+      .collect(),
+  )
+}
+
+#[update]
+#[candid_method(update)]
+pub fn subscribe(filter: SubscriptionFilter, callback: Option<String>) -> Result<(), String> {
+  runes_indexer::index::subscriptions::subscribe(
+    ic_cdk::api::caller(),
+    Subscription {
+      rune_ids: filter.rune_ids,
+      script_pubkeys: filter.script_pubkeys,
+      events: filter
+        .events
+        .into_iter()
+        .map(|kind| match kind {
+          EventKind::Etch => subscriptions::EventKind::Etch,
+          EventKind::Mint => subscriptions::EventKind::Mint,
+          EventKind::Transfer => subscriptions::EventKind::Transfer,
+          EventKind::Burn => subscriptions::EventKind::Burn,
+        })
+        .collect(),
+      callback,
+    },
+  )
+}
+
+#[update]
+#[candid_method(update)]
+pub fn unsubscribe() -> Result<(), String> {
+  runes_indexer::index::subscriptions::unsubscribe(ic_cdk::api::caller())
+}
+
+#[update]
+#[candid_method(update)]
+pub fn ack_notifications(seq: u64) -> Result<(), String> {
+  runes_indexer::index::outbox::ack(ic_cdk::api::caller(), seq)
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_tx_inclusion_proof(txid: String) -> Option<TxInclusionProof> {
+  let txid = Txid::from_str(&txid).ok()?;
+  let tx_proof = runes_indexer::index::mem_get_tx_proof(txid)?;
+  let height = runes_indexer::index::mem_get_block_height(tx_proof.block_hash)?;
+  let header = runes_indexer::index::mem_get_block_header(height)?;
+  let cur_height = runes_indexer::index::mem_latest_block_height()?;
+
+  Some(TxInclusionProof {
+    txid: txid.to_string(),
+    block_hash: tx_proof.block_hash.to_string(),
+    block_height: height,
+    block_header: bitcoin::consensus::encode::serialize_hex(&header),
+    tx_index: tx_proof.index,
+    merkle_branch: tx_proof
+      .branch
+      .iter()
+      .map(|node| node.to_string())
+      .collect(),
+    confirmations: cur_height - height + 1,
+  })
+}
+
+#[query]
+#[candid_method(query)]
+pub fn verify_tx_inclusion_proof(proof: TxInclusionProof) -> bool {
+  let (Ok(txid), Ok(block_hash), Ok(header)) = (
+    Txid::from_str(&proof.txid),
+    BlockHash::from_str(&proof.block_hash),
+    bitcoin::consensus::encode::deserialize_hex::<Header>(&proof.block_header),
+  ) else {
+    return false;
+  };
+  let Ok(branch) = proof
+    .merkle_branch
+    .iter()
+    .map(|node| TxMerkleNode::from_str(node))
+    .collect::<Result<Vec<TxMerkleNode>, _>>()
+  else {
+    return false;
+  };
+
+  header.block_hash() == block_hash
+    && runes_indexer::index::verify_tx_inclusion(
+      txid,
+      &header,
+      proof.block_height,
+      proof.tx_index,
+      &branch,
+    )
+}
+
+/// Reads the balances from the shards in a sharded deployment.
+#[query(composite = true)]
+#[candid_method(composite_query)]
+pub async fn get_rune_balances_for_outputs(
   outpoints: Vec<String>,
 ) -> Result<Vec<Option<Vec<RuneBalance>>>, Error> {
+  let parsed = parse_outpoints(&outpoints)?;
+  let outputs = runes_indexer::index::shard::get_outputs(
+    &parsed.iter().flatten().copied().collect::<Vec<OutPoint>>(),
+  )
+  .await
+  .map_err(|e| Error::ShardUnavailable(e.to_string()))?;
+  rune_balances_for_outputs(&outpoints, parsed, outputs)
+}
+
+fn parse_outpoints(outpoints: &[String]) -> Result<Vec<Option<OutPoint>>, Error> {
   if outpoints.len() > 64 {
     return Err(Error::MaxOutpointsExceeded);
   }
 
+  Ok(
+    outpoints
+      .iter()
+      .map(|str_outpoint| {
+        OutPoint::from_str(str_outpoint)
+          .inspect_err(|e| log!(WARNING, "Failed to parse outpoint {}: {}", str_outpoint, e))
+          .ok()
+      })
+      .collect(),
+  )
+}
+
+/// Balances of the parsed outpoints, given the outputs of the valid ones.
+fn rune_balances_for_outputs(
+  outpoints: &[String],
+  parsed: Vec<Option<OutPoint>>,
+  outputs: Vec<Option<(RuneBalances, u32)>>,
+) -> Result<Vec<Option<Vec<RuneBalance>>>, Error> {
+  let selection = runes_indexer::index::mem_get_config().rune_selection();
   let cur_height = runes_indexer::index::mem_latest_block_height().expect("No block height found");
+  let mut outputs = outputs.into_iter();
   let mut piles = Vec::new();
 
-  for str_outpoint in outpoints {
-    let outpoint = match OutPoint::from_str(&str_outpoint) {
-      Ok(o) => o,
-      Err(e) => {
-        log!(WARNING, "Failed to parse outpoint {}: {}", str_outpoint, e);
-        piles.push(None);
-        continue;
-      }
-    };
-    let k = OutPoint::store(outpoint);
-    if let Some(rune_balances) = runes_indexer::index::mem_get_outpoint_to_rune_balances(k) {
-      if let Some(height) = runes_indexer::index::mem_get_outpoint_to_height(k) {
-        let confirmations = cur_height - height + 1;
-
-        let mut outpoint_balances = Vec::new();
-        for rune_balance in rune_balances.balances.iter() {
-          let rune_entry =
-            runes_indexer::index::mem_get_rune_id_to_rune_entry(rune_balance.rune_id.store());
-          if let Some(rune_entry) = rune_entry {
-            outpoint_balances.push(RuneBalance {
-              confirmations,
-              rune_id: rune_balance.rune_id.to_string(),
-              amount: rune_balance.balance,
-              divisibility: rune_entry.divisibility,
-              symbol: rune_entry.symbol.map(|c| c.to_string()),
-            });
-          } else {
-            log!(
-              CRITICAL,
-              "Rune not found for rune_id {}",
-              rune_balance.rune_id.to_string()
-            );
+  for (str_outpoint, outpoint) in outpoints.iter().zip(parsed) {
+    if outpoint.is_none() {
+      piles.push(None);
+      continue;
+    }
+    if let Some((rune_balances, height)) = outputs.next().flatten() {
+      let confirmations = cur_height - height + 1;
+
+      let mut outpoint_balances = Vec::new();
+      for rune_balance in rune_balances.balances.iter() {
+        let rune_entry =
+          runes_indexer::index::mem_get_rune_id_to_rune_entry(rune_balance.rune_id.store());
+        if let Some(rune_entry) = rune_entry {
+          // only outputs loaded from a snapshot hold runes outside the selection
+          if selection.as_ref().is_some_and(|selection| {
+            !selection.contains(rune_balance.rune_id, rune_entry.spaced_rune.rune)
+          }) {
+            return Err(Error::RuneNotIndexed);
           }
+          outpoint_balances.push(RuneBalance {
+            confirmations,
+            rune_id: rune_balance.rune_id.to_string(),
+            amount: rune_balance.balance,
+            divisibility: rune_entry.divisibility,
+            symbol: rune_entry.symbol.map(|c| c.to_string()),
+          });
+        } else {
+          log!(
+            CRITICAL,
+            "Rune not found for rune_id {}",
+            rune_balance.rune_id.to_string()
+          );
         }
-        piles.push(Some(outpoint_balances));
-      } else {
-        log!(WARNING, "Height not found for outpoint {}", str_outpoint);
-        piles.push(None);
       }
+      piles.push(Some(outpoint_balances));
     } else {
       log!(
         WARNING,
@@ -151,6 +595,103 @@ pub fn get_rune_balances_for_outputs(
   Ok(piles)
 }
 
+/// Reads the balance from the shard holding the output in a sharded
+/// deployment.
+#[query(composite = true)]
+#[candid_method(composite_query)]
+pub async fn get_rune_balance_for_output(
+  outpoint: String,
+  rune_id: String,
+) -> Result<Option<RuneBalance>, Error> {
+  let Some((outpoint, rune_id, rune_entry)) = parse_output_rune(&outpoint, &rune_id)? else {
+    return Ok(None);
+  };
+  let output = runes_indexer::index::shard::get_outputs(&[outpoint])
+    .await
+    .map_err(|e| Error::ShardUnavailable(e.to_string()))?
+    .remove(0);
+  Ok(rune_balance_for_output(rune_id, rune_entry, output))
+}
+
+fn parse_output_rune(
+  outpoint: &str,
+  rune_id: &str,
+) -> Result<
+  Option<(
+    OutPoint,
+    ordinals::RuneId,
+    runes_indexer::index::entry::RuneEntry,
+  )>,
+  Error,
+> {
+  let (Ok(outpoint), Ok(rune_id)) = (
+    OutPoint::from_str(outpoint),
+    ordinals::RuneId::from_str(rune_id),
+  ) else {
+    return Ok(None);
+  };
+  let Some(rune_entry) = runes_indexer::index::mem_get_rune_id_to_rune_entry(rune_id.store())
+  else {
+    return Ok(None);
+  };
+
+  if let Some(selection) = runes_indexer::index::mem_get_config().rune_selection() {
+    if !selection.contains(rune_id, rune_entry.spaced_rune.rune) {
+      return Err(Error::RuneNotIndexed);
+    }
+  }
+
+  Ok(Some((outpoint, rune_id, rune_entry)))
+}
+
+fn rune_balance_for_output(
+  rune_id: ordinals::RuneId,
+  rune_entry: runes_indexer::index::entry::RuneEntry,
+  output: Option<(RuneBalances, u32)>,
+) -> Option<RuneBalance> {
+  let cur_height = runes_indexer::index::mem_latest_block_height().expect("No block height found");
+  let (rune_balances, height) = output?;
+
+  rune_balances
+    .balances
+    .iter()
+    .find(|rune_balance| rune_balance.rune_id == rune_id)
+    .map(|rune_balance| RuneBalance {
+      confirmations: cur_height - height + 1,
+      rune_id: rune_id.to_string(),
+      amount: rune_balance.balance,
+      divisibility: rune_entry.divisibility,
+      symbol: rune_entry.symbol.map(|c| c.to_string()),
+    })
+}
+
+#[query(hidden = true)]
+pub fn get_shard_outputs(outpoints: Vec<String>) -> Vec<Option<ShardOutput>> {
+  runes_indexer::index::shard::get_shard_outputs(outpoints)
+    .unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
+}
+
+#[update(hidden = true)]
+pub fn prepare_shard_block(
+  height: u32,
+  outpoints: Vec<String>,
+) -> Result<Vec<Option<ShardOutput>>, String> {
+  if Some(ic_cdk::api::caller()) != runes_indexer::index::mem_get_config().coordinator() {
+    return Err("Not authorized".to_string());
+  }
+
+  runes_indexer::index::shard::prepare_shard_block(height, outpoints).map_err(|e| e.to_string())
+}
+
+#[update(hidden = true)]
+pub fn apply_shard_block(block: ShardBlock) -> Result<(), String> {
+  if Some(ic_cdk::api::caller()) != runes_indexer::index::mem_get_config().coordinator() {
+    return Err("Not authorized".to_string());
+  }
+
+  runes_indexer::index::shard::apply_shard_block(block).map_err(|e| e.to_string())
+}
+
 #[query(hidden = true)]
 pub fn rpc_transform(args: TransformArgs) -> HttpResponse {
   let headers = args
@@ -166,26 +707,47 @@ pub fn rpc_transform(args: TransformArgs) -> HttpResponse {
   }
 }
 
+/// Keeps only the status of webhook responses, so that replicas agree on
+/// them whatever the receiver answers.
+#[query(hidden = true)]
+pub fn webhook_transform(args: TransformArgs) -> HttpResponse {
+  HttpResponse {
+    status: args.response.status,
+    body: vec![],
+    headers: vec![],
+  }
+}
+
 #[update(hidden = true)]
 pub fn start() -> Result<(), String> {
-  let caller = ic_cdk::api::caller();
-  if !ic_cdk::api::is_controller(&caller) {
-    return Err("Not authorized".to_string());
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Operator,
+    "start",
+    String::new(),
+  )?;
+
+  if runes_indexer::index::snapshot::is_pending() {
+    return Err("Snapshot not loaded".to_string());
   }
 
-  runes_indexer::index::cancel_shutdown();
   let config = runes_indexer::index::mem_get_config();
-  let _ = runes_indexer::index::updater::update_index(config.network, config.subscribers);
+  if config.coordinator().is_some() {
+    return Err("Shards are updated by their coordinator".to_string());
+  }
+  if config.archive_of.is_some() {
+    return Err("Archives don't index".to_string());
+  }
+
+  runes_indexer::index::cancel_shutdown();
+  let _ = runes_indexer::index::updater::update_index(config.chain_params());
 
   Ok(())
 }
 
 #[update(hidden = true)]
 pub fn stop() -> Result<(), String> {
-  let caller = ic_cdk::api::caller();
-  if !ic_cdk::api::is_controller(&caller) {
-    return Err("Not authorized".to_string());
-  }
+  access::authorize(ic_cdk::api::caller(), Role::Operator, "stop", String::new())?;
 
   runes_indexer::index::shut_down();
   log!(INFO, "Waiting for index thread to finish...");
@@ -193,12 +755,40 @@ pub fn stop() -> Result<(), String> {
   Ok(())
 }
 
+#[update(hidden = true)]
+pub fn load_snapshot_chunk(index: u32, chunk: Vec<u8>) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Admin,
+    "load_snapshot_chunk",
+    format!("{}, {} bytes", index, chunk.len()),
+  )?;
+
+  runes_indexer::index::snapshot::load_chunk(index, chunk).map_err(|e| e.to_string())
+}
+
+#[update(hidden = true)]
+pub async fn finalize_snapshot() -> Result<u32, String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Admin,
+    "finalize_snapshot",
+    String::new(),
+  )?;
+
+  runes_indexer::index::snapshot::finalize()
+    .await
+    .map_err(|e| e.to_string())
+}
+
 #[update(hidden = true)]
 pub fn set_bitcoin_rpc_url(url: String) -> Result<(), String> {
-  let caller = ic_cdk::api::caller();
-  if !ic_cdk::api::is_controller(&caller) {
-    return Err("Not authorized".to_string());
-  }
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::ConfigEditor,
+    "set_bitcoin_rpc_url",
+    runes_indexer::index::webhooks::host(&url).to_string(),
+  )?;
   let mut config = runes_indexer::index::mem_get_config();
   config.bitcoin_rpc_url = url;
   runes_indexer::index::mem_set_config(config).unwrap();
@@ -206,11 +796,169 @@ pub fn set_bitcoin_rpc_url(url: String) -> Result<(), String> {
   Ok(())
 }
 
+#[update(hidden = true)]
+pub fn set_archive_wasm(wasm: Vec<u8>) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Admin,
+    "set_archive_wasm",
+    format!("{} bytes", wasm.len()),
+  )?;
+
+  runes_indexer::index::archive::set_archive_wasm(wasm);
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn append_archived_events(start: u64, events: Vec<Vec<u8>>) -> Result<(), String> {
+  if Some(ic_cdk::api::caller()) != runes_indexer::index::mem_get_config().archive_of {
+    return Err("Not authorized".to_string());
+  }
+
+  runes_indexer::index::archive::append_archived_events(start, events);
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn add_subscriber(canister_id: Principal, settings: SubscriberSettings) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::ConfigEditor,
+    "add_subscriber",
+    format!("{}, {:?}", canister_id, settings),
+  )?;
+
+  runes_indexer::index::subscriptions::add_subscriber(canister_id, settings)?;
+  // deliver what a resumed subscriber missed
+  runes_indexer::index::outbox::schedule();
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn remove_subscriber(canister_id: Principal) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::ConfigEditor,
+    "remove_subscriber",
+    canister_id.to_string(),
+  )?;
+
+  runes_indexer::index::subscriptions::remove_subscriber(canister_id)
+}
+
+#[update(hidden = true)]
+pub fn add_webhook(webhook: Webhook) -> Result<u64, String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::ConfigEditor,
+    "add_webhook",
+    format!(
+      "{}, {}",
+      runes_indexer::index::webhooks::next_id(),
+      runes_indexer::index::webhooks::host(&webhook.url)
+    ),
+  )?;
+
+  runes_indexer::index::webhooks::add_webhook(webhook)
+}
+
+#[update(hidden = true)]
+pub fn remove_webhook(id: u64) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::ConfigEditor,
+    "remove_webhook",
+    id.to_string(),
+  )?;
+
+  runes_indexer::index::webhooks::remove_webhook(id)
+}
+
+#[query(hidden = true)]
+pub fn list_webhooks() -> Result<Vec<WebhookInfo>, String> {
+  access::check(&ic_cdk::api::caller(), Role::Metrics)?;
+
+  Ok(runes_indexer::index::webhooks::list_webhooks())
+}
+
+#[query(hidden = true)]
+pub fn list_subscribers() -> Result<Vec<Subscriber>, String> {
+  access::check(&ic_cdk::api::caller(), Role::Metrics)?;
+
+  Ok(runes_indexer::index::subscriptions::list_subscribers())
+}
+
+#[update(hidden = true)]
+pub fn retry_notifications() -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Operator,
+    "retry_notifications",
+    String::new(),
+  )?;
+
+  runes_indexer::index::outbox::retry_now();
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn grant_role(principal: Principal, role: Role) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Admin,
+    "grant_role",
+    format!("{}, {:?}", principal, role),
+  )?;
+
+  access::grant_role(principal, role);
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn revoke_role(principal: Principal, role: Role) -> Result<(), String> {
+  access::authorize(
+    ic_cdk::api::caller(),
+    Role::Admin,
+    "revoke_role",
+    format!("{}, {:?}", principal, role),
+  )?;
+
+  access::revoke_role(principal, role)
+}
+
+#[query(hidden = true)]
+pub fn list_roles() -> Result<Vec<(Principal, Vec<Role>)>, String> {
+  access::check(&ic_cdk::api::caller(), Role::Admin)?;
+
+  Ok(access::list_roles())
+}
+
+#[query(hidden = true)]
+pub fn get_audit_log(start: u64, length: u64) -> Result<Vec<AuditRecord>, String> {
+  access::check(&ic_cdk::api::caller(), Role::Admin)?;
+
+  Ok(access::get_audit_log(start, length))
+}
+
 #[query(hidden = true)]
 pub fn get_subscribers() -> Vec<Principal> {
+  access::check(&ic_cdk::api::caller(), Role::Metrics).unwrap_or_else(|e| ic_cdk::trap(&e));
+
   runes_indexer::index::mem_get_config().subscribers
 }
 
+#[query(hidden = true)]
+pub fn export_state(map: StateMap, cursor: Option<Vec<u8>>) -> Result<ExportChunk, String> {
+  access::check(&ic_cdk::api::caller(), Role::Admin)?;
+
+  runes_indexer::index::export::export_state(map, cursor)
+}
+
 #[query(hidden = true)]
 fn http_request(
   req: ic_canisters_http_types::HttpRequest,
@@ -230,7 +978,12 @@ fn http_request(
 fn init(runes_indexer_args: RunesIndexerArgs) {
   match runes_indexer_args {
     RunesIndexerArgs::Init(config) => {
+      if let Err(e) = config.validate() {
+        ic_cdk::trap(&e);
+      }
       runes_indexer::index::mem_set_config(config).unwrap();
+      runes_indexer::index::mem_set_schema_version(runes_indexer::index::schema::SCHEMA_VERSION)
+        .unwrap();
     }
     RunesIndexerArgs::Upgrade(_) => ic_cdk::trap(
       "Cannot initialize the canister with an Upgrade argument. Please provide an Init argument.",
@@ -240,6 +993,16 @@ fn init(runes_indexer_args: RunesIndexerArgs) {
 
 #[post_upgrade]
 fn post_upgrade(runes_indexer_args: Option<RunesIndexerArgs>) {
+  runes_indexer::index::mem_index_block_hashes();
+  runes_indexer::index::mem_index_chain_work();
+  runes_indexer::index::schema::migrate();
+  runes_indexer::index::icrc3::certify_tip();
+  // resume deliveries interrupted by the upgrade
+  ic_cdk_timers::set_timer(
+    std::time::Duration::ZERO,
+    runes_indexer::index::outbox::schedule,
+  );
+
   match runes_indexer_args {
     Some(RunesIndexerArgs::Upgrade(Some(upgrade_args))) => {
       let mut config = runes_indexer::index::mem_get_config();
@@ -248,8 +1011,48 @@ fn post_upgrade(runes_indexer_args: Option<RunesIndexerArgs>) {
       }
       if let Some(subscribers) = upgrade_args.subscribers {
         config.subscribers = subscribers;
+        runes_indexer::index::subscriptions::retain(&config.subscribers);
         log!(INFO, "subscribers updated: {:?}", config.subscribers);
       }
+      if let Some(max_reorg_depth) = upgrade_args.max_reorg_depth {
+        config.max_reorg_depth = Some(max_reorg_depth);
+        log!(INFO, "max_reorg_depth updated: {}", max_reorg_depth);
+      }
+      if let Some(checkpoint_interval) = upgrade_args.checkpoint_interval {
+        config.checkpoint_interval = Some(checkpoint_interval);
+        log!(INFO, "checkpoint_interval updated: {}", checkpoint_interval);
+      }
+      if let Some(tx_inclusion_proofs) = upgrade_args.tx_inclusion_proofs {
+        config.tx_inclusion_proofs = Some(tx_inclusion_proofs);
+        log!(INFO, "tx_inclusion_proofs updated: {}", tx_inclusion_proofs);
+      }
+      if let Some(bytes) = upgrade_args.stable_memory_warning_bytes {
+        config.stable_memory_warning_bytes = Some(bytes);
+        log!(INFO, "stable_memory_warning_bytes updated: {}", bytes);
+      }
+      if let Some(bytes) = upgrade_args.heap_memory_warning_bytes {
+        config.heap_memory_warning_bytes = Some(bytes);
+        log!(INFO, "heap_memory_warning_bytes updated: {}", bytes);
+      }
+      if let Some(event_log) = upgrade_args.event_log {
+        log!(INFO, "event_log updated: {:?}", event_log);
+        config.event_log = Some(event_log);
+      }
+      if let Some(version) = upgrade_args.notification_version {
+        config.notification_version = Some(version);
+        log!(INFO, "notification_version updated: {:?}", version);
+      }
+      if let Some(secs) = upgrade_args.notification_timeout_secs {
+        config.notification_timeout_secs = Some(secs);
+        log!(INFO, "notification_timeout_secs updated: {}", secs);
+      }
+      if let Some(depth) = upgrade_args.taproot_cache_depth {
+        config.taproot_cache_depth = Some(depth);
+        log!(INFO, "taproot_cache_depth updated: {}", depth);
+      }
+      if let Err(e) = config.validate() {
+        ic_cdk::trap(&e);
+      }
       runes_indexer::index::mem_set_config(config).unwrap();
     }
     None | Some(RunesIndexerArgs::Upgrade(None)) => {}
@@ -257,6 +1060,13 @@ fn post_upgrade(runes_indexer_args: Option<RunesIndexerArgs>) {
       "Cannot upgrade the canister with an Init argument. Please provide an Upgrade argument.",
     ),
   }
+
+  if runes_indexer::index::mem_get_config()
+    .checkpoint_interval()
+    .is_none()
+  {
+    runes_indexer::index::mem_clear_checkpoints();
+  }
 }
 
 ic_cdk::export_candid!();
@@ -1,30 +1,286 @@
-use crate::logs::CRITICAL;
-use candid::{self, CandidType, Principal};
-use ic_canister_log::log;
+use crate::index::entry::RuneTransactions;
+use crate::index::subscriptions::Filter;
+use candid::{self, Principal};
+use runes_indexer_interface::{
+  Notification, RuneAllocation, RuneAmount, RuneBlock, RuneBlockCursor, RuneEtching,
+  RuneTransaction,
+};
 
-type BlockHeight = u32;
+/// Encoded size of the transactions in a `RuneBlock`, leaving ample room
+/// below the 2MiB limit of inter-canister messages.
+const MAX_RUNE_BLOCK_BYTES: usize = 1_000_000;
 
-#[derive(CandidType)]
-pub struct NewBlockRequest {
-  pub block_height: BlockHeight,
-  pub block_hash: String,
-  pub tx_ids: Vec<String>,
+/// Calls the subscriber's callback for the notification. Blocks are sent to
+/// `callback` when the subscriber chose one.
+pub async fn notify(
+  canister_id: Principal,
+  notification: &Notification,
+  callback: Option<&str>,
+) -> Result<(), String> {
+  let result = match notification {
+    Notification::NewBlock(request) => {
+      let method = callback.unwrap_or("new_block_detected");
+      ic_cdk::call::<_, ()>(canister_id, method, (request,)).await
+    }
+    Notification::RuneBlock(block) => {
+      let method = callback.unwrap_or("new_block_detected_v2");
+      ic_cdk::call::<_, ()>(canister_id, method, (block,)).await
+    }
+    Notification::Reorg(reorg) => {
+      ic_cdk::call::<_, ()>(canister_id, "reorg_detected", (reorg,)).await
+    }
+  };
+  result.map_err(|(code, message)| format!("{:?} {}", code, message))
 }
 
-pub async fn notify_new_block(
-  canister_id: Principal,
+/// Rune transactions of the block at `block_height` from `start` on, as many
+/// as fit in a message, with only the events matching `filter` if given.
+/// Blocks are kept for `max_reorg_depth` blocks, older ones return `None`.
+pub fn rune_block(
   block_height: u32,
-  block_hash: String,
-  tx_ids: Vec<String>,
-) -> crate::Result<()> {
-  let req = NewBlockRequest {
+  start: RuneBlockCursor,
+  filter: Option<&Filter>,
+) -> Option<RuneBlock> {
+  let latest = crate::index::mem_latest_block_height()?;
+  if block_height > latest
+    || block_height + crate::index::mem_get_config().max_reorg_depth() <= latest
+  {
+    return None;
+  }
+  let block_hash = crate::index::mem_block_hash(block_height)?;
+  let (transactions, next) = page(
+    crate::index::mem_get_rune_transactions(block_height).unwrap_or_default(),
+    start,
+    filter,
+  );
+
+  Some(RuneBlock {
     block_height,
-    block_hash,
-    tx_ids,
-  };
+    block_hash: block_hash.to_string(),
+    transactions,
+    next,
+  })
+}
+
+/// Transactions from `start` on, with `next` indexing the full block so that
+/// filtered blocks can be continued too. Allocations are counted after the
+/// filter.
+fn page(
+  transactions: RuneTransactions,
+  start: RuneBlockCursor,
+  filter: Option<&Filter>,
+) -> (Vec<RuneTransaction>, Option<RuneBlockCursor>) {
+  let mut page = Vec::new();
+  let mut bytes = 0;
+  for (index, transaction) in (0..)
+    .zip(transactions.transactions)
+    .skip(start.transaction as usize)
+  {
+    let transaction = match filter {
+      Some(filter) => match filter.apply(&transaction) {
+        Some(transaction) => transaction,
+        None => continue,
+      },
+      None => transaction,
+    };
+    let mut transaction = into_rune_transaction(transaction);
+    let mut allocation = 0;
+    if index == start.transaction {
+      allocation = start.allocation;
+      skip_allocations(&mut transaction, allocation);
+    }
+
+    let size = encoded_size(&transaction);
+    if bytes + size <= MAX_RUNE_BLOCK_BYTES {
+      bytes += size;
+      page.push(transaction);
+      continue;
+    }
+    if !page.is_empty() {
+      return (
+        page,
+        Some(RuneBlockCursor {
+          transaction: index,
+          allocation,
+        }),
+      );
+    }
+
+    // a transaction too large for a message on its own is split between its
+    // allocations, taking at least one so that every page makes progress
+    let allocated = std::mem::take(&mut transaction.allocated);
+    let total = allocated.len();
+    bytes = encoded_size(&transaction);
+    for rune_allocation in allocated {
+      let size = encoded_size(&rune_allocation);
+      if !transaction.allocated.is_empty() && bytes + size > MAX_RUNE_BLOCK_BYTES {
+        break;
+      }
+      bytes += size;
+      transaction.allocated.push(rune_allocation);
+    }
+    let sent = transaction.allocated.len();
+    page.push(transaction);
+    if sent < total {
+      return (
+        page,
+        Some(RuneBlockCursor {
+          transaction: index,
+          allocation: allocation + u32::try_from(sent).unwrap(),
+        }),
+      );
+    }
+  }
+  (page, None)
+}
+
+/// Leaves out the first `allocation` allocations of a transaction that was
+/// split, along with what the first part already carried.
+fn skip_allocations(transaction: &mut RuneTransaction, allocation: u32) {
+  if allocation == 0 {
+    return;
+  }
+  let allocation = (allocation as usize).min(transaction.allocated.len());
+  transaction.allocated.drain(..allocation);
+  transaction.etched = None;
+  transaction.minted = None;
+  transaction.burned.clear();
+}
+
+/// Candid size of the value on its own, slightly more than its share of a
+/// message.
+fn encoded_size<T: candid::CandidType>(value: &T) -> usize {
+  candid::encode_one(value).unwrap().len()
+}
+
+fn into_rune_transaction(transaction: crate::index::entry::RuneTransaction) -> RuneTransaction {
+  let txid = transaction.txid;
+  let scripts = transaction.scripts;
+  RuneTransaction {
+    txid: txid.to_string(),
+    etched: transaction.etched.map(|(rune_id, rune)| RuneEtching {
+      rune_id: rune_id.to_string(),
+      rune: rune.to_string(),
+    }),
+    minted: transaction.minted.map(|(rune_id, amount)| RuneAmount {
+      rune_id: rune_id.to_string(),
+      amount,
+    }),
+    allocated: transaction
+      .allocated
+      .into_iter()
+      .map(|(vout, rune_id, amount)| RuneAllocation {
+        outpoint: bitcoin::OutPoint { txid, vout }.to_string(),
+        rune_id: rune_id.to_string(),
+        amount,
+        script_pubkey: scripts
+          .iter()
+          .find(|(output, _)| *output == vout)
+          .map(|(_, script)| script.to_hex_string())
+          .unwrap_or_default(),
+      })
+      .collect(),
+    burned: transaction
+      .burned
+      .into_iter()
+      .map(|(rune_id, amount)| RuneAmount {
+        rune_id: rune_id.to_string(),
+        amount,
+      })
+      .collect(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bitcoin::hashes::Hash;
+  use bitcoin::Txid;
+  use ordinals::RuneId;
+
+  fn transaction(outputs: u32) -> crate::index::entry::RuneTransaction {
+    let mut transaction = crate::index::entry::RuneTransaction::new(Txid::all_zeros());
+    transaction.allocated = (0..outputs)
+      .map(|vout| (vout, RuneId { block: 1, tx: 0 }, u128::MAX))
+      .collect();
+    transaction
+  }
+
+  #[test]
+  fn large_blocks_are_paged() {
+    let transactions = || RuneTransactions {
+      transactions: (0..4).map(|_| transaction(4_000)).collect(),
+    };
+
+    let (first, next) = page(transactions(), RuneBlockCursor::default(), None);
+    assert!(!first.is_empty() && first.len() < 4);
+    let next = next.unwrap();
+    assert_eq!(next.transaction as usize, first.len());
+    assert_eq!(next.allocation, 0);
+
+    let (rest, next) = page(transactions(), next, None);
+    assert_eq!(first.len() + rest.len(), 4);
+    assert_eq!(next, None);
+  }
+
+  #[test]
+  fn oversized_transactions_are_split() {
+    let transactions = || RuneTransactions {
+      transactions: vec![transaction(20_000), transaction(1)],
+    };
+
+    let mut cursor = RuneBlockCursor::default();
+    let mut allocations = Vec::new();
+    let mut pages = 0;
+    loop {
+      let (page, next) = page(transactions(), cursor, None);
+      assert!(encoded_size(&page) <= MAX_RUNE_BLOCK_BYTES);
+      allocations.extend(
+        page
+          .into_iter()
+          .flat_map(|transaction| transaction.allocated)
+          .map(|allocation| allocation.outpoint),
+      );
+      pages += 1;
+      match next {
+        Some(next) => cursor = next,
+        None => break,
+      }
+    }
+
+    assert!(pages >= 2);
+    let expected = (0..20_000)
+      .chain(0..1)
+      .map(|vout| {
+        bitcoin::OutPoint {
+          txid: Txid::all_zeros(),
+          vout,
+        }
+        .to_string()
+      })
+      .collect::<Vec<String>>();
+    assert_eq!(allocations, expected);
+  }
+
+  #[test]
+  fn filtered_pages_index_the_full_block() {
+    let mut etching = crate::index::entry::RuneTransaction::new(Txid::all_zeros());
+    etching.etched = Some((RuneId { block: 1, tx: 0 }, ordinals::Rune(0)));
+    let filter = crate::index::subscriptions::Subscription {
+      events: vec![crate::index::subscriptions::EventKind::Etch],
+      ..Default::default()
+    }
+    .filter();
 
-  if let Err(e) = ic_cdk::call::<_, ()>(canister_id, "new_block_detected", (req,)).await {
-    log!(CRITICAL, "failed to notify new block: {:?}", e);
+    let (page, next) = page(
+      RuneTransactions {
+        transactions: vec![transaction(1), etching, transaction(1)],
+      },
+      0,
+      Some(&filter),
+    );
+    assert_eq!(page.len(), 1);
+    assert!(page[0].etched.is_some());
+    assert_eq!(next, None);
   }
-  Ok(())
 }
@@ -1,7 +1,7 @@
 use super::Result;
 use crate::logs::{DEBUG, ERROR};
 use anyhow::anyhow;
-use bitcoin::{consensus::encode, Block};
+use bitcoin::{block::Header, consensus::encode, Block};
 use bitcoin::{BlockHash, Txid};
 use bitcoincore_rpc_json::{GetBlockHeaderResult, GetRawTransactionResult};
 use ic_canister_log::log;
@@ -125,7 +125,7 @@ pub(crate) fn estimate_cycles(req_len: u64, rsp_len: u64, n: u64) -> u128 {
   (3_000_000 + 60_000 * n as u128 + 400 * req_len as u128 + 800 * rsp_len as u128) * n as u128
 }
 
-async fn make_single_request(
+pub(crate) async fn make_single_request(
   args: CanisterHttpRequestArgument,
   estimate_cycle: u128,
 ) -> Result<HttpResponse> {
@@ -341,6 +341,42 @@ pub(crate) async fn get_block_header_info(
   .await
 }
 
+async fn inner_get_block_header(
+  url: &str,
+  max_response_bytes: u64,
+  subnet_nodes: u64,
+  hash: &BlockHash,
+) -> Result<Header> {
+  let args = [into_json(hash)?, false.into()];
+  let hex: String = make_rpc(
+    url,
+    "getblockheader",
+    args.to_vec(),
+    max_response_bytes,
+    subnet_nodes,
+  )
+  .await?;
+  Ok(encode::deserialize_hex(&hex)?)
+}
+
+// 160 hex characters
+pub(crate) async fn get_block_header(hash: &BlockHash) -> Result<Header> {
+  let config = crate::index::mem_get_config();
+  let header = inner_get_block_header(
+    &config.bitcoin_rpc_url,
+    512,
+    config.get_subnet_nodes(),
+    hash,
+  )
+  .await?;
+
+  if header.block_hash() != *hash {
+    return Err(anyhow!("wrong block header hash: {}", hash.to_string()));
+  }
+
+  Ok(header)
+}
+
 async fn inner_get_block_hash(
   url: &str,
   max_response_bytes: u64,
@@ -0,0 +1,118 @@
+use crate::config::RuneSelector;
+use ordinals::{Rune, RuneId};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Runes whose balances are indexed, parsed from `Config::indexed_runes`.
+#[derive(Debug, Clone, Default)]
+pub struct RuneSelection {
+  ids: HashSet<RuneId>,
+  patterns: Vec<String>,
+}
+
+impl RuneSelection {
+  pub fn new(selectors: &[RuneSelector]) -> Result<Self, String> {
+    let mut selection = Self::default();
+    for selector in selectors {
+      match selector {
+        RuneSelector::Id(id) => {
+          let id = RuneId::from_str(id).map_err(|e| format!("invalid rune id {id}: {e}"))?;
+          selection.ids.insert(id);
+        }
+        RuneSelector::Name(pattern) => {
+          if pattern.is_empty() || !pattern.chars().all(|c| c.is_ascii_uppercase() || c == '*') {
+            return Err(format!(
+              "invalid rune name pattern {pattern:?}, expected letters A-Z and `*`"
+            ));
+          }
+          selection.patterns.push(pattern.clone());
+        }
+      }
+    }
+    Ok(selection)
+  }
+
+  pub fn contains(&self, id: RuneId, rune: Rune) -> bool {
+    self.ids.contains(&id) || {
+      let name = rune.to_string();
+      self.patterns.iter().any(|pattern| matches(pattern, &name))
+    }
+  }
+}
+
+/// Matches a rune name against a pattern in which `*` stands for any run of
+/// letters, including none.
+fn matches(pattern: &str, name: &str) -> bool {
+  let mut parts = pattern.split('*');
+  let first = parts.next().unwrap_or_default();
+  let Some(mut rest) = name.strip_prefix(first) else {
+    return false;
+  };
+
+  let parts = parts.collect::<Vec<&str>>();
+  let Some((last, middle)) = parts.split_last() else {
+    // no wildcard, the name must equal the pattern
+    return rest.is_empty();
+  };
+
+  for part in middle {
+    match rest.find(part) {
+      Some(i) => rest = &rest[i + part.len()..],
+      None => return false,
+    }
+  }
+
+  rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn patterns() {
+    assert!(matches("DOG", "DOG"));
+    assert!(!matches("DOG", "DOGS"));
+    assert!(matches("DOG*", "DOGGOTOTHEMOON"));
+    assert!(matches("*MOON", "DOGGOTOTHEMOON"));
+    assert!(matches("DOG*THE*MOON", "DOGGOTOTHEMOON"));
+    assert!(matches("*", "A"));
+    assert!(matches("A*A", "AA"));
+    assert!(!matches("A*A", "A"));
+    assert!(!matches("*MOON", "MOONS"));
+    assert!(!matches("DOG*CAT", "DOGGOTOTHEMOON"));
+  }
+
+  #[test]
+  fn selection() {
+    let selection = RuneSelection::new(&[
+      RuneSelector::Id("840000:3".to_string()),
+      RuneSelector::Name("UNCOMMON*".to_string()),
+    ])
+    .unwrap();
+    let rune = |name: &str| Rune::from_str(name).unwrap();
+
+    assert!(selection.contains(
+      RuneId {
+        block: 840_000,
+        tx: 3
+      },
+      rune("DOG")
+    ));
+    assert!(selection.contains(RuneId { block: 1, tx: 0 }, rune("UNCOMMONGOODS")));
+    assert!(!selection.contains(
+      RuneId {
+        block: 840_000,
+        tx: 4
+      },
+      rune("DOG")
+    ));
+  }
+
+  #[test]
+  fn invalid_selectors() {
+    assert!(RuneSelection::new(&[RuneSelector::Id("840000".to_string())]).is_err());
+    assert!(RuneSelection::new(&[RuneSelector::Name("dog".to_string())]).is_err());
+    assert!(RuneSelection::new(&[RuneSelector::Name(String::new())]).is_err());
+  }
+}
@@ -1,4 +1,5 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use serde::Serialize;
 
 #[derive(Debug, CandidType, Deserialize)]
 pub struct RuneBalance {
@@ -41,7 +42,323 @@ pub struct RuneEntry {
   pub turbo: bool,
 }
 
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct TxInclusionProof {
+  pub txid: String,
+  pub block_hash: String,
+  pub block_height: u32,
+  pub block_header: String,
+  pub tx_index: u32,
+  pub merkle_branch: Vec<String>,
+  pub confirmations: u32,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct MapStats {
+  pub memory_id: u8,
+  pub name: String,
+  pub entries: u64,
+  pub stable_bytes: u64,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct StorageStats {
+  pub maps: Vec<MapStats>,
+  pub heap_bytes: u64,
+  pub stable_bytes: u64,
+  /// Average stable memory growth per block over the blocks sampled since the
+  /// last upgrade, `None` until two blocks have been indexed.
+  pub growth_per_block: Option<u64>,
+  pub sampled_blocks: u32,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct GetEventsRequest {
+  pub start: u64,
+  pub length: u64,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub enum RuneEvent {
+  Etched {
+    rune_id: String,
+    rune: String,
+    txid: String,
+  },
+  Minted {
+    rune_id: String,
+    mints: u128,
+    amount: u128,
+  },
+  Allocated {
+    outpoint: String,
+    rune_id: String,
+    amount: u128,
+  },
+  Spent {
+    outpoint: String,
+    rune_id: String,
+    amount: u128,
+  },
+  Burned {
+    rune_id: String,
+    amount: u128,
+  },
+  /// The events in `start..start + length` were undone by a reorg.
+  Reverted {
+    start: u64,
+    length: u64,
+  },
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct Event {
+  pub index: u64,
+  pub height: u32,
+  pub event: RuneEvent,
+}
+
+candid::define_function!(pub ArchivedEventsCallback : (GetEventsRequest) -> (Vec<Event>) query);
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct ArchivedEvents {
+  pub start: u64,
+  pub length: u64,
+  pub callback: ArchivedEventsCallback,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct GetEventsResult {
+  pub log_length: u64,
+  /// Events of the requested range still held by the index.
+  pub events: Vec<Event>,
+  /// Parts of the requested range to fetch from archives.
+  pub archived_events: Vec<ArchivedEvents>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct ArchiveInfo {
+  pub canister_id: Principal,
+  pub start: u64,
+  pub length: u64,
+}
+
+/// ICRC-3 generic value.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum Icrc3Value {
+  Blob(Vec<u8>),
+  Text(String),
+  Nat(Nat),
+  Int(Int),
+  Array(Vec<Icrc3Value>),
+  Map(Vec<(String, Icrc3Value)>),
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+  pub start: Nat,
+  pub length: Nat,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct BlockWithId {
+  pub id: Nat,
+  pub block: Icrc3Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+  pub args: Vec<GetBlocksArgs>,
+  pub callback: GetBlocksCallback,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct GetBlocksResult {
+  pub log_length: Nat,
+  pub blocks: Vec<BlockWithId>,
+  pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct Icrc3DataCertificate {
+  pub certificate: Vec<u8>,
+  /// CBOR encoded hash tree with the labels `last_block_index` and
+  /// `last_block_hash`.
+  pub hash_tree: Vec<u8>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct GetArchivesArgs {
+  /// Last archive already seen, to list the ones after it.
+  pub from: Option<Principal>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct Icrc3ArchiveInfo {
+  pub canister_id: Principal,
+  pub start: Nat,
+  /// Index of the last block in the archive.
+  pub end: Nat,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SupportedBlockType {
+  pub block_type: String,
+  pub url: String,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct RuneEtching {
+  pub rune_id: String,
+  pub rune: String,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct RuneAmount {
+  pub rune_id: String,
+  pub amount: u128,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct RuneAllocation {
+  pub outpoint: String,
+  pub rune_id: String,
+  pub amount: u128,
+  /// Hex-encoded script pubkey of the output.
+  pub script_pubkey: String,
+}
+
+/// Rune changes made by a transaction.
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct RuneTransaction {
+  pub txid: String,
+  pub etched: Option<RuneEtching>,
+  pub minted: Option<RuneAmount>,
+  /// Balances allocated to the transaction's outputs.
+  pub allocated: Vec<RuneAllocation>,
+  pub burned: Vec<RuneAmount>,
+}
+
+/// Rune transactions of a block, sent to subscribers by
+/// `new_block_detected_v2` and returned by `get_rune_transactions`.
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct RuneBlock {
+  pub block_height: u32,
+  pub block_hash: String,
+  pub transactions: Vec<RuneTransaction>,
+  /// Where the transactions left out to keep the message small start, to
+  /// fetch with `get_rune_transactions`.
+  pub next: Option<RuneBlockCursor>,
+}
+
+/// Position in the transactions of a `RuneBlock`. A transaction too large for
+/// one message is split between its allocations, and continues in the next
+/// message with the allocations from `allocation` on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub struct RuneBlockCursor {
+  /// Index of the transaction in the block.
+  pub transaction: u32,
+  /// Index of the first allocation of the transaction left to send.
+  pub allocation: u32,
+}
+
+/// Sent to subscribers by `reorg_detected` once a reorg has been rolled back,
+/// before the blocks replacing the rolled back ones are notified.
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct ReorgNotification {
+  /// Height of the first rolled back block.
+  pub fork_height: u32,
+  /// Number of rolled back blocks.
+  pub depth: u32,
+  /// Hashes of the rolled back blocks, newest first.
+  pub rolled_back_blocks: Vec<String>,
+  /// Outputs whose rune balances were restored or removed, at most 10,000
+  /// per notification.
+  pub reverted_outpoints: Vec<String>,
+  /// Whether another notification of the same reorg follows with more of the
+  /// reverted outputs.
+  pub more_outpoints: bool,
+}
+
+/// Sent to subscribers by `new_block_detected` for every indexed block.
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct NewBlockRequest {
+  pub block_height: u32,
+  pub block_hash: String,
+  pub tx_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub enum Notification {
+  /// Delivered by `new_block_detected`.
+  NewBlock(NewBlockRequest),
+  /// Delivered by `new_block_detected_v2`.
+  RuneBlock(RuneBlock),
+  /// Delivered by `reorg_detected`.
+  Reorg(ReorgNotification),
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct SequencedNotification {
+  pub seq: u64,
+  pub notification: Notification,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct GetNotificationsResult {
+  /// Oldest notification still kept, earlier ones were pruned.
+  pub oldest_seq: u64,
+  pub notifications: Vec<SequencedNotification>,
+  /// Sequence number to continue from.
+  pub next_seq: u64,
+}
+
+#[derive(Debug, Clone, Copy, CandidType, Deserialize)]
+pub enum EventKind {
+  Etch,
+  Mint,
+  Transfer,
+  Burn,
+}
+
+/// Events a subscriber is notified of. Empty lists match everything.
+#[derive(Debug, Default, CandidType, Deserialize)]
+pub struct SubscriptionFilter {
+  pub rune_ids: Vec<String>,
+  /// Hex-encoded script pubkeys. Only transactions sending runes to one of
+  /// them match, with only their allocations to them.
+  pub script_pubkeys: Vec<String>,
+  pub events: Vec<EventKind>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SubscriberStats {
+  pub subscriber: Principal,
+  /// Notifications the subscriber hasn't received yet.
+  pub pending: u64,
+  /// Failed deliveries since the last successful one.
+  pub consecutive_failures: u32,
+  /// Time of the next attempt in nanoseconds while deliveries back off.
+  pub retry_at: Option<u64>,
+  pub in_flight: bool,
+  /// Counters since the last upgrade.
+  pub delivered: u64,
+  pub failed: u64,
+  pub timed_out: u64,
+  /// Latencies of successful deliveries, in milliseconds.
+  pub last_latency_ms: Option<u64>,
+  pub average_latency_ms: Option<u64>,
+  pub max_latency_ms: u64,
+  pub last_error: Option<String>,
+}
+
 #[derive(Debug, CandidType, Deserialize)]
 pub enum Error {
   MaxOutpointsExceeded,
+  /// Balances of the rune are not indexed, see `Config::indexed_runes`.
+  RuneNotIndexed,
+  /// A shard holding some of the outputs could not be queried.
+  ShardUnavailable(String),
 }